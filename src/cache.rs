@@ -0,0 +1,108 @@
+//! On-disk cache of token counts behind `--cache`.
+
+use std::collections::BTreeMap;
+use std::fs::{self, Metadata};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+/// What a cached count was computed from; any mismatch means re-tokenizing.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub mtime: u64, // nanoseconds since the Unix epoch
+    pub size: u64,
+    pub tokens: u64,
+    pub encoding: String,
+    /// Counted after replacing invalid UTF-8 under `--lossy`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub lossy: bool,
+    /// Text encoding found by `--transcode`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub charset: Option<String>,
+}
+
+impl CacheEntry {
+    /// An entry for the file as it is now, with `tokens` still to be filled in.
+    pub fn fingerprint(metadata: &Metadata, encoding: &str) -> Self {
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_nanos() as u64)
+            .unwrap_or_default();
+        CacheEntry {
+            mtime,
+            size: metadata.len(),
+            tokens: 0,
+            encoding: encoding.to_string(),
+            lossy: false,
+            charset: None,
+        }
+    }
+
+    fn same_source(&self, other: &CacheEntry) -> bool {
+        self.mtime == other.mtime && self.size == other.size && self.encoding == other.encoding
+    }
+}
+
+/// Token counts keyed by absolute file path.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TokenCache {
+    entries: BTreeMap<String, CacheEntry>,
+}
+
+impl TokenCache {
+    /// Reads a cache file; a missing file is an empty cache.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read cache {}", path.display()))
+            }
+        };
+        serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse cache {}", path.display()))
+    }
+
+    /// Writes the cache through a temporary file so readers never see a partial one.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut file = NamedTempFile::new_in(dir)
+            .with_context(|| format!("failed to create cache {}", path.display()))?;
+        {
+            let mut out = BufWriter::new(&mut file);
+            serde_json::to_writer(&mut out, self)?;
+            out.flush()
+                .with_context(|| format!("failed to write cache {}", path.display()))?;
+        }
+        file.persist(path)
+            .with_context(|| format!("failed to write cache {}", path.display()))?;
+        Ok(())
+    }
+
+    /// The cached entry, if `current` still describes the same file contents.
+    pub fn lookup(&self, key: &str, current: &CacheEntry) -> Option<&CacheEntry> {
+        self.entries
+            .get(key)
+            .filter(|entry| entry.same_source(current))
+    }
+
+    pub fn insert(&mut self, key: String, entry: CacheEntry) {
+        self.entries.insert(key, entry);
+    }
+
+    /// Drops the entries of files that have since been deleted; files outside
+    /// this run's paths keep theirs.
+    pub fn retain_existing(&mut self) {
+        self.entries.retain(|key, _| Path::new(key).exists());
+    }
+}
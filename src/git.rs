@@ -0,0 +1,146 @@
+//! Git revisions for `--git-diff` and `--changed`, read by shelling out to `git`.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use anyhow::{bail, Context, Result};
+
+/// Writes the files tracked at `rev` under `paths` into `dest`, laid out
+/// relative to the top of the repository; [`from_top_level`] maps them back.
+///
+/// Only regular files are exported; symlinks and submodules are skipped.
+pub fn export_revision(rev: &str, paths: &[PathBuf], dest: &Path) -> Result<()> {
+    let listing = git(Command::new("git")
+        .args(["ls-tree", "-r", "-z", "--full-name", rev, "--"])
+        .args(paths))
+    .with_context(|| format!("failed to list files at {rev}"))?;
+
+    // Each entry is `<mode> <type> <object>\t<path>`.
+    let mut blobs = Vec::new();
+    for entry in listing.split(|&byte| byte == 0).filter(|e| !e.is_empty()) {
+        let entry = String::from_utf8_lossy(entry);
+        let Some((meta, path)) = entry.split_once('\t') else {
+            bail!("unexpected git ls-tree output: {entry}");
+        };
+        let mut fields = meta.split(' ');
+        let (Some(mode), Some("blob"), Some(object)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        // --full-name never prints `..`; a path that still would land
+        // outside `dest` means the listing is not what we asked for.
+        if Path::new(path)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            bail!("unexpected path in git ls-tree output: {path}");
+        }
+        if mode != "120000" {
+            blobs.push((object.to_string(), dest.join(path)));
+        }
+    }
+    if blobs.is_empty() {
+        return Ok(());
+    }
+
+    let mut child = Command::new("git")
+        .args(["cat-file", "--batch"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("failed to run git cat-file")?;
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    let objects: Vec<String> = blobs.iter().map(|(object, _)| object.clone()).collect();
+    // Fed from another thread so a full stdout pipe cannot stall the writes.
+    let feeder = thread::spawn(move || -> std::io::Result<()> {
+        for object in objects {
+            writeln!(stdin, "{object}")?;
+        }
+        Ok(())
+    });
+
+    let mut stdout = BufReader::new(child.stdout.take().expect("piped stdout"));
+    let mut header = String::new();
+    for (object, target) in &blobs {
+        header.clear();
+        stdout.read_line(&mut header)?;
+        // `<object> blob <size>`
+        let size: usize = header
+            .trim_end()
+            .rsplit(' ')
+            .next()
+            .and_then(|size| size.parse().ok())
+            .with_context(|| format!("unexpected git cat-file output for {object}: {header}"))?;
+        let mut contents = vec![0; size + 1];
+        stdout.read_exact(&mut contents)?;
+        contents.pop(); // the newline after each object
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(target, contents)
+            .with_context(|| format!("failed to write {}", target.display()))?;
+    }
+
+    feeder
+        .join()
+        .expect("git cat-file feeder panicked")
+        .context("failed to write to git cat-file")?;
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("git cat-file failed with {status}");
+    }
+    Ok(())
+}
+
+/// Where the current directory sits in the repository, like `src/` or empty
+/// at the top level.
+pub fn cwd_prefix() -> Result<PathBuf> {
+    let prefix = git(Command::new("git").args(["rev-parse", "--show-prefix"]))
+        .context("failed to locate the current directory in the repository")?;
+    Ok(PathBuf::from(
+        String::from_utf8_lossy(&prefix).trim_end_matches('\n'),
+    ))
+}
+
+/// Turns `path`, relative to the top of the repository, into a path relative
+/// to the directory at `prefix` (see [`cwd_prefix`]), with `..` as needed.
+pub fn from_top_level(path: &Path, prefix: &Path) -> PathBuf {
+    let mut path = path.components().peekable();
+    let mut prefix = prefix.components().peekable();
+    while path.peek().is_some() && path.peek() == prefix.peek() {
+        path.next();
+        prefix.next();
+    }
+    prefix.map(|_| Component::ParentDir).chain(path).collect()
+}
+
+/// Existing files that `git diff --name-only REV` reports, relative to the
+/// current directory; only changes inside it are listed. Untracked files are
+/// not part of a diff, so they are not included until staged.
+pub fn changed_files(rev: &str) -> Result<Vec<PathBuf>> {
+    if git(Command::new("git").args(["rev-parse", "--git-dir"])).is_err() {
+        bail!("--changed only works inside a git repository");
+    }
+    let names = git(Command::new("git").args(["diff", "--name-only", "-z", "--relative", rev]))
+        .with_context(|| format!("failed to list files changed since {rev}"))?;
+    Ok(names
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| PathBuf::from(String::from_utf8_lossy(name).into_owned()))
+        // Deleted files show up in the diff too.
+        .filter(|path| path.is_file())
+        .collect())
+}
+
+/// Runs a git command and returns its stdout, or its stderr as the error.
+fn git(command: &mut Command) -> Result<Vec<u8>> {
+    let output = command.output().context("failed to run git")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim_end());
+    }
+    Ok(output.stdout)
+}
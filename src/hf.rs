@@ -0,0 +1,29 @@
+//! Hugging Face `tokenizer.json` support behind the `hf` feature.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{TokenizeError, Tokenizer};
+
+/// A tokenizer loaded from a Hugging Face `tokenizer.json`, e.g. for Llama or Mistral.
+pub struct HfTokenizer(tokenizers::Tokenizer);
+
+impl HfTokenizer {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let tokenizer = tokenizers::Tokenizer::from_file(path)
+            .map_err(|err| anyhow!(err))
+            .with_context(|| format!("failed to load tokenizer {}", path.display()))?;
+        Ok(HfTokenizer(tokenizer))
+    }
+}
+
+impl Tokenizer for HfTokenizer {
+    fn count(&self, text: &str) -> std::result::Result<u64, TokenizeError> {
+        // Special tokens such as BOS are left out, like tiktoken's encode_ordinary.
+        self.0
+            .encode(text, false)
+            .map(|encoding| encoding.len() as u64)
+            .map_err(|err| TokenizeError(err.to_string()))
+    }
+}
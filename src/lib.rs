@@ -0,0 +1,1702 @@
+//! Token counting pipeline behind the `tokencount` CLI.
+//!
+//! ```no_run
+//! use std::path::PathBuf;
+//! use tokencount::{build_summary, count_paths, CountOptions};
+//!
+//! let options = CountOptions {
+//!     include_ext: vec!["rs".into()],
+//!     ..CountOptions::default()
+//! };
+//! let stats = count_paths(&[PathBuf::from("src")], &options)?;
+//! let summary = build_summary(&stats, None);
+//! println!("{} tokens in {} files", summary.total, summary.files);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+mod cache;
+mod git;
+#[cfg(feature = "hf")]
+mod hf;
+mod vocab;
+
+pub use cache::{CacheEntry, TokenCache};
+pub use git::{changed_files, cwd_prefix, export_revision, from_top_level};
+#[cfg(feature = "hf")]
+pub use hf::HfTokenizer;
+pub use vocab::load_vocab;
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{BufRead, BufReader, Read};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use log::{debug, info, warn};
+use memmap2::Mmap;
+use rayon::prelude::*;
+use schemars::JsonSchema;
+use serde::ser::SerializeMap;
+use serde::Serialize;
+use thiserror::Error;
+use tiktoken_rs::tokenizer::Tokenizer as TiktokenModel;
+use tiktoken_rs::{cl100k_base, o200k_base, p50k_base, p50k_edit, r50k_base, CoreBPE};
+use twox_hash::XxHash3_64;
+
+/// Build and tooling files without an extension, for `--include-common-names`.
+pub const COMMON_FILE_NAMES: &[&str] = &[
+    "Dockerfile",
+    "Containerfile",
+    "Makefile",
+    "GNUmakefile",
+    "Justfile",
+    "Rakefile",
+    "Gemfile",
+    "Procfile",
+    "Vagrantfile",
+    "Jenkinsfile",
+];
+
+/// Per-directory ignore file using `.gitignore` syntax, honored on every walk.
+pub const IGNORE_FILENAME: &str = ".tokencountignore";
+
+/// How much of a file `looks_binary` inspects; also the smallest streaming chunk.
+const SNIFF_BYTES: usize = 8192;
+
+/// How [`count_paths`] walks the filesystem and tokenizes what it finds.
+#[derive(Clone, Debug)]
+pub struct CountOptions {
+    /// File extensions to include, with or without a leading dot; an entry
+    /// may list several separated by commas, and `*` includes every file.
+    pub include_ext: Vec<String>,
+    /// Extensions to leave out even when otherwise included, in `include_ext`
+    /// syntax. Compound suffixes such as `d.ts` or `min.js` match the end of
+    /// the file name, so `foo.d.ts` is excluded while `foo.ts` is not.
+    pub exclude_ext: Vec<String>,
+    /// Glob patterns, relative to each scanned path, that include a file
+    /// even when its extension is not listed in `include_ext`.
+    pub include: Vec<String>,
+    /// Exact file names, such as `Dockerfile`, to include whatever their extension.
+    pub include_names: Vec<String>,
+    /// Match `include_names` ignoring ASCII case.
+    pub ignore_name_case: bool,
+    /// Decode by byte order mark (UTF-8, UTF-16LE/BE, dropping the BOM) and fall
+    /// back to windows-1252 for files that are not UTF-8. Disables `chunk_bytes`.
+    pub transcode: bool,
+    /// Sniff the `#!` line of extension-less files and include them when the
+    /// interpreter maps to an included extension (`python3` to `py`, ...).
+    pub detect_shebang: bool,
+    /// Glob patterns to exclude on top of `.git`, `target` and `node_modules`.
+    pub exclude: Vec<String>,
+    pub respect_gitignore: bool,
+    /// Honor `.ignore` files, the tool-neutral ignore files ripgrep and fd read.
+    pub respect_ignore: bool,
+    pub follow_symlinks: bool,
+    /// Walk hidden files and directories (dotfiles).
+    pub hidden: bool,
+    /// Maximum directory depth below each path; 1 means only its direct children.
+    pub max_depth: Option<usize>,
+    /// Skip files larger than this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Skip files smaller than this many bytes, such as empty generated stubs.
+    pub min_bytes: Option<u64>,
+    /// Tokenize files larger than this many bytes in chunks of about that size
+    /// (at least 8 KiB) instead of reading them whole.
+    ///
+    /// Chunks end after a newline, so a pre-token that would have spanned the
+    /// split, typically a run of blank lines, is tokenized in two pieces; expect
+    /// a token or so of drift per chunk. Lines are never split, so a single huge
+    /// line is still read whole.
+    pub chunk_bytes: Option<u64>,
+    /// Memory-map files instead of reading them, falling back to a read if mapping fails.
+    pub mmap: bool,
+    /// Decode invalid UTF-8 with replacement characters instead of skipping the file.
+    pub lossy: bool,
+    /// Skip files that look binary instead of trying to decode them.
+    pub skip_binary: bool,
+    /// Report canonical absolute paths instead of paths relative to the cwd.
+    pub absolute: bool,
+    /// Also record line, byte and char counts for each file.
+    pub extended: bool,
+    /// Count each distinct file content once, keeping the first path seen.
+    pub dedup: bool,
+    /// Reuse and refresh token counts stored in this JSON file.
+    pub cache: Option<PathBuf>,
+    pub encoding: EncoderSource,
+    /// Further encodings counted alongside `encoding`; files then carry a count
+    /// per encoding and the delta of the first of these against `encoding`.
+    pub compare: Vec<EncoderSource>,
+    /// Count special markers such as `<|endoftext|>` as single tokens: all of
+    /// them for an empty list, otherwise only the listed ones.
+    pub allow_special: Option<Vec<String>>,
+    /// Bumped once per file as [`count_tokens`] gets through it, so callers
+    /// can report progress from another thread.
+    pub progress: Option<Arc<AtomicU64>>,
+}
+
+impl Default for CountOptions {
+    fn default() -> Self {
+        CountOptions {
+            include_ext: vec![String::from("elm")],
+            exclude_ext: Vec::new(),
+            include: Vec::new(),
+            include_names: Vec::new(),
+            ignore_name_case: false,
+            detect_shebang: false,
+            transcode: false,
+            exclude: Vec::new(),
+            respect_gitignore: true,
+            respect_ignore: true,
+            follow_symlinks: false,
+            hidden: true,
+            max_depth: None,
+            max_bytes: None,
+            min_bytes: None,
+            chunk_bytes: None,
+            mmap: false,
+            lossy: false,
+            skip_binary: true,
+            absolute: false,
+            extended: false,
+            dedup: false,
+            cache: None,
+            encoding: EncoderSource::Builtin(Encoding::Cl100kBase),
+            compare: Vec::new(),
+            allow_special: None,
+            progress: None,
+        }
+    }
+}
+
+impl CountOptions {
+    fn include_extensions(&self) -> HashSet<String> {
+        extension_set(&self.include_ext)
+    }
+
+    /// Excluded extensions as `.ext` suffixes, ready to match file names against.
+    fn exclude_suffixes(&self) -> Vec<String> {
+        extension_set(&self.exclude_ext)
+            .into_iter()
+            .map(|ext| format!(".{ext}"))
+            .collect()
+    }
+
+    fn name_included(&self, name: &str) -> bool {
+        self.include_names.iter().any(|included| {
+            if self.ignore_name_case {
+                included.eq_ignore_ascii_case(name)
+            } else {
+                included == name
+            }
+        })
+    }
+
+    /// Names of `encoding` followed by those in `compare`.
+    fn encoder_names(&self) -> Vec<String> {
+        std::iter::once(&self.encoding)
+            .chain(&self.compare)
+            .map(EncoderSource::name)
+            .collect()
+    }
+
+    /// The encoding as recorded in cache entries; special-token counts differ,
+    /// and a hit skips the binary check, so counts kept despite it are marked.
+    fn cache_encoding_name(&self) -> String {
+        let name = self.encoding.name();
+        let mut name = match &self.allow_special {
+            None => name,
+            Some(allowed) if allowed.is_empty() => format!("{name}+special"),
+            Some(allowed) => format!("{name}+special:{}", allowed.join(",")),
+        };
+        if self.transcode {
+            name.push_str("+transcode");
+        } else if let Some(chunk_bytes) = self.chunk_bytes {
+            // Chunk boundaries can split what one pass would merge into a token.
+            name.push_str(&format!("+chunk:{chunk_bytes}"));
+        }
+        if !self.skip_binary {
+            name.push_str("+binary");
+        }
+        name
+    }
+}
+
+/// Files counted by [`scan_paths`], plus how many were dropped as duplicates,
+/// skipped for looking binary or fell outside `min_bytes`/`max_bytes`.
+#[derive(Clone, Debug, Default)]
+pub struct Scan {
+    pub stats: Vec<FileStat>,
+    pub duplicates: u64,
+    pub skipped_binary: u64,
+    pub skipped_too_large: u64,
+    pub skipped_too_small: u64,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct FileStat {
+    pub path: String,
+    pub tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub over_budget: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chars: Option<u64>,
+    /// Tokens per encoding name when comparing encodings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counts: Option<BTreeMap<String, u64>>,
+    /// Second encoding's count minus the first's when comparing encodings, or
+    /// the change since the `--baseline` report.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<i64>,
+    /// Dollar cost at the `--price-per-million` rate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+    /// Whether the file fits the `--context-window` on its own.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fits: Option<bool>,
+    /// Tokens once wrapped as a chat message (`--chat-overhead`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens_with_overhead: Option<u64>,
+    /// Set when invalid UTF-8 was replaced under `--lossy`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lossy: Option<bool>,
+    /// Text encoding found by `--transcode`, e.g. `UTF-16LE`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charset: Option<String>,
+}
+
+impl FileStat {
+    pub fn new(path: String, tokens: u64) -> Self {
+        FileStat {
+            path,
+            tokens,
+            over_budget: None,
+            lines: None,
+            bytes: None,
+            chars: None,
+            counts: None,
+            delta: None,
+            cost: None,
+            fits: None,
+            tokens_with_overhead: None,
+            lossy: None,
+            charset: None,
+        }
+    }
+
+    /// Fills in line, byte and char counts for `--extended` style reports.
+    pub fn with_metrics(mut self, contents: &str) -> Self {
+        self.lines = Some(contents.lines().count() as u64);
+        self.bytes = Some(contents.len() as u64);
+        self.chars = Some(contents.chars().count() as u64);
+        self
+    }
+
+    /// Records per-encoding counts; `tokens` stays the first encoding's count.
+    pub fn with_comparison(mut self, names: &[String], counts: &[u64]) -> Self {
+        if let (Some(first), Some(second)) = (counts.first(), counts.get(1)) {
+            self.delta = Some(*second as i64 - *first as i64);
+        }
+        self.counts = Some(names.iter().cloned().zip(counts.iter().copied()).collect());
+        self
+    }
+
+    /// The cache record for this count, on top of the file's `fingerprint`.
+    fn cache_entry(&self, fingerprint: CacheEntry) -> CacheEntry {
+        CacheEntry {
+            tokens: self.tokens,
+            lossy: self.lossy.is_some(),
+            charset: self.charset.clone(),
+            ..fingerprint
+        }
+    }
+
+    /// Bytes per token, a rough density signal (minified files score high).
+    pub fn bytes_per_token(&self) -> Option<f64> {
+        let bytes = self.bytes?;
+        Some(if self.tokens == 0 {
+            0.0
+        } else {
+            bytes as f64 / self.tokens as f64
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct Summary {
+    pub files: u64,
+    pub total: u64,
+    pub average: f64,
+    #[serde(serialize_with = "serialize_percentile")]
+    pub p50: f64,
+    #[serde(serialize_with = "serialize_percentile")]
+    pub p90: f64,
+    #[serde(serialize_with = "serialize_percentile")]
+    pub p99: f64,
+    /// The `--percentiles` list, keyed `p75`, `p99.9` and so on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<BTreeMap<String, f64>>")]
+    pub percentiles: Option<Percentiles>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// Total tokens per encoding name when comparing encodings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub totals: Option<BTreeMap<String, u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top: Option<Vec<FileStat>>, // sorted by tokens desc
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by_extension: Option<BTreeMap<String, ExtensionStat>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub by_directory: Option<Vec<DirectoryStat>>, // sorted by tokens desc
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>, // rounded to 6 decimals
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_cost: Option<f64>, // at the per-million rate, unrounded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub histogram: Option<Vec<HistogramBucket>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplicates: Option<u64>, // files skipped by --dedup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped_binary: Option<u64>, // files that looked binary, when any did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped_too_large: Option<u64>, // files over --max-bytes, when any were
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped_too_small: Option<u64>, // files under --min-bytes, when any were
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lossy_files: Option<u64>, // files decoded with replacements under --lossy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hidden_files: Option<u64>, // rows left out by --min-tokens, when any were
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files_over_window: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<u64>,
+    /// Files that fit the context window on their own.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files_fitting_individually: Option<u64>,
+    /// Files that fit the context window together, added greedily in
+    /// `--pack-order` and skipping any that would overflow it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files_fitting_packed: Option<u64>,
+    /// Change in total tokens since the `--baseline` report.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_delta: Option<i64>,
+    /// Paths in the `--baseline` report that were not counted this time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub removed_files: Option<Vec<String>>,
+    /// Files over the per-file budget when it is enforced, largest first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub violations: Option<Vec<Violation>>,
+    /// Every file sent as its own chat message, plus the reply priming.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_with_overhead: Option<u64>,
+}
+
+/// Nearest-rank percentiles are whole token counts and serialize as integers,
+/// as they always have; only interpolated ones carry a fraction.
+fn serialize_percentile<S: serde::Serializer>(
+    value: &f64,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if value.fract() == 0.0 && *value >= 0.0 && *value <= u64::MAX as f64 {
+        serializer.serialize_u64(*value as u64)
+    } else {
+        serializer.serialize_f64(*value)
+    }
+}
+
+/// Percentiles of the per-file counts as `(percent, value)` pairs in ascending
+/// order of percent; serializes as a map like `{"p75": 12, "p95": 40}`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Percentiles(pub Vec<(f64, f64)>);
+
+impl Percentiles {
+    /// The key a percent is reported under, e.g. `p99.9`.
+    pub fn label(percent: f64) -> String {
+        format!("p{percent}")
+    }
+}
+
+impl Serialize for Percentiles {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        struct Value(f64);
+        impl Serialize for Value {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serialize_percentile(&self.0, serializer)
+            }
+        }
+
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (percent, value) in &self.0 {
+            map.serialize_entry(&Percentiles::label(*percent), &Value(*value))?;
+        }
+        map.end()
+    }
+}
+
+/// The given percents (each in `(0, 100]`) of every file's count.
+pub fn percentiles(
+    all_stats: &[FileStat],
+    percents: &[f64],
+    method: PercentileMethod,
+) -> Percentiles {
+    let mut counts: Vec<u64> = all_stats.iter().map(|s| s.tokens).collect();
+    counts.sort_unstable();
+    let mut percents = percents.to_vec();
+    percents.sort_by(f64::total_cmp);
+    percents.dedup();
+    Percentiles(
+        percents
+            .into_iter()
+            .map(|percent| (percent, percentile(&counts, percent / 100.0, method)))
+            .collect(),
+    )
+}
+
+/// How `p50`/`p90`/`p99` pick a value between the sorted per-file counts.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PercentileMethod {
+    /// The smallest count with at least that share of files at or below it.
+    #[default]
+    Nearest,
+    /// Interpolate between the two closest ranks (numpy's default).
+    Linear,
+}
+
+/// A file over the enforced per-file token budget.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct Violation {
+    pub path: String,
+    pub tokens: u64,
+}
+
+/// Files whose token count is at most `le` and above the previous bucket's edge.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct HistogramBucket {
+    /// Inclusive upper edge; `None` for the final overflow bucket.
+    #[schemars(required, extend("type" = ["integer", "null"]))]
+    pub le: Option<u64>,
+    /// Smallest token count that lands in this bucket.
+    pub range_start: u64,
+    /// Same as `le`: the largest count in this bucket, `None` when open-ended.
+    #[schemars(required, extend("type" = ["integer", "null"]))]
+    pub range_end: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, JsonSchema)]
+pub struct ExtensionStat {
+    pub files: u64,
+    pub tokens: u64,
+}
+
+/// Label used for files without an extension.
+pub const NO_EXTENSION: &str = "(none)";
+
+/// A row of `--group-by ext` output.
+#[derive(Clone, Debug, Serialize)]
+pub struct ExtensionGroup {
+    pub ext: String,
+    pub files: u64,
+    pub tokens: u64,
+    pub average: f64,
+    pub max: u64,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct DirectoryStat {
+    pub path: String,
+    pub files: u64,
+    pub tokens: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum ProcessError {
+    #[error("failed to read metadata for {path}")]
+    Metadata {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("skipping {path}: file size {size} exceeds max {limit}")]
+    TooLarge { path: String, size: u64, limit: u64 },
+    #[error("skipping {path}: file size {size} is below min {limit}")]
+    TooSmall { path: String, size: u64, limit: u64 },
+    #[error("skipping {path}: {source}")]
+    Read {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("skipping {path}: looks like a binary file")]
+    Binary { path: String },
+    #[error("skipping {path}: not valid UTF-8 (use --lossy to count it anyway)")]
+    InvalidUtf8 { path: String },
+    #[error("skipping {path}: not valid {charset} despite its byte order mark")]
+    Undecodable { path: String, charset: &'static str },
+    #[error("skipping {path}: {source}")]
+    Tokenize {
+        path: String,
+        #[source]
+        source: TokenizeError,
+    },
+}
+
+/// A tokenizer that could not encode some text, so there is no count to report.
+#[derive(Debug, Error)]
+#[error("tokenizer failed: {0}")]
+pub struct TokenizeError(pub String);
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum Encoding {
+    /// GPT-4, GPT-3.5 Turbo and the text-embedding-3/ada-002 models.
+    #[value(alias = "cl100k_base")]
+    Cl100kBase,
+    /// GPT-4o and GPT-4o mini.
+    #[value(alias = "o200k_base")]
+    O200kBase,
+    /// Codex and text-davinci-002/003.
+    #[value(alias = "p50k_base")]
+    P50kBase,
+    /// text-davinci-edit-001 and code-davinci-edit-001.
+    #[value(alias = "p50k_edit")]
+    P50kEdit,
+    /// GPT-3 models such as davinci (also known as gpt2).
+    #[value(alias = "r50k_base")]
+    R50kBase,
+}
+
+/// Well-known model names and the encoding they tokenize with.
+pub const MODEL_ENCODINGS: &[(&str, Encoding)] = &[
+    ("gpt-4o", Encoding::O200kBase),
+    ("gpt-4o-mini", Encoding::O200kBase),
+    ("gpt-4", Encoding::Cl100kBase),
+    ("gpt-4-turbo", Encoding::Cl100kBase),
+    ("gpt-3.5-turbo", Encoding::Cl100kBase),
+    ("text-embedding-3-small", Encoding::Cl100kBase),
+    ("text-embedding-3-large", Encoding::Cl100kBase),
+    ("text-embedding-ada-002", Encoding::Cl100kBase),
+    ("text-davinci-003", Encoding::P50kBase),
+    ("text-davinci-002", Encoding::P50kBase),
+    ("code-davinci-002", Encoding::P50kBase),
+    ("text-davinci-edit-001", Encoding::P50kEdit),
+    ("davinci", Encoding::R50kBase),
+    ("gpt2", Encoding::R50kBase),
+];
+
+impl Encoding {
+    /// The tiktoken name, e.g. `cl100k_base`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Cl100kBase => "cl100k_base",
+            Encoding::O200kBase => "o200k_base",
+            Encoding::P50kBase => "p50k_base",
+            Encoding::P50kEdit => "p50k_edit",
+            Encoding::R50kBase => "r50k_base",
+        }
+    }
+
+    /// Wrapper tokens each chat message adds, per OpenAI's token counting guide:
+    /// 3 for the chat models on cl100k_base and o200k_base, 4 for older ones.
+    pub fn chat_message_overhead(&self) -> u64 {
+        match self {
+            Encoding::Cl100kBase | Encoding::O200kBase => 3,
+            Encoding::P50kBase | Encoding::P50kEdit | Encoding::R50kBase => 4,
+        }
+    }
+
+    /// Resolves a model name such as `gpt-4o` to the encoding it uses.
+    pub fn from_model_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        if let Some((_, encoding)) = MODEL_ENCODINGS.iter().find(|(model, _)| *model == name) {
+            return Some(*encoding);
+        }
+        // tiktoken knows dated snapshots such as gpt-4o-2024-08-06 by prefix.
+        let tokenizer = tiktoken_rs::tokenizer::get_tokenizer(&name)?;
+        Some(match tokenizer {
+            TiktokenModel::O200kBase => Encoding::O200kBase,
+            TiktokenModel::Cl100kBase => Encoding::Cl100kBase,
+            TiktokenModel::P50kBase => Encoding::P50kBase,
+            TiktokenModel::P50kEdit => Encoding::P50kEdit,
+            TiktokenModel::R50kBase | TiktokenModel::Gpt2 => Encoding::R50kBase,
+        })
+    }
+
+    /// Loads the BPE tables for this encoding.
+    pub fn load(&self) -> Result<Arc<dyn Tokenizer>> {
+        EncoderSource::Builtin(*self).load()
+    }
+}
+
+/// Anything that can count the tokens in a piece of text.
+pub trait Tokenizer: Send + Sync {
+    fn count(&self, text: &str) -> std::result::Result<u64, TokenizeError>;
+}
+
+impl Tokenizer for CoreBPE {
+    fn count(&self, text: &str) -> std::result::Result<u64, TokenizeError> {
+        Ok(self.encode_ordinary(text).len() as u64)
+    }
+}
+
+/// A BPE that turns special markers such as `<|endoftext|>` into single tokens.
+struct SpecialBpe {
+    bpe: CoreBPE,
+    /// Names to recognize; empty means every special token of the encoding.
+    allowed: Vec<String>,
+}
+
+impl Tokenizer for SpecialBpe {
+    fn count(&self, text: &str) -> std::result::Result<u64, TokenizeError> {
+        Ok(encode_ids(&self.bpe, text, Some(&self.allowed)).len() as u64)
+    }
+}
+
+/// Token IDs for `text`, matching how files are counted: special markers are
+/// single tokens only when `allowed` is given (empty allows every one).
+pub fn encode_ids(bpe: &CoreBPE, text: &str, allowed: Option<&[String]>) -> Vec<usize> {
+    match allowed {
+        None => bpe.encode_ordinary(text),
+        Some([]) => bpe.encode_with_special_tokens(text),
+        Some(allowed) => bpe.encode(text, allowed.iter().map(String::as_str).collect()),
+    }
+}
+
+/// Where the tokenizer comes from: a bundled encoding, a `.tiktoken` file or,
+/// with the `hf` feature, a Hugging Face `tokenizer.json`.
+#[derive(Clone, Debug)]
+pub enum EncoderSource {
+    Builtin(Encoding),
+    File {
+        path: PathBuf,
+        special_tokens: Vec<(String, usize)>,
+    },
+    #[cfg(feature = "hf")]
+    HuggingFace(PathBuf),
+}
+
+impl EncoderSource {
+    /// The encoding name, or the vocabulary path for custom files.
+    pub fn name(&self) -> String {
+        match self {
+            EncoderSource::Builtin(encoding) => encoding.name().to_string(),
+            EncoderSource::File { path, .. } => path.display().to_string(),
+            #[cfg(feature = "hf")]
+            EncoderSource::HuggingFace(path) => path.display().to_string(),
+        }
+    }
+
+    /// Per-message chat overhead; custom tokenizers get the cl100k_base value.
+    pub fn chat_message_overhead(&self) -> u64 {
+        match self {
+            EncoderSource::Builtin(encoding) => encoding.chat_message_overhead(),
+            _ => Encoding::Cl100kBase.chat_message_overhead(),
+        }
+    }
+
+    /// Loads the tiktoken BPE behind this source, e.g. to look at token IDs.
+    pub fn load_bpe(&self) -> Result<CoreBPE> {
+        Ok(match self {
+            EncoderSource::Builtin(Encoding::Cl100kBase) => cl100k_base()?,
+            EncoderSource::Builtin(Encoding::O200kBase) => o200k_base()?,
+            EncoderSource::Builtin(Encoding::P50kBase) => p50k_base()?,
+            EncoderSource::Builtin(Encoding::P50kEdit) => p50k_edit()?,
+            EncoderSource::Builtin(Encoding::R50kBase) => r50k_base()?,
+            EncoderSource::File {
+                path,
+                special_tokens,
+            } => load_vocab(path, special_tokens)?,
+            #[cfg(feature = "hf")]
+            EncoderSource::HuggingFace(path) => {
+                anyhow::bail!("{} is not a tiktoken encoding", path.display())
+            }
+        })
+    }
+
+    /// Loads the tokenizer, parsing the vocabulary or tokenizer.json if there is one.
+    pub fn load(&self) -> Result<Arc<dyn Tokenizer>> {
+        self.load_with_special(None)
+    }
+
+    /// Like [`EncoderSource::load`], but counts special markers as single tokens:
+    /// every one when `allowed` is empty, otherwise only those listed.
+    ///
+    /// Hugging Face tokenizers always match their added tokens, so `allowed`
+    /// makes no difference there.
+    pub fn load_with_special(&self, allowed: Option<&[String]>) -> Result<Arc<dyn Tokenizer>> {
+        #[cfg(feature = "hf")]
+        if let EncoderSource::HuggingFace(path) = self {
+            return Ok(Arc::new(HfTokenizer::from_file(path)?));
+        }
+        let bpe = self.load_bpe()?;
+        Ok(match allowed {
+            Some(allowed) => Arc::new(SpecialBpe {
+                bpe,
+                allowed: allowed.to_vec(),
+            }),
+            None => Arc::new(bpe),
+        })
+    }
+}
+
+impl From<Encoding> for EncoderSource {
+    fn from(encoding: Encoding) -> Self {
+        EncoderSource::Builtin(encoding)
+    }
+}
+
+/// Walks `paths` and counts tokens in every matching file.
+pub fn count_paths(paths: &[PathBuf], options: &CountOptions) -> Result<Vec<FileStat>> {
+    Ok(scan_paths(paths, options)?.stats)
+}
+
+/// Like [`count_paths`], but also reports what was skipped along the way.
+pub fn scan_paths(paths: &[PathBuf], options: &CountOptions) -> Result<Scan> {
+    let encoders = load_encoders(options)?;
+    let files = collect_files(paths, options)?;
+    debug!("collected {} candidate files", files.len());
+    Ok(count_tokens(files, options, &encoders))
+}
+
+/// Loads `options.encoding` followed by every encoding in `options.compare`.
+pub fn load_encoders(options: &CountOptions) -> Result<Vec<Arc<dyn Tokenizer>>> {
+    std::iter::once(&options.encoding)
+        .chain(&options.compare)
+        .map(|source| {
+            source
+                .load_with_special(options.allow_special.as_deref())
+                .with_context(|| format!("failed to load encoding {}", source.name()))
+        })
+        .collect()
+}
+
+fn build_include_globset(patterns: &[String]) -> Result<GlobSet> {
+    build_globset(patterns.iter().map(String::as_str))
+}
+
+fn build_exclude_globset(mut patterns: Vec<String>) -> Result<Arc<GlobSet>> {
+    let defaults = vec![
+        "**/.git/**",
+        "**/.git",
+        "**/target/**",
+        "**/target",
+        "**/node_modules/**",
+        "**/node_modules",
+    ];
+    for pattern in defaults {
+        patterns.push(pattern.to_string());
+    }
+    Ok(Arc::new(build_globset(
+        patterns.iter().map(String::as_str),
+    )?))
+}
+
+fn build_globset<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob =
+            Glob::new(pattern).with_context(|| format!("invalid glob pattern: {pattern}"))?;
+        builder.add(glob);
+    }
+    builder.build().context("failed to build glob set")
+}
+
+/// Normalizes extension lists: each value may itself be a comma-separated
+/// list such as `elm,ts`, and entries lose a leading dot and their case.
+fn extension_set(lists: &[String]) -> HashSet<String> {
+    lists
+        .iter()
+        .flat_map(|list| list.split(','))
+        .map(str::trim)
+        .filter(|ext| !ext.is_empty())
+        .map(|ext| ext.strip_prefix('.').unwrap_or(ext).to_lowercase())
+        .collect()
+}
+
+/// Lists the files under `paths` that pass the include, exclude and ignore filters.
+pub fn collect_files(paths: &[PathBuf], options: &CountOptions) -> Result<Vec<PathBuf>> {
+    let excludes = build_exclude_globset(options.exclude.clone())?;
+    let includes = build_include_globset(&options.include)?;
+    let include_exts = options.include_extensions();
+    let exclude_suffixes = options.exclude_suffixes();
+    let mut files = Vec::new();
+    for root in paths {
+        // A file named on the command line was asked for explicitly, so the
+        // extension, include and exclude filters only apply to directory walks.
+        if root.is_file() {
+            files.push(root.clone());
+            continue;
+        }
+        walk_root(
+            root,
+            options,
+            &excludes,
+            &includes,
+            &include_exts,
+            &exclude_suffixes,
+            &mut files,
+        );
+    }
+    Ok(files)
+}
+
+fn walk_root(
+    root: &Path,
+    options: &CountOptions,
+    excludes: &Arc<GlobSet>,
+    includes: &GlobSet,
+    include_exts: &HashSet<String>,
+    exclude_suffixes: &[String],
+    files: &mut Vec<PathBuf>,
+) {
+    let respect_gitignore = options.respect_gitignore;
+    let excludes_for_filter = Arc::clone(excludes);
+    let mut builder = WalkBuilder::new(root);
+    builder.standard_filters(false);
+    builder.follow_links(options.follow_symlinks);
+    // The root itself is depth 0, so the depth == 0 shortcut below still sees it.
+    builder.max_depth(options.max_depth);
+    // Hidden files are a separate switch: a git-ignored dotfile is skipped
+    // unless gitignore handling is off, whatever `hidden` says.
+    builder.hidden(!options.hidden);
+
+    builder.git_ignore(respect_gitignore);
+    builder.git_global(respect_gitignore);
+    builder.git_exclude(respect_gitignore);
+    // Separate from git: `.ignore` files work outside a repository, and their
+    // rules override `.gitignore` ones.
+    builder.ignore(options.respect_ignore);
+    // Applies even with gitignore handling disabled; --exclude globs still win.
+    builder.add_custom_ignore_filename(IGNORE_FILENAME);
+
+    builder.filter_entry(move |entry| {
+        let excludes = &excludes_for_filter;
+        if entry.depth() == 0 {
+            return true;
+        }
+        let path = entry.path();
+        let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false);
+        if excludes.is_match(path) {
+            if is_dir {
+                debug!("excluding directory {}", path.display());
+            }
+            return !is_dir;
+        }
+        true
+    });
+
+    for result in builder.build() {
+        match result {
+            Ok(entry) => {
+                if excludes.is_match(entry.path()) {
+                    continue;
+                }
+                if !entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
+                    continue;
+                }
+                let path = entry.path();
+                let ext_matches = include_exts.contains("*")
+                    || path
+                        .extension()
+                        .and_then(|e| e.to_str())
+                        .is_some_and(|ext| include_exts.contains(&ext.to_ascii_lowercase()));
+                // Include globs are written relative to the scanned root.
+                let relative = path.strip_prefix(root).unwrap_or(path);
+                let name_matches = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| options.name_included(name));
+                let shebang_matches = options.detect_shebang
+                    && path.extension().is_none()
+                    && shebang_extension(path).is_some_and(|ext| include_exts.contains(ext));
+                if !ext_matches && !name_matches && !shebang_matches && !includes.is_match(relative)
+                {
+                    continue;
+                }
+                let excluded = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map(str::to_lowercase)
+                    .is_some_and(|name| {
+                        exclude_suffixes.iter().any(|suffix| name.ends_with(suffix))
+                    });
+                if excluded {
+                    continue;
+                }
+                files.push(entry.into_path());
+            }
+            Err(err) => warn!("walk error: {err}"),
+        }
+    }
+}
+
+/// How much of a file `shebang_extension` reads looking for the `#!` line.
+const SHEBANG_BYTES: u64 = 256;
+
+/// Interpreters, without version suffixes, and the extension they stand for.
+const SHEBANG_INTERPRETERS: &[(&str, &str)] = &[
+    ("python", "py"),
+    ("sh", "sh"),
+    ("bash", "sh"),
+    ("zsh", "sh"),
+    ("dash", "sh"),
+    ("ksh", "sh"),
+    ("node", "js"),
+    ("deno", "ts"),
+    ("ts-node", "ts"),
+    ("ruby", "rb"),
+    ("perl", "pl"),
+    ("php", "php"),
+    ("lua", "lua"),
+    ("fish", "fish"),
+];
+
+/// The extension a script's `#!` line implies, e.g. `py` for `#!/usr/bin/env python3`.
+fn shebang_extension(path: &Path) -> Option<&'static str> {
+    let mut head = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(SHEBANG_BYTES).read_to_end(&mut head))
+        .ok()?;
+    let line = head
+        .strip_prefix(b"#!")?
+        .split(|byte| *byte == b'\n')
+        .next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let mut words = line.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+    }
+    let name = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    SHEBANG_INTERPRETERS
+        .iter()
+        .find(|(interpreter, _)| *interpreter == name)
+        .map(|(_, ext)| *ext)
+}
+
+/// Tokenizes `files` in parallel with `encoders` (see [`load_encoders`]),
+/// logging and skipping any that cannot be read or tokenized.
+pub fn count_tokens(
+    files: Vec<PathBuf>,
+    options: &CountOptions,
+    encoders: &[Arc<dyn Tokenizer>],
+) -> Scan {
+    let encoders: Vec<&dyn Tokenizer> = encoders.iter().map(|encoder| encoder.as_ref()).collect();
+    // Loaded up front and only read inside the parallel section; this run's
+    // entries are merged in and written once everything has been counted.
+    let cache = options.cache.as_deref().map(|path| {
+        TokenCache::load(path).unwrap_or_else(|err| {
+            warn!("ignoring cache: {err:#}");
+            TokenCache::default()
+        })
+    });
+    let skipped_binary = AtomicU64::new(0);
+    let too_large = AtomicU64::new(0);
+    let too_small = AtomicU64::new(0);
+    let counted: Vec<Counted> = files
+        .par_iter()
+        .filter_map(|path| {
+            let result = read_and_count(path, options, &encoders, cache.as_ref());
+            if let Some(progress) = &options.progress {
+                progress.fetch_add(1, Ordering::Relaxed);
+            }
+            match result {
+                Ok(counted) => Some(counted),
+                Err(err @ ProcessError::Binary { .. }) => {
+                    debug!("{}", err);
+                    skipped_binary.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
+                Err(err @ ProcessError::TooLarge { .. }) => {
+                    info!("{}", err);
+                    too_large.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
+                // Expected for stubs and empty files, so quieter than TooLarge.
+                Err(err @ ProcessError::TooSmall { .. }) => {
+                    debug!("{}", err);
+                    too_small.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
+                Err(err) => {
+                    warn!("{}", err);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    // Sequential so the first path in walk order wins, whatever rayon's scheduling.
+    let mut seen: HashMap<u64, String> = HashMap::new();
+    let mut duplicates = 0;
+    let mut stats = Vec::with_capacity(counted.len());
+    let mut cache = cache.unwrap_or_default();
+    for Counted { stat, hash, entry } in counted {
+        if let Some((key, entry)) = entry {
+            cache.insert(key, entry);
+        }
+        if let Some(hash) = hash {
+            if let Some(first) = seen.get(&hash) {
+                info!("skipping {}: same content as {}", stat.path, first);
+                duplicates += 1;
+                continue;
+            }
+            seen.insert(hash, stat.path.clone());
+        }
+        stats.push(stat);
+    }
+    if let Some(path) = &options.cache {
+        cache.retain_existing();
+        if let Err(err) = cache.save(path) {
+            warn!("{err:#}");
+        }
+    }
+    Scan {
+        stats,
+        duplicates,
+        skipped_binary: skipped_binary.into_inner(),
+        skipped_too_large: too_large.into_inner(),
+        skipped_too_small: too_small.into_inner(),
+    }
+}
+
+/// One file's result, with what `count_tokens` needs for dedup and caching.
+struct Counted {
+    stat: FileStat,
+    hash: Option<u64>,
+    entry: Option<(String, CacheEntry)>,
+}
+
+/// Reads and tokenizes a single file.
+pub fn process_file(
+    path: &Path,
+    options: &CountOptions,
+    encoding: &dyn Tokenizer,
+) -> std::result::Result<FileStat, ProcessError> {
+    read_and_count(path, options, &[encoding], None).map(|counted| counted.stat)
+}
+
+/// Like [`process_file`], plus a content hash when `options.dedup` is set and
+/// a cache lookup/refresh when `cache` is given.
+fn read_and_count(
+    path: &Path,
+    options: &CountOptions,
+    encoders: &[&dyn Tokenizer],
+    cache: Option<&TokenCache>,
+) -> std::result::Result<Counted, ProcessError> {
+    let display_path = display_path(path, options);
+    // One open plus an fstat on the handle serves the size check, the cache
+    // fingerprint and the read, instead of a separate stat of the path.
+    let file = File::open(path).map_err(|source| ProcessError::Read {
+        path: display_path.clone(),
+        source,
+    })?;
+    let metadata = file.metadata().map_err(|source| ProcessError::Metadata {
+        path: display_path.clone(),
+        source,
+    })?;
+
+    if let Some(limit) = options.max_bytes {
+        if metadata.len() > limit {
+            return Err(ProcessError::TooLarge {
+                path: display_path.clone(),
+                size: metadata.len(),
+                limit,
+            });
+        }
+    }
+    if let Some(limit) = options.min_bytes {
+        if metadata.len() < limit {
+            return Err(ProcessError::TooSmall {
+                path: display_path.clone(),
+                size: metadata.len(),
+                limit,
+            });
+        }
+    }
+
+    let current = CacheEntry::fingerprint(&metadata, &options.cache_encoding_name());
+    let cache_key = cache.map(|_| cache_key(path));
+    // Extended metrics, dedup hashes and comparisons need the contents, so
+    // they bypass hits.
+    if let (Some(cache), Some(key)) = (cache, &cache_key) {
+        if !options.extended && !options.dedup && encoders.len() == 1 {
+            // A lossy count is only a valid answer when lossy decoding is on.
+            let hit = cache
+                .lookup(key, &current)
+                .filter(|cached| options.lossy || !cached.lossy);
+            if let Some(cached) = hit {
+                let mut stat = FileStat::new(display_path, cached.tokens);
+                stat.lossy = cached.lossy.then_some(true);
+                stat.charset = cached.charset.clone();
+                return Ok(Counted {
+                    stat,
+                    hash: None,
+                    entry: Some((key.clone(), cached.clone())),
+                });
+            }
+        }
+    }
+
+    if let Some(chunk_bytes) = options.chunk_bytes.filter(|_| !options.transcode) {
+        if metadata.len() > chunk_bytes {
+            let (stat, hash) =
+                read_and_count_chunked(file, display_path, options, encoders, chunk_bytes)?;
+            let entry = cache_key.map(|key| (key, stat.cache_entry(current)));
+            return Ok(Counted { stat, hash, entry });
+        }
+    }
+
+    let bytes = read_bytes(path, file, metadata.len(), options.mmap).map_err(|source| {
+        ProcessError::Read {
+            path: display_path.clone(),
+            source,
+        }
+    })?;
+    // UTF-16 is full of NUL bytes, so a BOM vouches for the file being text.
+    let bom = options.transcode && encoding_rs::Encoding::for_bom(&bytes).is_some();
+    if options.skip_binary && !bom && looks_binary(&bytes) {
+        return Err(ProcessError::Binary { path: display_path });
+    }
+    let (contents, lossy, charset) = decode(&bytes, &display_path, options)?;
+
+    let hash = options.dedup.then(|| XxHash3_64::oneshot(&bytes));
+    let mut stat =
+        count_contents(display_path.clone(), &contents, encoders, options).map_err(|source| {
+            ProcessError::Tokenize {
+                path: display_path,
+                source,
+            }
+        })?;
+    stat.lossy = lossy.then_some(true);
+    stat.charset = charset;
+    let entry = cache_key.map(|key| (key, stat.cache_entry(current)));
+    Ok(Counted { stat, hash, entry })
+}
+
+/// Decodes file contents the way `options` asks, returning the text, whether
+/// anything was replaced under `lossy` and the charset under `transcode`.
+fn decode<'a>(
+    bytes: &'a [u8],
+    display_path: &str,
+    options: &CountOptions,
+) -> std::result::Result<(Cow<'a, str>, bool, Option<String>), ProcessError> {
+    if options.transcode {
+        let (text, name) = transcode(bytes).map_err(|charset| ProcessError::Undecodable {
+            path: display_path.to_string(),
+            charset,
+        })?;
+        Ok((text, false, Some(name.to_string())))
+    } else if options.lossy {
+        let text = String::from_utf8_lossy(bytes);
+        // from_utf8_lossy only allocates when it had to replace something.
+        let lossy = matches!(text, Cow::Owned(_));
+        Ok((text, lossy, None))
+    } else {
+        let text = std::str::from_utf8(bytes).map_err(|_| ProcessError::InvalidUtf8 {
+            path: display_path.to_string(),
+        })?;
+        Ok((Cow::Borrowed(text), false, None))
+    }
+}
+
+/// Reads a file as text, decoded as it would be for counting with `options`.
+pub fn read_text(path: &Path, options: &CountOptions) -> std::result::Result<String, ProcessError> {
+    let display_path = normalize_display_path(path);
+    let bytes = fs::read(path).map_err(|source| ProcessError::Read {
+        path: display_path.clone(),
+        source,
+    })?;
+    decode(&bytes, &display_path, options).map(|(text, _, _)| text.into_owned())
+}
+
+/// Decodes `bytes` for `--transcode`: a byte order mark picks UTF-8 or UTF-16
+/// and is dropped, anything else is UTF-8 or else windows-1252 (a superset of
+/// Latin-1). Fails with the charset name when the BOM's encoding is malformed.
+fn transcode(bytes: &[u8]) -> Result<(Cow<'_, str>, &'static str), &'static str> {
+    if let Some((encoding, bom)) = encoding_rs::Encoding::for_bom(bytes) {
+        return encoding
+            .decode_without_bom_handling_and_without_replacement(&bytes[bom..])
+            .map(|text| (text, encoding.name()))
+            .ok_or(encoding.name());
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok((Cow::Borrowed(text), encoding_rs::UTF_8.name())),
+        Err(_) => {
+            let latin = encoding_rs::WINDOWS_1252;
+            Ok((latin.decode_without_bom_handling(bytes).0, latin.name()))
+        }
+    }
+}
+
+/// File contents, either memory-mapped or read into a buffer.
+enum FileBytes {
+    Mapped(Mmap),
+    Read(Vec<u8>),
+}
+
+impl Deref for FileBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileBytes::Mapped(map) => map,
+            FileBytes::Read(bytes) => bytes,
+        }
+    }
+}
+
+/// Maps non-empty files when `mmap` is set, falling back to a plain read.
+fn read_bytes(path: &Path, mut file: File, len: u64, mmap: bool) -> std::io::Result<FileBytes> {
+    if mmap && len > 0 {
+        // SAFETY: the map is read-only and dropped once the file is counted; a
+        // concurrent writer can at worst skew this file's count, like a racing read.
+        match unsafe { Mmap::map(&file) } {
+            Ok(map) => return Ok(FileBytes::Mapped(map)),
+            Err(err) => debug!("mmap failed for {}, reading instead: {err}", path.display()),
+        }
+    }
+    let mut bytes = Vec::with_capacity(len as usize);
+    file.read_to_end(&mut bytes)?;
+    Ok(FileBytes::Read(bytes))
+}
+
+/// Streams `path` through the encoder one line-aligned chunk at a time.
+fn read_and_count_chunked(
+    file: File,
+    display_path: String,
+    options: &CountOptions,
+    encoders: &[&dyn Tokenizer],
+    chunk_bytes: u64,
+) -> std::result::Result<(FileStat, Option<u64>), ProcessError> {
+    let read_error = |source| ProcessError::Read {
+        path: display_path.clone(),
+        source,
+    };
+    let chunk_bytes = (chunk_bytes as usize).max(SNIFF_BYTES);
+    let mut reader = BufReader::new(file);
+    let mut hasher = options.dedup.then(XxHash3_64::new);
+    let mut chunk = Vec::with_capacity(chunk_bytes);
+    let mut first = true;
+    let mut tokens = vec![0; encoders.len()];
+    let (mut lines, mut bytes, mut chars) = (0, 0, 0);
+    let mut lossy = false;
+
+    loop {
+        let read = reader.read_until(b'\n', &mut chunk).map_err(read_error)?;
+        if read > 0 {
+            lines += 1;
+            if chunk.len() < chunk_bytes {
+                continue;
+            }
+        }
+        if chunk.is_empty() {
+            break;
+        }
+
+        if first && options.skip_binary && looks_binary(&chunk) {
+            return Err(ProcessError::Binary { path: display_path });
+        }
+        first = false;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.write(&chunk);
+        }
+        let text = if options.lossy {
+            String::from_utf8_lossy(&chunk)
+        } else {
+            let text = std::str::from_utf8(&chunk).map_err(|_| ProcessError::InvalidUtf8 {
+                path: display_path.clone(),
+            })?;
+            Cow::Borrowed(text)
+        };
+        lossy |= matches!(text, Cow::Owned(_));
+        for (total, encoder) in tokens.iter_mut().zip(encoders) {
+            *total += encoder
+                .count(&text)
+                .map_err(|source| ProcessError::Tokenize {
+                    path: display_path.clone(),
+                    source,
+                })?;
+        }
+        bytes += text.len() as u64;
+        chars += text.chars().count() as u64;
+        chunk.clear();
+        if read == 0 {
+            break;
+        }
+    }
+
+    let mut stat = FileStat::new(display_path, tokens.first().copied().unwrap_or_default());
+    stat.lossy = lossy.then_some(true);
+    if encoders.len() > 1 {
+        stat = stat.with_comparison(&options.encoder_names(), &tokens);
+    }
+    if options.extended {
+        stat.lines = Some(lines);
+        stat.bytes = Some(bytes);
+        stat.chars = Some(chars);
+    }
+    Ok((stat, hasher.map(|hasher| hasher.finish())))
+}
+
+fn cache_key(path: &Path) -> String {
+    std::path::absolute(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Sniffs the first 8 KiB: any NUL byte, or more than 30% control bytes, means binary.
+fn looks_binary(bytes: &[u8]) -> bool {
+    let head = &bytes[..bytes.len().min(SNIFF_BYTES)];
+    if head.contains(&0) {
+        return true;
+    }
+    let control = head
+        .iter()
+        .filter(|&&b| (b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b)) || b == 0x7f)
+        .count();
+    control * 10 > head.len() * 3
+}
+
+/// Tokenizes `contents`, reporting it under `path`.
+pub fn count_text(
+    path: String,
+    contents: &str,
+    encoding: &dyn Tokenizer,
+) -> std::result::Result<FileStat, TokenizeError> {
+    Ok(FileStat::new(path, encoding.count(contents)?))
+}
+
+/// Tokenizes `contents` with every encoder, adding comparison counts when
+/// there is more than one and line/byte/char counts if `options.extended`.
+pub fn count_contents(
+    path: String,
+    contents: &str,
+    encoders: &[&dyn Tokenizer],
+    options: &CountOptions,
+) -> std::result::Result<FileStat, TokenizeError> {
+    let counts = encoders
+        .iter()
+        .map(|encoder| encoder.count(contents))
+        .collect::<std::result::Result<Vec<u64>, _>>()?;
+    let mut stat = FileStat::new(path, counts.first().copied().unwrap_or_default());
+    if counts.len() > 1 {
+        stat = stat.with_comparison(&options.encoder_names(), &counts);
+    }
+    if options.extended {
+        stat = stat.with_metrics(contents);
+    }
+    Ok(stat)
+}
+
+/// Computes totals and percentiles; the breakdown and cost fields start empty.
+pub fn build_summary(all_stats: &[FileStat], top: Option<Vec<FileStat>>) -> Summary {
+    build_summary_with(all_stats, top, PercentileMethod::Nearest)
+}
+
+/// [`build_summary`] with the percentiles computed by `method`.
+pub fn build_summary_with(
+    all_stats: &[FileStat],
+    top: Option<Vec<FileStat>>,
+    method: PercentileMethod,
+) -> Summary {
+    let files = all_stats.len() as u64;
+    let total: u64 = all_stats.iter().map(|s| s.tokens).sum();
+    let average = if files > 0 {
+        total as f64 / files as f64
+    } else {
+        0.0
+    };
+    let mut counts: Vec<u64> = all_stats.iter().map(|s| s.tokens).collect();
+    counts.sort_unstable();
+    let totals = all_stats.first().and_then(|s| s.counts.as_ref()).map(|_| {
+        let mut totals = BTreeMap::new();
+        for counts in all_stats.iter().filter_map(|s| s.counts.as_ref()) {
+            for (name, tokens) in counts {
+                *totals.entry(name.clone()).or_insert(0) += tokens;
+            }
+        }
+        totals
+    });
+
+    Summary {
+        files,
+        total,
+        average,
+        p50: percentile(&counts, 0.50, method),
+        p90: percentile(&counts, 0.90, method),
+        p99: percentile(&counts, 0.99, method),
+        percentiles: None,
+        encoding: None,
+        totals,
+        top,
+        by_extension: None,
+        by_directory: None,
+        cost: None,
+        total_cost: None,
+        histogram: None,
+        duplicates: None,
+        skipped_binary: None,
+        skipped_too_large: None,
+        skipped_too_small: None,
+        lossy_files: None,
+        hidden_files: None,
+        files_over_window: None,
+        context_window: None,
+        files_fitting_individually: None,
+        files_fitting_packed: None,
+        violations: None,
+        total_delta: None,
+        removed_files: None,
+        total_with_overhead: None,
+    }
+}
+
+/// Tokens every chat request adds once to prime the reply (`<|start|>assistant<|message|>`).
+pub const CHAT_REPLY_PRIMING: u64 = 3;
+
+/// Input prices in dollars per million tokens, as published by OpenAI.
+pub const MODEL_PRICES: &[(&str, f64)] = &[
+    ("gpt-4o", 2.50),
+    ("gpt-4o-mini", 0.15),
+    ("gpt-4-turbo", 10.00),
+    ("gpt-4", 30.00),
+    ("gpt-3.5-turbo", 0.50),
+    ("text-embedding-3-small", 0.02),
+    ("text-embedding-3-large", 0.13),
+    ("text-embedding-ada-002", 0.10),
+];
+
+/// Looks up a model's input price per million tokens in [`MODEL_PRICES`].
+pub fn model_price(model: &str) -> Option<f64> {
+    MODEL_PRICES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(model))
+        .map(|(_, price)| *price)
+}
+
+/// Context window sizes in tokens for well-known models.
+pub const MODEL_CONTEXT_WINDOWS: &[(&str, u64)] = &[
+    ("gpt-4o", 128_000),
+    ("gpt-4o-mini", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4", 8_192),
+    ("gpt-4-32k", 32_768),
+    ("gpt-3.5-turbo", 16_385),
+    ("text-embedding-3-small", 8_191),
+    ("text-embedding-3-large", 8_191),
+    ("text-embedding-ada-002", 8_191),
+];
+
+/// Looks up a model's context window in [`MODEL_CONTEXT_WINDOWS`].
+pub fn context_window(model: &str) -> Option<u64> {
+    MODEL_CONTEXT_WINDOWS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(model))
+        .map(|(_, window)| *window)
+}
+
+/// `tokens * price_per_million / 1_000_000`, without rounding.
+pub fn cost_per_million(tokens: u64, price_per_million: f64) -> f64 {
+    tokens as f64 * price_per_million / 1_000_000.0
+}
+
+pub fn estimate_cost(tokens: u64, price_per_1k: f64) -> f64 {
+    let cost = tokens as f64 / 1000.0 * price_per_1k;
+    (cost * 1_000_000.0).round() / 1_000_000.0
+}
+
+pub fn extension_breakdown(stats: &[FileStat]) -> BTreeMap<String, ExtensionStat> {
+    let mut breakdown: BTreeMap<String, ExtensionStat> = BTreeMap::new();
+    for stat in stats {
+        let entry = breakdown.entry(extension_key(&stat.path)).or_default();
+        entry.files += 1;
+        entry.tokens += stat.tokens;
+    }
+    breakdown
+}
+
+/// One row per lowercase extension, sorted by extension.
+pub fn group_by_extension(stats: &[FileStat]) -> Vec<ExtensionGroup> {
+    let mut groups: BTreeMap<String, ExtensionGroup> = BTreeMap::new();
+    for stat in stats {
+        let ext = extension_key(&stat.path);
+        let group = groups.entry(ext.clone()).or_insert_with(|| ExtensionGroup {
+            ext,
+            files: 0,
+            tokens: 0,
+            average: 0.0,
+            max: 0,
+        });
+        group.files += 1;
+        group.tokens += stat.tokens;
+        group.max = group.max.max(stat.tokens);
+    }
+    groups
+        .into_values()
+        .map(|mut group| {
+            group.average = group.tokens as f64 / group.files as f64;
+            group
+        })
+        .collect()
+}
+
+fn extension_key(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_else(|| String::from(NO_EXTENSION))
+}
+
+/// Powers of ten from 10 up to the first edge that covers `max`.
+pub fn log_edges(max: u64) -> Vec<u64> {
+    let mut edges = vec![10];
+    let mut edge = 10u64;
+    while edge < max {
+        match edge.checked_mul(10) {
+            Some(next) => edge = next,
+            None => break,
+        }
+        edges.push(edge);
+    }
+    edges
+}
+
+/// `buckets - 1` evenly spaced edges, so that with the overflow bucket
+/// [`histogram`] returns `buckets` buckets and the last one starts below `max`.
+pub fn linear_edges(max: u64, buckets: u64) -> Vec<u64> {
+    let width = max.div_ceil(buckets.max(1)).max(1);
+    (1..buckets).map(|i| i * width).collect()
+}
+
+/// How many of `counts`, taken in order, fit into `window` tokens together.
+/// A count that would overflow what is left is skipped, not a stopping point.
+pub fn packed_count(counts: impl IntoIterator<Item = u64>, window: u64) -> u64 {
+    let mut left = window;
+    let mut packed = 0;
+    for count in counts {
+        if count <= left {
+            left -= count;
+            packed += 1;
+        }
+    }
+    packed
+}
+
+/// Buckets token counts by ascending `edges`, plus a trailing overflow bucket.
+pub fn histogram(stats: &[FileStat], edges: &[u64]) -> Vec<HistogramBucket> {
+    let starts = std::iter::once(0).chain(edges.iter().map(|&edge| edge + 1));
+    let ends = edges.iter().copied().map(Some).chain(std::iter::once(None));
+    let mut buckets: Vec<HistogramBucket> = starts
+        .zip(ends)
+        .map(|(range_start, le)| HistogramBucket {
+            le,
+            range_start,
+            range_end: le,
+            count: 0,
+        })
+        .collect();
+    for stat in stats {
+        let index = edges.partition_point(|&edge| edge < stat.tokens);
+        buckets[index].count += 1;
+    }
+    buckets
+}
+
+pub fn directory_breakdown(stats: &[FileStat], depth: usize) -> Vec<DirectoryStat> {
+    let mut totals: HashMap<String, (u64, u64)> = HashMap::new();
+    for stat in stats {
+        let parent = Path::new(&stat.path).parent().unwrap_or(Path::new(""));
+        let truncated: PathBuf = parent.components().take(depth).collect();
+        let key = if truncated.as_os_str().is_empty() {
+            String::from(".")
+        } else {
+            truncated.to_string_lossy().into_owned()
+        };
+        let entry = totals.entry(key).or_default();
+        entry.0 += 1;
+        entry.1 += stat.tokens;
+    }
+
+    let mut dirs: Vec<DirectoryStat> = totals
+        .into_iter()
+        .map(|(path, (files, tokens))| DirectoryStat {
+            path,
+            files,
+            tokens,
+        })
+        .collect();
+    dirs.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.path.cmp(&b.path)));
+    dirs
+}
+
+/// The `percentile` (0.0 to 1.0) of ascending `sorted` counts; 0 when empty.
+pub fn percentile(sorted: &[u64], percentile: f64, method: PercentileMethod) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    match method {
+        PercentileMethod::Nearest => {
+            let rank = (percentile * (sorted.len() as f64)).ceil().max(1.0) as usize;
+            sorted[rank.saturating_sub(1).min(sorted.len() - 1)] as f64
+        }
+        PercentileMethod::Linear => {
+            let position = percentile.clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+            let below = position.floor() as usize;
+            let above = (below + 1).min(sorted.len() - 1);
+            let (low, high) = (sorted[below] as f64, sorted[above] as f64);
+            low + (high - low) * (position - below as f64)
+        }
+    }
+}
+
+/// The path a file is reported under: relative to the cwd, or canonical with
+/// `options.absolute`.
+pub fn display_path(path: &Path, options: &CountOptions) -> String {
+    if options.absolute {
+        absolute_display_path(path)
+    } else {
+        normalize_display_path(path)
+    }
+}
+
+/// Canonical absolute path, falling back to the raw path when it cannot be
+/// resolved (e.g. a broken symlink).
+fn absolute_display_path(path: &Path) -> String {
+    fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn normalize_display_path(path: &Path) -> String {
+    if let Ok(cwd) = std::env::current_dir() {
+        if let Ok(stripped) = path.strip_prefix(&cwd) {
+            let display = stripped.to_string_lossy();
+            return if display.is_empty() {
+                String::from(".")
+            } else {
+                display.into_owned()
+            };
+        }
+    }
+    if let Ok(stripped) = path.strip_prefix(Path::new(".")) {
+        let display = stripped.to_string_lossy();
+        if display.is_empty() {
+            return String::from(".");
+        }
+        return display.into_owned();
+    }
+    path.to_string_lossy().into_owned()
+}
@@ -7,122 +7,919 @@
 //! # scan a directory, include TS + Elm, respect .gitignore
 //! tokencount ./frontend --include-ext elm --include-ext ts
 //!
+//! # only Rust sources under src/, not tests/
+//! tokencount --include 'src/**/*.rs'
+//!
 //! # top-10 largest by tokens
 //! tokencount --top 10
 //!
 //! # JSON summary for CI
 //! tokencount --format json > tokens.json
 //!
+//! # just the total, for shell scripts
+//! if [ "$(tokencount --total)" -gt 100000 ]; then echo "too big"; fi
+//!
+//! # JSON Schema of the --format json output
+//! tokencount --print-schema
+//!
+//! # JSON object with version, files and summary keys
+//! tokencount --format json2 | jq '.files[].path'
+//!
+//! # shell completions (bash, zsh, fish, powershell, elvish)
+//! tokencount completions bash > /etc/bash_completion.d/tokencount
+//!
+//! # YAML with the same files and summary keys
+//! tokencount --format yaml
+//!
 //! # NDJSON streaming
 //! tokencount --format ndjson
 //!
+//! # CSV for spreadsheets
+//! tokencount --format csv
+//!
+//! # Markdown table for PR comments
+//! tokencount --format markdown
+//!
+//! # self-contained HTML report
+//! tokencount --format html > report.html
+//!
+//! # JUnit XML for CI, failing files above 2000 tokens
+//! tokencount --format junit --max-file-tokens 2000
+//!
+//! # GitHub Actions annotations plus a step summary
+//! tokencount --format github --max-file-tokens 2000
+//!
+//! # Prometheus textfile, per-file series bounded to the top 50
+//! tokencount --format prometheus --top 50
+//!
+//! # estimated cost at $0.00013 per 1k tokens
+//! tokencount --price-per-1k 0.00013
+//!
+//! # per-file and total dollar cost from a per-million price or a known model
+//! tokencount --price-per-million 2.50
+//! tokencount --price-model gpt-4o-mini
+//!
+//! # token IDs of one file, or each ID next to its decoded piece
+//! tokencount --dump-tokens src/Main.elm
+//! tokencount --dump-tokens --decode-each src/Main.elm
+//!
+//! # totals as separate chat messages, wrapper tokens included
+//! tokencount --chat-overhead
+//! tokencount --chat-overhead=4
+//!
+//! # JSON report to a file, table on the terminal
+//! tokencount --format json --output report.json --also-table
+//!
+//! # feed the 20 largest files to xargs
+//! tokencount --top 20 -0 | xargs -0 wc -l
+//!
+//! # fail CI (exit code 2) when the codebase exceeds 100k tokens
+//! tokencount --max-total-tokens 100000
+//!
+//! # mark files over 8k tokens and fail if any exist
+//! tokencount --max-file-tokens 8000 --strict
+//!
+//! # hide files below 500 tokens, summarize only what is shown
+//! tokencount --min-tokens 500 --summary-scope filtered
+//!
+//! # choose and order columns
+//! tokencount --format csv --fields tokens,path
+//!
+//! # one line per file from a template
+//! tokencount --template '{{tokens}}\t{{path}}'
+//!
+//! # human-readable counts (1.2k, 3.4M)
+//! tokencount --human
+//!
+//! # aggregate numbers only, no per-file rows
+//! tokencount --summary-only --format json
+//!
+//! # distribution of file sizes, log-scaled or with explicit edges
+//! tokencount --histogram
+//! tokencount --histogram=100,500,1000,5000
+//!
+//! # one row per language, largest first
+//! tokencount --include-ext elm --include-ext ts --group-by ext --sort tokens
+//!
+//! # heaviest directories two levels deep
+//! tokencount --group-by dir --group-depth 2 --sort tokens
+//!
+//! # wc-style lines/bytes/chars and bytes per token, to spot minified files
+//! tokencount --extended
+//!
+//! # du-style tree with directory subtotals, two levels deep
+//! tokencount --format tree --tree-depth 2
+//!
+//! # count copied or symlinked files once
+//! tokencount . vendor --dedup
+//!
+//! # skip re-tokenizing unchanged files on repeat runs
+//! tokencount --cache .tokencount-cache.json
+//!
+//! # pick the encoding by model name
+//! tokencount --model gpt-4o
+//!
+//! # use an in-house tiktoken-format vocabulary
+//! tokencount --encoding-file ours.tiktoken --special-token '<|end|>=50000'
+//!
+//! # which files do not fit a context window on their own (exit 3 with --fail-if-any-over)
+//! tokencount --fits gpt-4o --fail-if-any-over
+//!
 //! # sort by tokens desc
 //! tokencount --sort tokens
+//!
+//! # count a prompt piped on stdin
+//! cat prompt.txt | tokencount --stdin
+//!
+//! # count literal strings without touching the filesystem
+//! tokencount --text "hello world" --text "goodbye"
+//! tokencount text "hello world"
+//!
+//! # per-file deltas between two saved JSON reports
+//! tokencount diff before.json after.json
+//!
+//! # token IDs of one file
+//! tokencount tokens prompt.txt
 //! ```
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
-use clap::{ArgAction, Parser, ValueEnum};
-use globset::{Glob, GlobSet, GlobSetBuilder};
-use ignore::WalkBuilder;
-use log::{debug, info, warn};
-use rayon::prelude::*;
-use serde::Serialize;
-use thiserror::Error;
-use tiktoken_rs::{cl100k_base, o200k_base, CoreBPE};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::builder::{PossibleValue, TypedValueParser};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use indicatif::ProgressBar;
+use log::{info, warn};
+use notify::{RecursiveMode, Watcher};
+use schemars::JsonSchema;
+use serde::ser::{SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tokencount::{
+    build_summary, build_summary_with, changed_files, collect_files, context_window,
+    cost_per_million, count_contents, count_tokens, cwd_prefix, directory_breakdown, display_path,
+    encode_ids, estimate_cost, export_revision, extension_breakdown, from_top_level,
+    group_by_extension, histogram, linear_edges, load_encoders, log_edges, model_price,
+    packed_count, percentiles, read_text, CountOptions, DirectoryStat, EncoderSource, Encoding,
+    ExtensionGroup, FileStat, HistogramBucket, PercentileMethod, Percentiles, Scan, Summary,
+    Tokenizer, Violation, CHAT_REPLY_PRIMING, COMMON_FILE_NAMES, MODEL_CONTEXT_WINDOWS,
+    MODEL_ENCODINGS, MODEL_PRICES,
+};
 
 #[derive(Debug, Parser)]
 #[command(name = "tokencount", version, about = "Count GPT tokens across files.", long_about = None)]
+// `tokencount help` would shadow a directory named help for no gain over --help.
+#[command(disable_help_subcommand = true, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Scanning is what runs without a subcommand.
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Count tokens across files; the same as giving no subcommand.
+    Scan(Box<Args>),
+    /// Print the token count of a string.
+    Text(TextArgs),
+    /// Compare two JSON reports file by file.
+    Diff(DiffArgs),
+    /// Print the token IDs of one file.
+    Tokens(TokensArgs),
+    /// Print a completion script for SHELL to stdout.
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct TextArgs {
+    /// Text to count (`-` reads stdin).
+    #[arg(value_name = "STRING")]
+    text: String,
+
+    #[command(flatten)]
+    tokenizer: TokenizerArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+#[derive(Debug, clap::Args)]
+struct DiffArgs {
+    /// Earlier report: `--format json` or json2 output, or a --write-baseline snapshot.
+    #[arg(value_name = "OLD")]
+    old: PathBuf,
+
+    /// Later report, in any of the same forms.
+    #[arg(value_name = "NEW")]
+    new: PathBuf,
+
+    /// Output format to use (table, json or json2).
+    #[arg(long = "format", value_enum, default_value = "table")]
+    format: OutputFormat,
+
+    /// Sort order for output; tokens puts the largest changes first.
+    #[arg(long = "sort", value_enum, default_value = "path")]
+    sort: SortBy,
+
+    /// Flip the --sort order.
+    #[arg(long = "reverse", action = ArgAction::SetTrue)]
+    reverse: bool,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+#[derive(Debug, clap::Args)]
+struct TokensArgs {
+    /// File to tokenize.
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+
+    /// Print one `id<TAB>piece` line per token instead of a JSON array.
+    #[arg(long = "decode-each", action = ArgAction::SetTrue)]
+    decode_each: bool,
+
+    /// Replace invalid UTF-8 instead of failing, as a scan with --lossy would.
+    #[arg(long = "lossy", action = ArgAction::SetTrue)]
+    lossy: bool,
+
+    /// Decode the file by its byte order mark or as windows-1252, as a scan with --transcode would.
+    #[arg(long = "transcode", action = ArgAction::SetTrue)]
+    transcode: bool,
+
+    #[command(flatten)]
+    tokenizer: TokenizerArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+#[derive(Debug, clap::Args)]
 struct Args {
-    /// Paths to scan (defaults to current directory).
+    /// Paths to scan (defaults to current directory; `-` reads stdin).
     #[arg(value_name = "PATH", default_value = ".")]
     paths: Vec<PathBuf>,
 
-    /// File extensions to include (can repeat, default: elm).
+    /// File extensions to include (can repeat or be comma-separated; * for every file).
+    /// Defaults to TOKENCOUNT_EXT when set, else elm.
     #[arg(long = "include-ext", value_name = "EXT", action = ArgAction::Append)]
     include_ext: Vec<String>,
 
+    /// File extensions to leave out, checked against the end of the file name
+    /// so compound suffixes work (can repeat or be comma-separated, e.g. d.ts).
+    #[arg(long = "exclude-ext", value_name = "EXT", action = ArgAction::Append)]
+    exclude_ext: Vec<String>,
+
+    /// Count every file regardless of extension (same as --include-ext '*').
+    #[arg(long = "all-text", action = ArgAction::SetTrue, conflicts_with = "include_ext")]
+    all_text: bool,
+
+    /// Glob patterns to include, relative to each PATH (can repeat).
+    #[arg(long = "include", value_name = "GLOB", action = ArgAction::Append)]
+    include: Vec<String>,
+
+    /// Exact file names to include on top of the extensions, e.g. Dockerfile (can repeat).
+    #[arg(long = "include-name", value_name = "NAME", action = ArgAction::Append)]
+    include_name: Vec<String>,
+
+    /// Include common extension-less build files (Dockerfile, Makefile, Justfile, ...).
+    #[arg(long = "include-common-names", action = ArgAction::SetTrue)]
+    include_common_names: bool,
+
+    /// Match --include-name and --include-common-names ignoring case.
+    #[arg(long = "ignore-name-case", action = ArgAction::SetTrue)]
+    ignore_name_case: bool,
+
+    /// Include extension-less scripts whose #! line names an interpreter for an included extension.
+    #[arg(long = "detect-shebang", action = ArgAction::SetTrue)]
+    detect_shebang: bool,
+
     /// Glob patterns to exclude (e.g. node_modules/**).
     #[arg(long = "exclude", value_name = "GLOB", action = ArgAction::Append)]
     exclude: Vec<String>,
 
-    /// Disable respecting .gitignore files.
+    /// Disable respecting .gitignore files (.tokencountignore still applies).
     #[arg(long = "no-respect-gitignore", action = ArgAction::SetTrue)]
     no_respect_gitignore: bool,
 
+    /// Disable respecting .ignore files (.tokencountignore still applies).
+    #[arg(long = "no-respect-ignore", action = ArgAction::SetTrue)]
+    no_respect_ignore: bool,
+
     /// Follow symlinks when walking.
     #[arg(long = "follow-symlinks", action = ArgAction::SetTrue)]
     follow_symlinks: bool,
 
+    /// Include hidden files and directories (the default).
+    #[arg(long = "hidden", action = ArgAction::SetTrue, overrides_with = "no_hidden")]
+    hidden: bool,
+
+    /// Skip hidden files and directories, like git's default view of a tree.
+    #[arg(long = "no-hidden", action = ArgAction::SetTrue, overrides_with = "hidden")]
+    no_hidden: bool,
+
+    /// Only descend N directory levels below each PATH (1 = direct children only).
+    #[arg(long = "depth", value_name = "N")]
+    depth: Option<usize>,
+
     /// Skip files larger than this many bytes.
     #[arg(long = "max-bytes", value_name = "BYTES")]
     max_bytes: Option<u64>,
 
-    /// Encoding/model to use for tokenization.
-    #[arg(long = "encoding", value_enum, default_value = "cl100k-base")]
-    encoding: Encoding,
+    /// Skip files smaller than this many bytes.
+    #[arg(long = "min-bytes", value_name = "BYTES")]
+    min_bytes: Option<u64>,
+
+    /// Tokenize files larger than BYTES in line-aligned chunks of about that size
+    /// to bound memory; counts may drift by a token or so per chunk.
+    #[arg(long = "chunk-bytes", value_name = "BYTES")]
+    chunk_bytes: Option<u64>,
+
+    /// Memory-map files instead of reading them (can be faster on large scans).
+    #[arg(long = "mmap", action = ArgAction::SetTrue)]
+    mmap: bool,
+
+    /// Count files with invalid UTF-8 by replacing the bad bytes instead of skipping them.
+    #[arg(long = "lossy", action = ArgAction::SetTrue)]
+    lossy: bool,
+
+    /// Decode UTF-16 and BOM-marked UTF-8 by their byte order mark, and non-UTF-8 files as windows-1252.
+    #[arg(long = "transcode", action = ArgAction::SetTrue)]
+    transcode: bool,
+
+    /// Count files that look binary (NUL bytes or mostly control characters) instead of skipping them.
+    #[arg(long = "no-skip-binary", visible_alias = "no-binary-check", action = ArgAction::SetTrue)]
+    no_skip_binary: bool,
+
+    /// Report canonical absolute paths instead of paths relative to the cwd.
+    #[arg(long = "absolute", action = ArgAction::SetTrue)]
+    absolute: bool,
+
+    #[command(flatten)]
+    tokenizer: TokenizerArgs,
 
     /// Output format to use.
     #[arg(long = "format", value_enum, default_value = "table")]
     format: OutputFormat,
 
+    /// Print only the total token count, whatever --format says.
+    #[arg(long = "total", action = ArgAction::SetTrue)]
+    total: bool,
+
     /// Limit output to the top-N largest files by tokens.
     #[arg(long = "top", value_name = "N")]
     top: Option<usize>,
 
-    /// Suppress warnings.
-    #[arg(short = 'q', long = "quiet", action = ArgAction::SetTrue)]
-    quiet: bool,
+    /// Comma-separated columns to show in table, csv and ndjson output.
+    #[arg(
+        long = "fields",
+        value_enum,
+        value_name = "FIELD",
+        value_delimiter = ','
+    )]
+    fields: Vec<Field>,
 
-    /// Increase logging verbosity.
-    #[arg(short = 'v', long = "verbose", action = ArgAction::Count)]
-    verbosity: u8,
+    /// Reuse token counts for unchanged files (same mtime, size and encoding) from FILE.
+    #[arg(long = "cache", value_name = "FILE")]
+    cache: Option<PathBuf>,
+
+    /// Count identical file contents once, keeping the first path seen.
+    #[arg(long = "dedup", action = ArgAction::SetTrue)]
+    dedup: bool,
+
+    /// Add line, byte and char counts plus bytes/token to table and JSON output.
+    #[arg(long = "extended", action = ArgAction::SetTrue)]
+    extended: bool,
+
+    /// Only show files with at least N tokens.
+    #[arg(long = "min-tokens", value_name = "N")]
+    min_tokens: Option<u64>,
+
+    /// Whether the summary covers all counted files or only the shown ones.
+    #[arg(long = "summary-scope", value_enum, default_value = "all")]
+    summary_scope: SummaryScope,
+
+    /// How percentiles are picked from the per-file counts.
+    #[arg(long = "percentile-method", value_enum, default_value = "nearest")]
+    percentile_method: PercentileMethod,
+
+    /// Percentiles to report, e.g. 50,75,95,99.9: summary trailers list them
+    /// instead of p50/p90/p99, JSON and YAML add them under `percentiles`.
+    #[arg(long = "percentiles", value_name = "P", value_delimiter = ',', value_parser = parse_percent)]
+    percentiles: Vec<f64>,
+
+    /// Like --min-tokens, but also leave the hidden files out of the summary.
+    #[arg(long = "min-tokens-strict", value_name = "N", conflicts_with_all = ["min_tokens", "summary_scope"])]
+    min_tokens_strict: Option<u64>,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    /// Same as the `completions` subcommand.
+    #[arg(long = "generate-completions", value_name = "SHELL", hide = true)]
+    generate_completions: Option<Shell>,
+
+    /// Print the JSON Schema of `--format json` output and exit without scanning.
+    #[arg(long = "print-schema", action = ArgAction::SetTrue)]
+    print_schema: bool,
 
     /// Sort order for output.
     #[arg(long = "sort", value_enum, default_value = "path")]
     sort: SortBy,
 
+    /// Flip the --sort order of rows and groups (path descending, tokens ascending).
+    #[arg(long = "reverse", action = ArgAction::SetTrue)]
+    reverse: bool,
+
     /// Limit the number of Rayon worker threads.
     #[arg(long = "threads", value_name = "N")]
     threads: Option<usize>,
 
-    /// Emit summary footer in ndjson mode.
+    /// Emit summary footer in ndjson mode (commented trailer in csv mode, total line in tsv).
     #[arg(long = "with-summary", action = ArgAction::SetTrue)]
     with_summary_flag: bool,
 
     /// Disable summary footer in ndjson mode.
     #[arg(long = "no-summary", action = ArgAction::SetTrue)]
     no_summary_flag: bool,
+
+    /// Print only the summary, without per-file rows.
+    #[arg(long = "summary-only", action = ArgAction::SetTrue, conflicts_with = "no_summary_flag")]
+    summary_only: bool,
+
+    /// Count content read from stdin instead of walking paths.
+    #[arg(long = "stdin", action = ArgAction::SetTrue)]
+    stdin: bool,
+
+    /// Count the files listed in FILE, one path per line (`-` for stdin),
+    /// instead of walking; the include, exclude and ignore filters do not apply.
+    #[arg(
+        long = "files-from",
+        value_name = "FILE",
+        conflicts_with_all = ["paths", "stdin", "text", "dump_tokens"]
+    )]
+    files_from: Option<PathBuf>,
+
+    /// Exit with status 2 when the total token count exceeds this budget.
+    #[arg(
+        long = "max-total-tokens",
+        visible_alias = "fail-if-over",
+        value_name = "N"
+    )]
+    max_total_tokens: Option<u64>,
+
+    /// Flag files whose token count exceeds this limit.
+    #[arg(long = "max-file-tokens", value_name = "N")]
+    max_file_tokens: Option<u64>,
+
+    /// Exit with status 2 when any file exceeds --max-file-tokens.
+    #[arg(long = "strict", action = ArgAction::SetTrue, requires = "max_file_tokens")]
+    strict: bool,
+
+    /// Exit with status 2 when any file has more than N tokens, listing every
+    /// offender (same as --max-file-tokens N --strict).
+    #[arg(long = "fail-if-file-over", value_name = "N", conflicts_with_all = ["max_file_tokens", "strict"])]
+    fail_if_file_over: Option<u64>,
+
+    /// Flag files that do not fit a context window of N tokens on their own.
+    #[arg(long = "context-window", value_name = "N", group = "window")]
+    context_window: Option<u64>,
+
+    /// Like --context-window, with the window size of a known model.
+    #[arg(
+        long = "fits",
+        value_name = "MODEL",
+        value_parser = ModelLookup { table: MODEL_CONTEXT_WINDOWS, lookup: context_window },
+        group = "window"
+    )]
+    fits: Option<u64>,
+
+    /// Exit with status 3 when any file exceeds the context window.
+    #[arg(long = "fail-if-any-over", action = ArgAction::SetTrue, requires = "window")]
+    fail_if_any_over: bool,
+
+    /// Only count files that `git diff --name-only REF` lists (HEAD by default,
+    /// i.e. staged and unstaged changes), after the usual filters.
+    #[arg(
+        long = "changed",
+        value_name = "REF",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "HEAD",
+        conflicts_with_all = ["files_from", "git_diff"]
+    )]
+    changed: Option<String>,
+
+    /// Add each file's change in tokens since FILE, an earlier --format json
+    /// or json2 report, and list the files that have gone since.
+    #[arg(long = "baseline", value_name = "FILE")]
+    baseline: Option<PathBuf>,
+
+    /// Keep running and reprint the report whenever a counted file changes
+    /// (the budget flags then no longer set the exit status).
+    #[arg(
+        long = "watch",
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["stdin", "text", "files_from", "changed", "git_diff", "dump_tokens", "pack", "check"]
+    )]
+    watch: bool,
+
+    /// Record every file's token count in FILE for a later --check.
+    #[arg(long = "write-baseline", value_name = "FILE")]
+    write_baseline: Option<PathBuf>,
+
+    /// Exit with status 4 when a file grew past --tolerance since the counts
+    /// recorded by --write-baseline (FILE defaults to .tokencount-baseline.json).
+    #[arg(
+        long = "check",
+        value_name = "FILE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = DEFAULT_SNAPSHOT,
+        conflicts_with = "write_baseline"
+    )]
+    check: Option<PathBuf>,
+
+    /// Growth --check allows per file: a token count like 200 or a percentage like 5%.
+    #[arg(long = "tolerance", value_name = "N|PCT", value_parser = Tolerance::parse, requires = "check")]
+    tolerance: Option<Tolerance>,
+
+    /// With --check, also fail on new files with more than N tokens.
+    #[arg(long = "new-file-limit", value_name = "N", requires = "check")]
+    new_file_limit: Option<u64>,
+
+    /// Compare the files tracked at REF with those at HEAD and print the change
+    /// in tokens per file and in total (table or json output).
+    #[arg(
+        long = "git-diff",
+        value_name = "REF",
+        conflicts_with_all = ["stdin", "text", "files_from", "dump_tokens", "pack"]
+    )]
+    git_diff: Option<String>,
+
+    /// Print the contents of as many files as fit the context window, in --sort
+    /// order and separated by `// === path ===` lines, instead of the report.
+    #[arg(
+        long = "pack",
+        action = ArgAction::SetTrue,
+        requires = "window",
+        conflicts_with_all = ["summary_only", "template", "print0", "group_by", "also_table"]
+    )]
+    pack: bool,
+
+    /// Order files are packed into the context window for files_fitting_packed.
+    #[arg(
+        long = "pack-order",
+        value_enum,
+        default_value = "largest",
+        requires = "window"
+    )]
+    pack_order: PackOrder,
+
+    /// Replace per-file rows with one row per group (table, json, json2 and ndjson).
+    #[arg(long = "group-by", value_enum, value_name = "KEY")]
+    group_by: Option<GroupBy>,
+
+    /// Collapse --format tree below N levels into their parent's total.
+    #[arg(long = "tree-depth", value_name = "N")]
+    tree_depth: Option<usize>,
+
+    /// Layout of --format markdown.
+    #[arg(long = "markdown-style", value_enum, default_value = "list")]
+    markdown_style: MarkdownStyle,
+
+    /// Path components kept when rolling files up with --group-by dir.
+    #[arg(long = "group-depth", value_name = "N", default_value_t = 1)]
+    group_depth: usize,
+
+    /// Break down files and tokens per file extension in the summary.
+    #[arg(long = "by-ext", action = ArgAction::SetTrue)]
+    by_ext: bool,
+
+    /// Total tokens per directory, truncated to DEPTH path components (default 1).
+    #[arg(
+        long = "group-by-dir",
+        value_name = "DEPTH",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "1"
+    )]
+    group_by_dir: Option<usize>,
+
+    /// Histogram of per-file tokens: log-scaled, or explicit edges like =100,500,1000.
+    #[arg(
+        long = "histogram",
+        value_name = "EDGES",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "log",
+        value_parser = HistogramEdges::parse
+    )]
+    histogram: Option<HistogramEdges>,
+
+    /// Histogram with N equal-width buckets from 0 to the largest file (e.g. 10).
+    #[arg(long = "histogram-buckets", value_name = "N", conflicts_with = "histogram", value_parser = clap::value_parser!(u64).range(1..))]
+    histogram_buckets: Option<u64>,
+
+    /// Estimate cost from a price per 1,000 tokens (per-file costs with -v in table mode).
+    #[arg(long = "price-per-1k", value_name = "PRICE")]
+    price_per_1k: Option<f64>,
+
+    /// Add a dollar cost per file and in total from a price per million tokens.
+    #[arg(
+        long = "price-per-million",
+        value_name = "PRICE",
+        conflicts_with = "price_per_1k"
+    )]
+    price_per_million: Option<f64>,
+
+    /// Price per million tokens from the built-in table for this model
+    /// (--price-per-million wins when both are given).
+    #[arg(
+        long = "price-model",
+        value_name = "MODEL",
+        value_parser = ModelLookup { table: MODEL_PRICES, lookup: model_price },
+        conflicts_with = "price_per_1k"
+    )]
+    price_model: Option<f64>,
+
+    /// Add chat message wrapper tokens: TOKENS per file (default 3, or 4 for
+    /// p50k/r50k encodings) and 3 once per request for the reply priming.
+    #[arg(
+        long = "chat-overhead",
+        value_name = "TOKENS",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    chat_overhead: Option<Option<u64>>,
+
+    /// Terminate records with a NUL byte: paths only, or `tokens<TAB>path` with --format tsv.
+    #[arg(short = '0', long = "print0", action = ArgAction::SetTrue)]
+    print0: bool,
+
+    /// Color the table output with graded counts (auto disables color when piped or NO_COLOR is set).
+    #[arg(long = "color", value_enum, default_value = "auto")]
+    color: ColorChoice,
+
+    /// Render table counts in human-readable form (e.g. 1.2k, 3.4M).
+    #[arg(long = "human", action = ArgAction::SetTrue)]
+    human: bool,
+
+    /// Write the report to this file instead of stdout (replaced atomically, parent directories created).
+    #[arg(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Also print the table to stdout when writing the report with --output.
+    #[arg(long = "also-table", action = ArgAction::SetTrue, requires = "output")]
+    also_table: bool,
+
+    /// Count a literal string instead of walking paths (can repeat).
+    #[arg(long = "text", value_name = "STRING", action = ArgAction::Append)]
+    text: Vec<String>,
+
+    /// Render each row through a template such as '{{tokens}}\t{{path}}' (overrides --format).
+    #[arg(long = "template", value_name = "STRING", value_parser = Template::parse)]
+    template: Option<Template>,
+
+    /// Print the token IDs of a single FILE as a JSON array instead of counting.
+    #[arg(long = "dump-tokens", action = ArgAction::SetTrue, conflicts_with_all = ["stdin", "text"])]
+    dump_tokens: bool,
+
+    /// With --dump-tokens, print one `id<TAB>piece` line per token instead.
+    #[arg(long = "decode-each", action = ArgAction::SetTrue, requires = "dump_tokens")]
+    decode_each: bool,
+}
+
+/// How text is split into tokens; shared by every subcommand that counts.
+#[derive(Debug, clap::Args)]
+struct TokenizerArgs {
+    /// Encoding or model name (e.g. gpt-4o) to use for tokenization; give it
+    /// twice to compare two encodings file by file.
+    #[arg(long = "encoding", value_parser = EncodingParser, default_value = "cl100k-base")]
+    encoding: Vec<Encoding>,
+
+    /// Model name (e.g. gpt-4o) whose encoding to use instead of --encoding.
+    #[arg(long = "model", value_name = "NAME", value_parser = ModelLookup { table: MODEL_ENCODINGS, lookup: Encoding::from_model_name }, conflicts_with = "encoding")]
+    model: Option<Encoding>,
+
+    /// Tokenize with a tiktoken-format vocabulary file (base64 token and rank per line).
+    #[arg(
+        long = "encoding-file",
+        value_name = "PATH",
+        conflicts_with_all = ["encoding", "model"]
+    )]
+    encoding_file: Option<PathBuf>,
+
+    /// Register a special token for --encoding-file (repeatable).
+    #[arg(
+        long = "special-token",
+        value_name = "NAME=ID",
+        value_parser = parse_special_token,
+        requires = "encoding_file"
+    )]
+    special_tokens: Vec<(String, usize)>,
+
+    /// Count with a Hugging Face tokenizer.json (e.g. Llama or Mistral) instead of tiktoken.
+    #[cfg(feature = "hf")]
+    #[arg(
+        long = "tokenizer-json",
+        value_name = "PATH",
+        conflicts_with_all = ["encoding", "model", "encoding_file"]
+    )]
+    tokenizer_json: Option<PathBuf>,
+
+    /// Count special markers such as <|endoftext|> as single tokens, like an API
+    /// reading a prompt template; all of them, or only those in a comma-separated
+    /// list. Counts shrink for files that contain such markers.
+    #[arg(
+        long = "allow-special",
+        value_name = "TOKENS",
+        num_args = 0..=1,
+        require_equals = true,
+        value_delimiter = ','
+    )]
+    allow_special: Option<Vec<String>>,
+}
+
+impl TokenizerArgs {
+    /// Options with only the tokenizer set, for counting outside a walk.
+    fn count_options(&self) -> CountOptions {
+        CountOptions {
+            encoding: self.source(),
+            compare: self.compare(),
+            allow_special: self.allow_special.clone(),
+            ..CountOptions::default()
+        }
+    }
+
+    fn source(&self) -> EncoderSource {
+        #[cfg(feature = "hf")]
+        if let Some(path) = &self.tokenizer_json {
+            return EncoderSource::HuggingFace(path.clone());
+        }
+        match &self.encoding_file {
+            Some(path) => EncoderSource::File {
+                path: path.clone(),
+                special_tokens: self.special_tokens.clone(),
+            },
+            None => self.model.unwrap_or(self.encoding[0]).into(),
+        }
+    }
+
+    /// Encodings after the first `--encoding`, counted side by side with it.
+    fn compare(&self) -> Vec<EncoderSource> {
+        self.encoding[1..].iter().map(|&e| e.into()).collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self.encoding.as_slice() {
+            [_] => Ok(()),
+            [first, second] if first.name() == second.name() => {
+                bail!("--encoding was given twice with the same encoding");
+            }
+            [_, _] => Ok(()),
+            _ => bail!("--encoding can be given at most twice"),
+        }
+    }
+}
+
+/// Logging options shared by every subcommand.
+#[derive(Debug, clap::Args)]
+struct LogArgs {
+    /// Suppress warnings.
+    #[arg(short = 'q', long = "quiet", action = ArgAction::SetTrue)]
+    quiet: bool,
+
+    /// Increase logging verbosity.
+    #[arg(short = 'v', long = "verbose", action = ArgAction::Count)]
+    verbosity: u8,
 }
 
 impl Args {
-    fn include_extensions(&self) -> HashSet<String> {
-        let mut exts = if self.include_ext.is_empty() {
-            vec!["elm".to_string()]
-        } else {
-            self.include_ext.clone()
+    fn count_options(&self) -> CountOptions {
+        let mut options = CountOptions {
+            exclude_ext: self.exclude_ext.clone(),
+            include: self.include.clone(),
+            ignore_name_case: self.ignore_name_case,
+            detect_shebang: self.detect_shebang,
+            exclude: self.exclude.clone(),
+            respect_gitignore: self.respect_gitignore(),
+            respect_ignore: !self.no_respect_ignore,
+            follow_symlinks: self.follow_symlinks,
+            hidden: !self.no_hidden,
+            max_depth: self.depth,
+            max_bytes: self.max_bytes,
+            min_bytes: self.min_bytes,
+            chunk_bytes: self.chunk_bytes,
+            mmap: self.mmap,
+            lossy: self.lossy,
+            transcode: self.transcode,
+            skip_binary: !self.no_skip_binary,
+            absolute: self.absolute,
+            extended: self.needs_metrics(),
+            dedup: self.dedup,
+            cache: self.cache.clone(),
+            ..self.tokenizer.count_options()
         };
-        exts.iter_mut().for_each(|ext| {
-            if ext.starts_with('.') {
-                ext.remove(0);
-            }
+        // --include on its own replaces the default extension rather than adding to it.
+        if self.all_text {
+            options.include_ext = vec![String::from("*")];
+        } else if !self.include_ext.is_empty() || !self.include.is_empty() {
+            options.include_ext = self.include_ext.clone();
+        } else if let Some(exts) = default_extensions() {
+            options.include_ext = vec![exts];
+        }
+        options.include_names = self.include_name.clone();
+        if self.include_common_names {
+            options
+                .include_names
+                .extend(COMMON_FILE_NAMES.iter().map(|name| name.to_string()));
+        }
+        options
+    }
+
+    fn table_options(&self, to_terminal: bool) -> TableOptions {
+        TableOptions {
+            file_price_per_1k: self.price_per_1k.filter(|_| self.log.verbosity > 0),
+            color: self.color.enabled(to_terminal),
+            color_limit: self.max_file_tokens.or(self.context_window.or(self.fits)),
+            human: self.human,
+            extended: self.extended,
+            fields: self.fields.clone(),
+        }
+    }
+
+    /// --extended, or a --fields or --template column that only it computes.
+    fn needs_metrics(&self) -> bool {
+        let template = self.template.iter().flat_map(|template| {
+            template.parts.iter().filter_map(|part| match part {
+                TemplatePart::Field(field) => Some(*field),
+                TemplatePart::Literal(_) => None,
+            })
         });
-        exts.into_iter().map(|ext| ext.to_lowercase()).collect()
+        self.extended
+            || self
+                .fields
+                .iter()
+                .copied()
+                .chain(template)
+                .any(Field::is_metric)
+    }
+
+    fn csv_fields(&self) -> Vec<Field> {
+        if self.fields.is_empty() {
+            vec![Field::Path, Field::Tokens]
+        } else {
+            self.fields.clone()
+        }
+    }
+
+    fn encoding(&self) -> EncoderSource {
+        self.tokenizer.source()
+    }
+
+    fn price_per_million(&self) -> Option<f64> {
+        self.price_per_million.or(self.price_model)
+    }
+
+    /// Per-file chat message overhead, defaulting to the encoding's constant.
+    fn chat_overhead(&self) -> Option<u64> {
+        self.chat_overhead
+            .map(|tokens| tokens.unwrap_or_else(|| self.encoding().chat_message_overhead()))
     }
 
     fn respect_gitignore(&self) -> bool {
         !self.no_respect_gitignore
     }
 
+    /// CSV and TSV only append the summary when asked to.
+    fn trailing_summary(&self) -> bool {
+        (self.with_summary_flag || self.summary_only) && !self.no_summary_flag
+    }
+
     fn with_summary(&self) -> bool {
         if self.no_summary_flag {
             return false;
         }
-        if self.with_summary_flag {
+        if self.with_summary_flag || self.summary_only {
             return true;
         }
         true
@@ -132,402 +929,3266 @@ impl Args {
 #[derive(Copy, Clone, Debug, ValueEnum)]
 enum OutputFormat {
     Table,
+    /// Array of rows followed by a summary object (see json2 for a keyed shape).
     Json,
+    /// Object with `version`, `files` and `summary` keys.
+    Json2,
     Ndjson,
+    Csv,
+    /// One `tokens<TAB>path` line per file, unpadded (summary only with --with-summary).
+    Tsv,
+    Markdown,
+    Html,
+    Junit,
+    Github,
+    Prometheus,
+    Paths,
+    Total,
+    /// Indented directory tree with per-directory subtotals.
+    Tree,
+    /// YAML mapping with `files` and `summary` keys.
+    Yaml,
 }
 
-#[derive(Copy, Clone, Debug, ValueEnum)]
-enum SortBy {
-    Path,
-    Tokens,
-}
-
-#[derive(Clone, Debug, Serialize)]
-struct FileStat {
-    path: String,
-    tokens: u64,
+/// Presentation settings that only affect the table printer.
+#[derive(Clone, Debug, Default)]
+struct TableOptions {
+    file_price_per_1k: Option<f64>,
+    color: bool,
+    /// Per-file limit the color gradient grades against; percentiles are used without one.
+    color_limit: Option<u64>,
+    human: bool,
+    extended: bool,
+    fields: Vec<Field>,
 }
 
-#[derive(Clone, Debug, Serialize)]
-struct Summary {
-    files: u64,
-    total: u64,
-    average: f64,
-    p50: u64,
-    p90: u64,
-    p99: u64,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    top: Option<Vec<FileStat>>, // sorted by tokens desc
-}
-
-#[derive(Debug, Error)]
-enum ProcessError {
-    #[error("failed to read metadata for {path}")]
-    Metadata {
-        path: String,
-        #[source]
-        source: std::io::Error,
-    },
-    #[error("skipping {path}: file size {size} exceeds max {limit}")]
-    TooLarge { path: String, size: u64, limit: u64 },
-    #[error("skipping {path}: {source}")]
-    Read {
-        path: String,
-        #[source]
-        source: std::io::Error,
-    },
+impl TableOptions {
+    fn count(&self, value: u64) -> String {
+        if self.human {
+            humanize(value)
+        } else {
+            value.to_string()
+        }
+    }
 }
 
-#[derive(Clone, Debug, ValueEnum)]
-enum Encoding {
-    #[value(alias = "cl100k_base")]
-    Cl100kBase,
-    #[value(alias = "o200k_base")]
-    O200kBase,
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
 }
 
-impl Encoding {
-    fn load(&self) -> Result<Arc<CoreBPE>> {
-        let bpe = match self {
-            Encoding::Cl100kBase => cl100k_base()?,
-            Encoding::O200kBase => o200k_base()?,
-        };
-        Ok(Arc::new(bpe))
+impl ColorChoice {
+    fn enabled(self, to_terminal: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                to_terminal && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            }
+        }
     }
 }
 
-fn init_logging(quiet: bool, verbosity: u8) {
-    let mut builder =
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
-    builder
-        .format_module_path(false)
-        .format_timestamp(None)
-        .format_level(true);
-
-    if quiet {
-        builder.filter_level(log::LevelFilter::Off);
-    } else {
-        let level = match verbosity {
-            0 => log::LevelFilter::Warn,
-            1 => log::LevelFilter::Info,
-            _ => log::LevelFilter::Debug,
-        };
-        builder.filter_level(level);
-    }
+const ANSI_RED: &str = "\x1b[31m";
+const ANSI_GREEN: &str = "\x1b[32m";
+const ANSI_YELLOW: &str = "\x1b[33m";
+const ANSI_BOLD: &str = "\x1b[1m";
+const ANSI_RESET: &str = "\x1b[0m";
 
-    let _ = builder.try_init();
+/// A per-file column selectable with `--fields`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Field {
+    Path,
+    Tokens,
+    #[value(name = "over_budget", alias = "over-budget")]
+    OverBudget,
+    Lines,
+    Bytes,
+    Chars,
 }
 
-fn main() {
-    let args = Args::parse();
-    init_logging(args.quiet, args.verbosity);
-    if let Err(err) = run(args) {
-        eprintln!("error: {err:#}");
-        std::process::exit(1);
+impl Field {
+    fn name(self) -> &'static str {
+        match self {
+            Field::Path => "path",
+            Field::Tokens => "tokens",
+            Field::OverBudget => "over_budget",
+            Field::Lines => "lines",
+            Field::Bytes => "bytes",
+            Field::Chars => "chars",
+        }
     }
-}
 
-fn run(args: Args) -> Result<()> {
-    if let Some(threads) = args.threads {
-        rayon::ThreadPoolBuilder::new()
-            .num_threads(threads)
-            .build_global()
-            .context("failed to configure rayon thread pool")?;
+    fn json(self, stat: &FileStat) -> serde_json::Value {
+        match self {
+            Field::Path => stat.path.as_str().into(),
+            Field::Tokens => stat.tokens.into(),
+            Field::OverBudget => stat.over_budget.into(),
+            Field::Lines => stat.lines.into(),
+            Field::Bytes => stat.bytes.into(),
+            Field::Chars => stat.chars.into(),
+        }
     }
 
-    let include_exts = args.include_extensions();
-    let encoding = args.encoding.load().context("failed to load encoding")?;
-    let paths = if args.paths.is_empty() {
-        vec![PathBuf::from(".")]
-    } else {
-        args.paths.clone()
-    };
+    fn text(self, stat: &FileStat) -> String {
+        match self {
+            Field::Path => stat.path.clone(),
+            Field::Tokens => stat.tokens.to_string(),
+            Field::OverBudget => stat.over_budget.map(|b| b.to_string()).unwrap_or_default(),
+            Field::Lines => stat.lines.map(|n| n.to_string()).unwrap_or_default(),
+            Field::Bytes => stat.bytes.map(|n| n.to_string()).unwrap_or_default(),
+            Field::Chars => stat.chars.map(|n| n.to_string()).unwrap_or_default(),
+        }
+    }
 
-    let exclude_set = build_exclude_globset(args.exclude.clone())?;
-    let mut files = Vec::new();
+    /// Filled in only when the contents are measured (see `Args::needs_metrics`).
+    fn is_metric(self) -> bool {
+        matches!(self, Field::Lines | Field::Bytes | Field::Chars)
+    }
 
-    for root in paths {
-        collect_files(&root, &args, &exclude_set, &include_exts, &mut files)?;
+    fn right_aligned(self) -> bool {
+        matches!(
+            self,
+            Field::Tokens | Field::Lines | Field::Bytes | Field::Chars
+        )
     }
+}
 
-    debug!("collected {} candidate files", files.len());
+/// A parsed `--template`: literal text interleaved with `{{field}}` placeholders.
+#[derive(Clone, Debug)]
+struct Template {
+    parts: Vec<TemplatePart>,
+}
 
-    let stats = count_tokens(files, &args, encoding)?;
-    output_results(&stats, &args);
-    Ok(())
+#[derive(Clone, Debug)]
+enum TemplatePart {
+    Literal(String),
+    Field(Field),
 }
 
-fn build_exclude_globset(mut patterns: Vec<String>) -> Result<Arc<GlobSet>> {
-    let defaults = vec![
-        "**/.git/**",
-        "**/.git",
-        "**/target/**",
-        "**/target",
-        "**/node_modules/**",
-        "**/node_modules",
-    ];
-    for pattern in defaults {
-        patterns.push(pattern.to_string());
+impl Template {
+    fn parse(source: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(unescape(&rest[..start])));
+            }
+            let after = &rest[start + 2..];
+            let end = after
+                .find("}}")
+                .ok_or_else(|| format!("unclosed placeholder in template: {source}"))?;
+            let name = after[..end].trim();
+            let field = Field::from_str(name, false).map_err(|_| {
+                let known: Vec<&str> = Field::value_variants().iter().map(|f| f.name()).collect();
+                format!(
+                    "unknown placeholder {{{{{name}}}}} (expected one of: {})",
+                    known.join(", ")
+                )
+            })?;
+            parts.push(TemplatePart::Field(field));
+            rest = &after[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(unescape(rest)));
+        }
+        Ok(Template { parts })
     }
 
-    let mut builder = GlobSetBuilder::new();
-    for pattern in patterns {
-        let glob =
-            Glob::new(&pattern).with_context(|| format!("invalid glob pattern: {pattern}"))?;
-        builder.add(glob);
+    fn render(&self, stat: &FileStat) -> String {
+        let mut line = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(text) => line.push_str(text),
+                TemplatePart::Field(field) => line.push_str(&field.text(stat)),
+            }
+        }
+        line
     }
-    let set = builder.build().context("failed to build glob set")?;
-    Ok(Arc::new(set))
 }
 
-fn collect_files(
-    root: &Path,
-    args: &Args,
-    excludes: &Arc<GlobSet>,
-    include_exts: &HashSet<String>,
-    files: &mut Vec<PathBuf>,
+/// Expands `\t`, `\n` and `\\` so templates can be passed without shell quoting tricks.
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('\\') => result.push('\\'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Bucket edges requested with `--histogram`.
+#[derive(Clone, Debug)]
+enum HistogramEdges {
+    Log,
+    Explicit(Vec<u64>),
+    /// `--histogram-buckets N`.
+    Linear(u64),
+}
+
+fn parse_special_token(value: &str) -> Result<(String, usize), String> {
+    let (name, id) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected NAME=ID, got '{value}'"))?;
+    let id = id
+        .parse()
+        .map_err(|_| format!("invalid token id '{id}' for {name}"))?;
+    Ok((name.to_string(), id))
+}
+
+impl HistogramEdges {
+    fn parse(value: &str) -> Result<Self, String> {
+        if value == "log" {
+            return Ok(HistogramEdges::Log);
+        }
+        let edges = value
+            .split(',')
+            .map(|edge| {
+                edge.trim()
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid bucket edge '{edge}'"))
+            })
+            .collect::<Result<Vec<u64>, String>>()?;
+        if edges.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(String::from("bucket edges must be strictly increasing"));
+        }
+        Ok(HistogramEdges::Explicit(edges))
+    }
+
+    fn resolve(&self, stats: &[FileStat]) -> Vec<u64> {
+        let max = stats.iter().map(|s| s.tokens).max().unwrap_or(0);
+        match self {
+            HistogramEdges::Log => log_edges(max),
+            HistogramEdges::Explicit(edges) => edges.clone(),
+            HistogramEdges::Linear(buckets) => linear_edges(max, *buckets),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum GroupBy {
+    /// One row per file extension.
+    Ext,
+    /// One row per directory, truncated to --group-depth components.
+    Dir,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum PackOrder {
+    /// Biggest files first, skipping any that no longer fit.
+    Largest,
+    /// Smallest files first, fitting as many files as possible.
+    Smallest,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum MarkdownStyle {
+    /// `| Path | Tokens |` rows and a bold summary list.
+    List,
+    /// `| tokens | path |` rows and the summary in a fenced code block.
+    Fenced,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum SummaryScope {
+    /// Summarize every counted file.
+    All,
+    /// Summarize only the files shown after filtering.
+    Filtered,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum SortBy {
+    /// Paths in plain string order.
+    Path,
+    /// Most tokens first.
+    Tokens,
+    /// Paths with digit runs compared as numbers (chunk2 before chunk10).
+    Natural,
+}
+
+/// Compares paths like `str::cmp`, except that runs of ASCII digits compare
+/// by numeric value, with fewer leading zeros first on a tie.
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    fn digit_run(s: &str) -> (&str, &str) {
+        let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        s.split_at(end)
+    }
+
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(x), Some(y)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let (run_a, rest_a) = digit_run(a);
+            let (run_b, rest_b) = digit_run(b);
+            let (num_a, num_b) = (run_a.trim_start_matches('0'), run_b.trim_start_matches('0'));
+            let order = num_a
+                .len()
+                .cmp(&num_b.len())
+                .then_with(|| num_a.cmp(num_b))
+                .then_with(|| run_a.len().cmp(&run_b.len()));
+            if order.is_ne() {
+                return order;
+            }
+            (a, b) = (rest_a, rest_b);
+        } else {
+            if x != y {
+                return x.cmp(&y);
+            }
+            (a, b) = (&a[x.len_utf8()..], &b[y.len_utf8()..]);
+        }
+    }
+}
+
+/// Accepts either an encoding name or a model name for `--encoding`.
+#[derive(Clone)]
+struct EncodingParser;
+
+impl TypedValueParser for EncodingParser {
+    type Value = Encoding;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &OsStr,
+    ) -> std::result::Result<Encoding, clap::Error> {
+        let raw = value.to_string_lossy();
+        if let Ok(encoding) = Encoding::from_str(&raw, true) {
+            return Ok(encoding);
+        }
+        if let Some(encoding) = Encoding::from_model_name(&raw) {
+            return Ok(encoding);
+        }
+
+        let encodings: Vec<String> = Encoding::value_variants()
+            .iter()
+            .filter_map(|variant| variant.to_possible_value())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        let models: Vec<&str> = MODEL_ENCODINGS.iter().map(|(model, _)| *model).collect();
+        let arg = arg
+            .map(|a| a.to_string())
+            .unwrap_or_else(|| "--encoding".into());
+        let message = format!(
+            "invalid value '{raw}' for '{arg}': unknown encoding or model\n  \
+             encodings: {}\n  models: {}",
+            encodings.join(", "),
+            models.join(", ")
+        );
+        Err(cmd
+            .clone()
+            .error(clap::error::ErrorKind::InvalidValue, message))
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(
+            Encoding::value_variants()
+                .iter()
+                .filter_map(|variant| variant.to_possible_value()),
+        ))
+    }
+}
+
+/// Resolves a model name through one of the library's model tables, listing
+/// the table's models when the name is unknown.
+#[derive(Clone)]
+struct ModelLookup<T: 'static> {
+    table: &'static [(&'static str, T)],
+    lookup: fn(&str) -> Option<T>,
+}
+
+impl<T: Clone + Send + Sync + 'static> TypedValueParser for ModelLookup<T> {
+    type Value = T;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &OsStr,
+    ) -> std::result::Result<T, clap::Error> {
+        let raw = value.to_string_lossy();
+        if let Some(found) = (self.lookup)(&raw) {
+            return Ok(found);
+        }
+
+        let models: Vec<&str> = self.table.iter().map(|(model, _)| *model).collect();
+        let arg = arg.map(|a| a.to_string()).unwrap_or_else(|| "MODEL".into());
+        let message = format!(
+            "invalid value '{raw}' for '{arg}': unknown model\n  supported models: {}",
+            models.join(", ")
+        );
+        Err(cmd
+            .clone()
+            .error(clap::error::ErrorKind::InvalidValue, message))
+    }
+
+    fn possible_values(&self) -> Option<Box<dyn Iterator<Item = PossibleValue> + '_>> {
+        Some(Box::new(
+            self.table
+                .iter()
+                .map(|(model, _)| PossibleValue::new(*model)),
+        ))
+    }
+}
+
+fn init_logging(logging: &LogArgs) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    builder
+        .format_module_path(false)
+        .format_timestamp(None)
+        .format_level(true);
+
+    if logging.quiet {
+        builder.filter_level(log::LevelFilter::Off);
+    } else {
+        let level = match logging.verbosity {
+            0 => log::LevelFilter::Warn,
+            1 => log::LevelFilter::Info,
+            _ => log::LevelFilter::Debug,
+        };
+        builder.filter_level(level);
+    }
+
+    let _ = builder.try_init();
+}
+
+/// Exit code used when a token budget is exceeded (generic errors exit with 1).
+const EXIT_BUDGET_EXCEEDED: u8 = 2;
+const EXIT_WINDOW_EXCEEDED: u8 = 3;
+const EXIT_CHECK_FAILED: u8 = 4;
+
+fn main() -> ExitCode {
+    let matches = parse_args();
+    let Cli { command, args } = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let (args, matches) = match command {
+        None => (args, &matches),
+        Some(Command::Scan(args)) => (*args, matches.subcommand_matches("scan").expect("scan")),
+        Some(Command::Text(text)) => {
+            init_logging(&text.log);
+            return exit_code(count_text(&text));
+        }
+        Some(Command::Diff(diff)) => {
+            init_logging(&diff.log);
+            return exit_code(diff_reports(&diff));
+        }
+        Some(Command::Tokens(tokens)) => {
+            init_logging(&tokens.log);
+            let options = CountOptions {
+                lossy: tokens.lossy,
+                transcode: tokens.transcode,
+                ..tokens.tokenizer.count_options()
+            };
+            return exit_code(
+                tokens
+                    .tokenizer
+                    .validate()
+                    .and_then(|()| dump_tokens(&tokens.file, tokens.decode_each, &options)),
+            );
+        }
+        Some(Command::Completions { shell }) => {
+            print_completions(shell);
+            return ExitCode::SUCCESS;
+        }
+    };
+    // The hidden flag from before the subcommand existed.
+    if let Some(shell) = args.generate_completions {
+        print_completions(shell);
+        return ExitCode::SUCCESS;
+    }
+    if args.print_schema {
+        if let Err(err) = print_schema(&mut io::stdout().lock()) {
+            eprintln!("error: {err}");
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+    init_logging(&args.log);
+    match run(args, matches) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Exit status of a subcommand that either succeeds or fails outright.
+fn exit_code(result: Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `tokencount text`: the bare token count of one string.
+fn count_text(args: &TextArgs) -> Result<()> {
+    args.tokenizer.validate()?;
+    if args.tokenizer.encoding.len() > 1 {
+        bail!("text counts with one encoding; give --encoding once");
+    }
+    let text = if args.text == "-" {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .context("failed to read stdin")?;
+        text
+    } else {
+        args.text.clone()
+    };
+    let options = args.tokenizer.count_options();
+    let encoders = load_encoders(&options)?;
+    let encoders: Vec<&dyn Tokenizer> = encoders.iter().map(|e| e.as_ref()).collect();
+    let stat = count_contents(String::from("<text>"), &text, &encoders, &options)?;
+    println!("{}", stat.tokens);
+    Ok(())
+}
+
+/// `tokencount diff`: the per-file changes between two saved reports.
+fn diff_reports(args: &DiffArgs) -> Result<()> {
+    if !matches!(
+        args.format,
+        OutputFormat::Table | OutputFormat::Json | OutputFormat::Json2
+    ) {
+        bail!("diff only supports table, json and json2 output");
+    }
+    let load = |path: &Path| -> Result<BTreeMap<String, FileStat>> {
+        Ok(Baseline::load(path)?
+            .tokens
+            .into_iter()
+            .map(|(path, tokens)| (path.clone(), FileStat::new(path, tokens)))
+            .collect())
+    };
+    let base = load(&args.old)?;
+    let head = load(&args.new)?;
+    print_diff(
+        base,
+        head,
+        [
+            &args.old.display().to_string(),
+            &args.new.display().to_string(),
+        ],
+        args.format,
+        args.sort,
+        args.reverse,
+    )
+}
+
+fn run(mut args: Args, matches: &ArgMatches) -> Result<ExitCode> {
+    let cwd = std::env::current_dir().context("failed to read the current directory")?;
+    if let Some((path, config)) = Config::discover(&cwd)? {
+        info!("using defaults from {}", path.display());
+        config
+            .apply(&mut args, matches)
+            .with_context(|| format!("invalid config {}", path.display()))?;
+    }
+    if args.total {
+        args.format = OutputFormat::Total;
+    }
+    if let Some(limit) = args.fail_if_file_over {
+        args.max_file_tokens = Some(limit);
+        args.strict = true;
+    }
+    args.tokenizer.validate()?;
+    if args.baseline.is_some() && args.tokenizer.encoding.len() > 1 {
+        bail!("--baseline cannot be combined with a second --encoding");
+    }
+    if args.summary_only && matches!(args.format, OutputFormat::Paths) {
+        bail!("--summary-only has nothing to print with --format paths");
+    }
+    if args.group_by.is_some()
+        && !matches!(
+            args.format,
+            OutputFormat::Table | OutputFormat::Json | OutputFormat::Json2 | OutputFormat::Ndjson
+        )
+    {
+        bail!("--group-by only supports table, json, json2 and ndjson output");
+    }
+
+    if !args.all_text
+        && args.include_ext.is_empty()
+        && args.include.is_empty()
+        && default_extensions().is_none()
+    {
+        info!("counting .elm files only; pass --include-ext, --all-text or set TOKENCOUNT_EXT");
+    }
+
+    if args.dump_tokens {
+        dump_path_tokens(&args)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(rev) = &args.git_diff {
+        git_diff(&args, rev)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .context("failed to configure rayon thread pool")?;
+    }
+
+    // `-` among the paths reads stdin next to whatever else was named; without
+    // it, --stdin and --text replace the walk altogether.
+    let dash = args.paths.iter().any(|path| path == Path::new("-"));
+    let read_stdin = args.stdin || dash;
+    let walk: Vec<PathBuf> = if dash {
+        args.paths
+            .iter()
+            .filter(|path| *path != Path::new("-"))
+            .cloned()
+            .collect()
+    } else if args.stdin || !args.text.is_empty() {
+        Vec::new()
+    } else {
+        args.paths.clone()
+    };
+
+    if args.watch {
+        if dash {
+            bail!("--watch cannot read stdin");
+        }
+        watch(&args, &walk)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let files = if let Some(list) = &args.files_from {
+        read_file_list(list)?
+    } else if walk.is_empty() {
+        Vec::new()
+    } else {
+        let mut files = collect_files(&walk, &args.count_options())?;
+        if let Some(rev) = &args.changed {
+            // Canonical on both sides: the walk yields `./src/a.rs`, git `src/a.rs`.
+            let changed: HashSet<PathBuf> = changed_files(rev)?
+                .iter()
+                .filter_map(|path| fs::canonicalize(path).ok())
+                .collect();
+            files.retain(|file| fs::canonicalize(file).is_ok_and(|file| changed.contains(&file)));
+        }
+        files
+    };
+    let mut scan = if walk.is_empty() && args.files_from.is_none() {
+        Scan::default()
+    } else {
+        scan_with_progress(files, &args)?
+    };
+    let mut stats = std::mem::take(&mut scan.stats);
+    // `<stdin>` and `<text:N>` have no file for --pack to read back.
+    let mut inline = HashMap::new();
+    if read_stdin || !args.text.is_empty() {
+        let options = args.count_options();
+        let encoders = load_encoders(&options)?;
+        let encoders: Vec<&dyn Tokenizer> = encoders.iter().map(|e| e.as_ref()).collect();
+        if read_stdin {
+            let mut bytes = Vec::new();
+            io::stdin()
+                .read_to_end(&mut bytes)
+                .context("failed to read stdin")?;
+            let (contents, lossy) = match String::from_utf8(bytes) {
+                Ok(contents) => (contents, false),
+                Err(err) if args.lossy => {
+                    (String::from_utf8_lossy(err.as_bytes()).into_owned(), true)
+                }
+                Err(err) => {
+                    return Err(err).context("stdin is not valid UTF-8 (use --lossy)");
+                }
+            };
+            let mut stat = count_contents(String::from("<stdin>"), &contents, &encoders, &options)
+                .context("failed to count stdin")?;
+            stat.lossy = lossy.then_some(true);
+            inline.insert(stat.path.clone(), contents);
+            stats.push(stat);
+        }
+        for (index, text) in args.text.iter().enumerate() {
+            let stat = count_contents(format!("<text:{index}>"), text, &encoders, &options)
+                .with_context(|| format!("failed to count --text {index}"))?;
+            inline.insert(stat.path.clone(), text.clone());
+            stats.push(stat);
+        }
+    }
+
+    // Lets scripts tell "0 tokens" apart from "nothing scanned".
+    if matches!(args.format, OutputFormat::Total) && stats.is_empty() {
+        bail!("no files matched");
+    }
+
+    let baseline = args.baseline.as_deref().map(Baseline::load).transpose()?;
+    let over_budget = annotate(&mut stats, &args, baseline.as_ref());
+    let window = args.context_window.or(args.fits);
+
+    let summary = output_results(&stats, &scan, &inline, baseline.as_ref(), &args)?;
+
+    if let Some(path) = &args.write_baseline {
+        write_snapshot(path, &stats, &args)?;
+    }
+    if let Some(path) = &args.check {
+        let offenders = check_snapshot(path, &stats, &args)?;
+        if !offenders.is_empty() {
+            eprintln!(
+                "error: {} file(s) grew past the baseline in {}",
+                offenders.len(),
+                path.display()
+            );
+            for offender in offenders {
+                eprintln!("  {offender}");
+            }
+            return Ok(ExitCode::from(EXIT_CHECK_FAILED));
+        }
+    }
+
+    if args.strict && over_budget > 0 {
+        eprintln!(
+            "error: {} file(s) exceed the per-file budget of {} tokens",
+            over_budget,
+            args.max_file_tokens.unwrap_or_default()
+        );
+        // Named here too, since --top, --min-tokens or --summary-only may
+        // keep them out of the rows.
+        for violation in summary.violations.iter().flatten() {
+            eprintln!("  {} ({} tokens)", violation.path, violation.tokens);
+        }
+        return Ok(ExitCode::from(EXIT_BUDGET_EXCEEDED));
+    }
+
+    if args.fail_if_any_over {
+        if let Some(over) = summary.files_over_window.filter(|over| *over > 0) {
+            eprintln!(
+                "error: {} file(s) exceed the context window of {} tokens",
+                over,
+                window.unwrap_or_default()
+            );
+            return Ok(ExitCode::from(EXIT_WINDOW_EXCEEDED));
+        }
+    }
+
+    if let Some(budget) = args.max_total_tokens {
+        if summary.total > budget {
+            eprintln!(
+                "error: total tokens {} exceed budget of {} by {}",
+                summary.total,
+                budget,
+                summary.total - budget
+            );
+            return Ok(ExitCode::from(EXIT_BUDGET_EXCEEDED));
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// How long `--watch` waits for the filesystem to go quiet before recounting.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+enum WatchEvent {
+    Fs(notify::Result<notify::Event>),
+    Interrupt,
+}
+
+/// `--watch`: counts `roots`, prints the report, then recounts the files that
+/// changed and prints it again after each burst of filesystem events.
+///
+/// Every burst re-walks the roots, so the usual filters decide what is
+/// counted: events for files they exclude change nothing and print nothing.
+fn watch(args: &Args, roots: &[PathBuf]) -> Result<()> {
+    let options = args.count_options();
+    let encoders = load_encoders(&options)?;
+    let baseline = args.baseline.as_deref().map(Baseline::load).transpose()?;
+
+    let (sender, events) = mpsc::channel();
+    let interrupt = sender.clone();
+    ctrlc::set_handler(move || {
+        let _ = interrupt.send(WatchEvent::Interrupt);
+    })
+    .context("failed to install the Ctrl-C handler")?;
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = sender.send(WatchEvent::Fs(event));
+    })
+    .context("failed to start watching")?;
+    for root in roots {
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .with_context(|| format!("failed to watch {}", root.display()))?;
+    }
+
+    let mut counted: BTreeMap<String, FileStat> = BTreeMap::new();
+    // Canonical paths touched since the last report; `None` before the first.
+    let mut changed: Option<HashSet<PathBuf>> = None;
+    loop {
+        let files = collect_files(roots, &options)?;
+        let keys: HashSet<String> = files
+            .iter()
+            .map(|file| display_path(file, &options))
+            .collect();
+        let before = counted.len();
+        counted.retain(|key, _| keys.contains(key));
+        let mut dirty = changed.is_none() || counted.len() != before;
+        let stale: Vec<PathBuf> = files
+            .into_iter()
+            .filter(|file| {
+                !counted.contains_key(&display_path(file, &options))
+                    || changed.as_ref().is_some_and(|changed| {
+                        fs::canonicalize(file).is_ok_and(|file| changed.contains(&file))
+                    })
+            })
+            .collect();
+        if !stale.is_empty() {
+            dirty = true;
+            for file in &stale {
+                counted.remove(&display_path(file, &options));
+            }
+            for stat in count_tokens(stale, &options, &encoders).stats {
+                counted.insert(stat.path.clone(), stat);
+            }
+        }
+        if dirty {
+            let mut stats: Vec<FileStat> = counted.values().cloned().collect();
+            annotate(&mut stats, args, baseline.as_ref());
+            if matches!(args.format, OutputFormat::Table) && io::stdout().is_terminal() {
+                print!("\x1b[2J\x1b[H");
+            }
+            output_results(
+                &stats,
+                &Scan::default(),
+                &HashMap::new(),
+                baseline.as_ref(),
+                args,
+            )?;
+            io::stdout().flush()?;
+        }
+
+        // Block for the first event, then gather until things go quiet.
+        // Writing the report to --output is not a change to report on.
+        let output = args
+            .output
+            .as_deref()
+            .and_then(|path| fs::canonicalize(path).ok());
+        let mut touched = HashSet::new();
+        let mut next = events.recv().ok();
+        while let Some(event) = next {
+            match event {
+                WatchEvent::Interrupt => return Ok(()),
+                // Counting reads every stale file, which shows up as access
+                // events; only modifications should trigger a recount.
+                WatchEvent::Fs(Ok(event)) if event.kind.is_access() => {}
+                // Deleted paths no longer canonicalize; the re-walk drops them.
+                WatchEvent::Fs(Ok(event)) => touched.extend(
+                    event
+                        .paths
+                        .iter()
+                        .filter_map(|path| fs::canonicalize(path).ok())
+                        .filter(|path| Some(path) != output.as_ref()),
+                ),
+                WatchEvent::Fs(Err(err)) => warn!("watch error: {err}"),
+            }
+            next = events.recv_timeout(WATCH_DEBOUNCE).ok();
+        }
+        changed = Some(touched);
+    }
+}
+
+/// Fills in the per-file fields that depend on flags rather than contents
+/// (budget, window, cost, chat overhead, baseline delta). Returns how many
+/// files are over --max-file-tokens.
+fn annotate(stats: &mut [FileStat], args: &Args, baseline: Option<&Baseline>) -> usize {
+    let mut over_budget = 0;
+    if let Some(limit) = args.max_file_tokens {
+        for stat in stats.iter_mut() {
+            let over = stat.tokens > limit;
+            over_budget += usize::from(over);
+            stat.over_budget = Some(over);
+        }
+    }
+    if let Some(window) = args.context_window.or(args.fits) {
+        for stat in stats.iter_mut() {
+            stat.fits = Some(stat.tokens <= window);
+        }
+    }
+    if let Some(price) = args.price_per_million() {
+        for stat in stats.iter_mut() {
+            stat.cost = Some(cost_per_million(stat.tokens, price));
+        }
+    }
+    if let Some(overhead) = args.chat_overhead() {
+        for stat in stats.iter_mut() {
+            stat.tokens_with_overhead = Some(stat.tokens + overhead);
+        }
+    }
+    if let Some(baseline) = baseline {
+        for stat in stats.iter_mut() {
+            let before = baseline.tokens.get(&stat.path).copied().unwrap_or(0);
+            stat.delta = Some(stat.tokens as i64 - before as i64);
+        }
+    }
+    over_budget
+}
+
+/// Per-project config file, looked up from the current directory upwards.
+const CONFIG_FILENAME: &str = ".tokencount.toml";
+
+/// Defaults from `.tokencount.toml`; flags given on the command line win.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    include_ext: Option<Vec<String>>,
+    exclude_ext: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    encoding: Option<String>,
+    format: Option<String>,
+    max_bytes: Option<u64>,
+    min_bytes: Option<u64>,
+}
+
+impl Config {
+    /// Reads the nearest config file in `dir` or one of its ancestors.
+    fn discover(dir: &Path) -> Result<Option<(PathBuf, Config)>> {
+        for dir in dir.ancestors() {
+            let path = dir.join(CONFIG_FILENAME);
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("failed to read config {}", path.display()))
+                }
+            };
+            let config = toml::from_str(&contents)
+                .with_context(|| format!("invalid config {}", path.display()))?;
+            return Ok(Some((path, config)));
+        }
+        Ok(None)
+    }
+
+    /// Fills in each setting the command line left at its default.
+    fn apply(self, args: &mut Args, matches: &ArgMatches) -> Result<()> {
+        // TOKENCOUNT_* variables arrive as flags, so they beat the file too.
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        // TOKENCOUNT_EXT and --all-text are more specific than a project default.
+        if let Some(include_ext) = self.include_ext {
+            if !from_cli("include_ext") && !from_cli("all_text") && default_extensions().is_none() {
+                args.include_ext = include_ext;
+            }
+        }
+        if let Some(exclude_ext) = self.exclude_ext.filter(|_| !from_cli("exclude_ext")) {
+            args.exclude_ext = exclude_ext;
+        }
+        if let Some(exclude) = self.exclude.filter(|_| !from_cli("exclude")) {
+            args.exclude = exclude;
+        }
+        if let Some(name) = self.encoding.filter(|_| !from_cli("encoding")) {
+            let encoding = Encoding::from_str(&name, true)
+                .ok()
+                .or_else(|| Encoding::from_model_name(&name))
+                .ok_or_else(|| anyhow!("unknown encoding `{name}`"))?;
+            args.tokenizer.encoding = vec![encoding];
+        }
+        if let Some(name) = self.format.filter(|_| !from_cli("format")) {
+            args.format = OutputFormat::from_str(&name, true)
+                .map_err(|_| anyhow!("unknown format `{name}`"))?;
+        }
+        if let Some(max_bytes) = self.max_bytes.filter(|_| !from_cli("max_bytes")) {
+            args.max_bytes = Some(max_bytes);
+        }
+        if let Some(min_bytes) = self.min_bytes.filter(|_| !from_cli("min_bytes")) {
+            args.min_bytes = Some(min_bytes);
+        }
+        Ok(())
+    }
+}
+
+fn print_completions(shell: Shell) {
+    clap_complete::generate(shell, &mut command(), "tokencount", &mut io::stdout());
+}
+
+/// Options that act instead of scanning; they take no `TOKENCOUNT_*` variable.
+const NO_ENV_VAR: &[&str] = &["generate-completions", "print-schema"];
+
+/// The variable every other option falls back to, e.g. `TOKENCOUNT_MAX_BYTES`.
+fn env_var_name(long: &str) -> String {
+    format!("TOKENCOUNT_{}", long.replace('-', "_").to_uppercase())
+}
+
+/// The command with every option's variable attached, so `--help` lists them.
+fn command() -> clap::Command {
+    let with_env = |command: clap::Command| {
+        command.mut_args(|arg| match arg.get_long() {
+            Some(long) if !NO_ENV_VAR.contains(&long) => {
+                let name = env_var_name(long);
+                arg.env(name)
+            }
+            _ => arg,
+        })
+    };
+    with_env(Cli::command()).mut_subcommands(with_env)
+}
+
+/// Parses the command line, falling back to `TOKENCOUNT_*` variables for the
+/// options it leaves out.
+///
+/// The variables are turned into flags in front of the real arguments rather
+/// than read by clap, so that a variable conflicting with a flag on the
+/// command line yields to it instead of failing, and so that repeatable flags
+/// can take a comma-separated list.
+fn parse_args() -> ArgMatches {
+    let command = command();
+    let without_env = |command: clap::Command| command.mut_args(|arg| arg.env(None));
+    let bare = without_env(command.clone()).mut_subcommands(without_env);
+    let cli = match bare.clone().try_get_matches() {
+        Ok(cli) => cli,
+        // Show the variables in the help.
+        Err(err) if err.kind() == ErrorKind::DisplayHelp => command.clone().get_matches(),
+        Err(err) => err.exit(),
+    };
+
+    let mut argv: Vec<OsString> = std::env::args_os().collect();
+    // The flags go to whichever command runs: right after the program name,
+    // or after the subcommand's name (nothing can come before it).
+    let (target, matches, at) = match cli.subcommand() {
+        Some((name, matches)) => {
+            let at = argv.iter().position(|arg| arg == name).unwrap_or(1) + 1;
+            let target = command.find_subcommand(name).expect("parsed subcommand");
+            (target, matches, at)
+        }
+        None => (&command, &cli, 1),
+    };
+    let flags = env_flags(target, matches);
+    if flags.is_empty() {
+        return cli;
+    }
+    argv.splice(at..at, flags);
+    bare.get_matches_from(argv)
+}
+
+/// Flags for the options of `command` that `matches` did not get from the
+/// command line but whose variable is set.
+fn env_flags(command: &clap::Command, matches: &ArgMatches) -> Vec<OsString> {
+    let on_cli = |arg: &clap::Arg| {
+        matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+    };
+    let given: Vec<&clap::Arg> = command.get_arguments().filter(|arg| on_cli(arg)).collect();
+
+    let mut argv = Vec::new();
+    for arg in command.get_arguments().filter(|arg| !on_cli(arg)) {
+        let (Some(long), Some(name)) = (arg.get_long(), arg.get_env()) else {
+            continue;
+        };
+        let Some(value) = std::env::var_os(name) else {
+            continue;
+        };
+        let conflicts = command.get_arg_conflicts_with(arg);
+        if given.iter().any(|other| {
+            conflicts.iter().any(|c| c.get_id() == other.get_id())
+                || command
+                    .get_arg_conflicts_with(other)
+                    .iter()
+                    .any(|c| c.get_id() == arg.get_id())
+        }) {
+            continue;
+        }
+        let value = value.to_string_lossy();
+        let flag = format!("--{long}");
+        match arg.get_action() {
+            ArgAction::SetTrue => match env_flag(&value) {
+                Some(true) => argv.push(flag.into()),
+                Some(false) => {}
+                None => command
+                    .clone()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        format!(
+                            "{} must be 1/0, true/false, yes/no or on/off, got `{value}`",
+                            name.to_string_lossy()
+                        ),
+                    )
+                    .exit(),
+            },
+            ArgAction::Count => {
+                let times = value.trim().parse::<usize>().unwrap_or_else(|_| {
+                    command
+                        .clone()
+                        .error(
+                            ErrorKind::InvalidValue,
+                            format!("{} must be a number, got `{value}`", name.to_string_lossy()),
+                        )
+                        .exit()
+                });
+                argv.extend(std::iter::repeat_n(OsString::from(&flag), times));
+            }
+            ArgAction::Append => argv.extend(
+                split_env_list(&value)
+                    .into_iter()
+                    .map(|piece| OsString::from(format!("{flag}={piece}"))),
+            ),
+            // Empty means unset, except where the flag's value is optional:
+            // `TOKENCOUNT_CHANGED=` is a bare `--changed`.
+            _ if value.is_empty() => {
+                if arg.get_num_args().is_some_and(|n| n.min_values() == 0) {
+                    argv.push(flag.into());
+                }
+            }
+            _ => argv.push(format!("{flag}={value}").into()),
+        }
+    }
+    argv
+}
+
+/// A `TOKENCOUNT_*` value for an on/off flag.
+fn env_flag(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "" | "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Splits a `TOKENCOUNT_*` list on commas, except inside `{a,b}` glob
+/// alternatives, dropping empty pieces.
+fn split_env_list(value: &str) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let (mut depth, mut start) = (0usize, 0);
+    for (index, c) in value.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                pieces.push(&value[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    pieces.push(&value[start..]);
+    pieces.retain(|piece| !piece.trim().is_empty());
+    pieces
+}
+
+/// Extensions from `TOKENCOUNT_EXT`, in `--include-ext` syntax, when set.
+fn default_extensions() -> Option<String> {
+    std::env::var("TOKENCOUNT_EXT")
+        .ok()
+        .filter(|exts| !exts.trim().is_empty())
+}
+
+/// Candidate files a scan needs before it shows a progress bar.
+const PROGRESS_MIN_FILES: usize = 500;
+
+/// Reads `--files-from`: one path per line, blank lines ignored. Paths that
+/// are not files are skipped with a warning rather than failing the run.
+fn read_file_list(list: &Path) -> Result<Vec<PathBuf>> {
+    let contents = if list == Path::new("-") {
+        let mut contents = String::new();
+        io::stdin()
+            .read_to_string(&mut contents)
+            .context("failed to read file list from stdin")?;
+        contents
+    } else {
+        fs::read_to_string(list)
+            .with_context(|| format!("failed to read file list {}", list.display()))?
+    };
+    let mut files = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        let path = PathBuf::from(line);
+        if path.is_file() {
+            files.push(path);
+        } else {
+            warn!("skipping {}: not a file", path.display());
+        }
+    }
+    Ok(files)
+}
+
+/// Counts `files`, with a progress bar on stderr for big scans.
+///
+/// The bar only appears when stderr is a terminal and `--quiet` is off, so
+/// stdout and redirected stderr stay untouched. Rayon workers bump a shared
+/// counter and a separate thread copies it into the bar.
+fn scan_with_progress(files: Vec<PathBuf>, args: &Args) -> Result<Scan> {
+    let mut options = args.count_options();
+    let encoders = load_encoders(&options)?;
+    if args.log.quiet || files.len() < PROGRESS_MIN_FILES || !io::stderr().is_terminal() {
+        return Ok(count_tokens(files, &options, &encoders));
+    }
+
+    let counted = Arc::new(AtomicU64::new(0));
+    options.progress = Some(Arc::clone(&counted));
+    let bar = ProgressBar::new(files.len() as u64);
+    let done = AtomicBool::new(false);
+    let scan = thread::scope(|scope| {
+        scope.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                bar.set_position(counted.load(Ordering::Relaxed));
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let scan = count_tokens(files, &options, &encoders);
+        done.store(true, Ordering::Relaxed);
+        scan
+    });
+    bar.finish_and_clear();
+    Ok(scan)
+}
+
+/// `--dump-tokens`: the `tokens` subcommand on the one path given.
+fn dump_path_tokens(args: &Args) -> Result<()> {
+    let [path] = args.paths.as_slice() else {
+        bail!(
+            "--dump-tokens takes exactly one file, got {} paths",
+            args.paths.len()
+        );
+    };
+    if !path.is_file() {
+        bail!(
+            "--dump-tokens takes exactly one file, {} is not a file",
+            path.display()
+        );
+    }
+    dump_tokens(path, args.decode_each, &args.count_options())
+}
+
+/// Token IDs of one file, as a JSON array or one decoded line per token. The
+/// file is decoded the way a scan with `options` would read it.
+fn dump_tokens(path: &Path, decode_each: bool, options: &CountOptions) -> Result<()> {
+    let contents = read_text(path, options)?;
+    let bpe = options.encoding.load_bpe()?;
+    let ids = encode_ids(&bpe, &contents, options.allow_special.as_deref());
+
+    let mut out = io::stdout().lock();
+    if decode_each {
+        for id in ids {
+            // A token holding part of a character does not decode on its own.
+            let piece = bpe
+                .decode(vec![id])
+                .unwrap_or_else(|_| String::from(char::REPLACEMENT_CHARACTER));
+            writeln!(out, "{id}\t{}", escape_piece(&piece))?;
+        }
+    } else {
+        serde_json::to_writer(&mut out, &ids)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Token counts per path from the report given to `--baseline`.
+struct Baseline {
+    tokens: BTreeMap<String, u64>,
+}
+
+impl Baseline {
+    /// Reads `--format json` output (rows then a summary element), json2 or a
+    /// `--write-baseline` snapshot.
+    fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read baseline {}", path.display()))?;
+        let report: serde_json::Value = serde_json::from_str(&contents)
+            .with_context(|| format!("baseline {} is not JSON", path.display()))?;
+        let unrecognized = || {
+            anyhow!(
+                "unrecognized baseline {}: expected the output of --format json or json2",
+                path.display()
+            )
+        };
+        if let Ok(snapshot) = serde_json::from_value::<Snapshot>(report.clone()) {
+            return Ok(Baseline {
+                tokens: snapshot.files,
+            });
+        }
+        let rows = match &report {
+            serde_json::Value::Array(rows) => rows,
+            serde_json::Value::Object(report) if report.get("version") == Some(&2.into()) => report
+                .get("files")
+                .and_then(serde_json::Value::as_array)
+                .ok_or_else(unrecognized)?,
+            _ => return Err(unrecognized()),
+        };
+        let mut tokens = BTreeMap::new();
+        for row in rows.iter().filter(|row| row.get("summary").is_none()) {
+            let path = row.get("path").and_then(serde_json::Value::as_str);
+            let count = row.get("tokens").and_then(serde_json::Value::as_u64);
+            let (Some(path), Some(count)) = (path, count) else {
+                return Err(unrecognized());
+            };
+            tokens.insert(path.to_string(), count);
+        }
+        Ok(Baseline { tokens })
+    }
+}
+
+/// Where `--check` looks when no file is given.
+const DEFAULT_SNAPSHOT: &str = ".tokencount-baseline.json";
+
+/// What `--write-baseline` records: tokens per path, in path order so the
+/// file diffs cleanly when committed. A renamed file reads as removed + new.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Snapshot {
+    encoding: String,
+    files: BTreeMap<String, u64>,
+}
+
+/// Per-file growth `--check` lets through.
+#[derive(Copy, Clone, Debug)]
+enum Tolerance {
+    Tokens(u64),
+    Percent(f64),
+}
+
+/// A `--percentiles` entry, in `(0, 100]`.
+fn parse_percent(value: &str) -> Result<f64, String> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|percent| *percent > 0.0 && *percent <= 100.0)
+        .ok_or_else(|| format!("expected a percentile above 0 and up to 100, got '{value}'"))
+}
+
+impl Tolerance {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value.strip_suffix('%') {
+            Some(percent) => percent
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|percent| *percent >= 0.0)
+                .map(Tolerance::Percent)
+                .ok_or_else(|| format!("invalid percentage '{value}'")),
+            None => value
+                .trim()
+                .parse()
+                .map(Tolerance::Tokens)
+                .map_err(|_| format!("expected a token count or a percentage, got '{value}'")),
+        }
+    }
+
+    fn allows(self, before: u64, after: u64) -> bool {
+        let allowed = match self {
+            Tolerance::Tokens(tokens) => tokens,
+            Tolerance::Percent(percent) => (before as f64 * percent / 100.0).floor() as u64,
+        };
+        after <= before.saturating_add(allowed)
+    }
+}
+
+fn write_snapshot(path: &Path, stats: &[FileStat], args: &Args) -> Result<()> {
+    let snapshot = Snapshot {
+        encoding: args.encoding().name(),
+        files: stats
+            .iter()
+            .map(|stat| (stat.path.clone(), stat.tokens))
+            .collect(),
+    };
+    write_atomically(path, |out| {
+        serde_json::to_writer_pretty(&mut *out, &snapshot)?;
+        writeln!(out)
+    })
+}
+
+/// Files that grew past the tolerance, or are new and over `--new-file-limit`,
+/// as `path: before -> after` lines.
+fn check_snapshot(path: &Path, stats: &[FileStat], args: &Args) -> Result<Vec<String>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            bail!(
+                "no baseline at {}; record one with --write-baseline",
+                path.display()
+            );
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read baseline {}", path.display()))
+        }
+    };
+    let snapshot: Snapshot = serde_json::from_str(&contents)
+        .with_context(|| format!("{} is not a --write-baseline snapshot", path.display()))?;
+    let encoding = args.encoding().name();
+    if snapshot.encoding != encoding {
+        warn!(
+            "baseline {} was recorded with {}, counting with {}",
+            path.display(),
+            snapshot.encoding,
+            encoding
+        );
+    }
+
+    let tolerance = args.tolerance.unwrap_or(Tolerance::Tokens(0));
+    let mut offenders = Vec::new();
+    for stat in stats {
+        match snapshot.files.get(&stat.path) {
+            Some(&before) if !tolerance.allows(before, stat.tokens) => offenders.push(format!(
+                "{}: {} -> {} ({})",
+                stat.path,
+                before,
+                stat.tokens,
+                format_delta(stat.tokens as i64 - before as i64)
+            )),
+            None if args.new_file_limit.is_some_and(|limit| stat.tokens > limit) => {
+                offenders.push(format!("{}: new -> {}", stat.path, stat.tokens));
+            }
+            _ => {}
+        }
+    }
+    Ok(offenders)
+}
+
+/// A file whose token count differs between the two sides of a diff.
+#[derive(Debug, Serialize)]
+struct DiffRow {
+    path: String,
+    /// Tokens at the base revision; `None` for files added since.
+    base: Option<u64>,
+    /// Tokens at HEAD; `None` for files removed since.
+    head: Option<u64>,
+    delta: i64,
+}
+
+/// `--git-diff REF`: counts the files tracked at REF and at HEAD, each
+/// exported to a temporary directory and walked with the usual filters.
+fn git_diff(args: &Args, rev: &str) -> Result<()> {
+    if !matches!(
+        args.format,
+        OutputFormat::Table | OutputFormat::Json | OutputFormat::Json2
+    ) {
+        bail!("--git-diff only supports table, json and json2 output");
+    }
+    let mut options = args.count_options();
+    // Paths point into throwaway snapshots, so cached entries would never hit.
+    options.cache = None;
+    let encoders = load_encoders(&options)?;
+    let prefix = cwd_prefix()?;
+    let snapshot = |rev: &str| -> Result<BTreeMap<String, FileStat>> {
+        let dir = tempfile::tempdir().context("failed to create a snapshot directory")?;
+        export_revision(rev, &args.paths, dir.path())?;
+        let files = collect_files(&[dir.path().to_path_buf()], &options)?;
+        let scan = count_tokens(files, &options, &encoders);
+        Ok(scan
+            .stats
+            .into_iter()
+            .map(|mut stat| {
+                let path = Path::new(&stat.path);
+                let relative = path.strip_prefix(dir.path()).unwrap_or(path);
+                stat.path = from_top_level(relative, &prefix)
+                    .to_string_lossy()
+                    .into_owned();
+                (stat.path.clone(), stat)
+            })
+            .collect())
+    };
+    let base = snapshot(rev)?;
+    let head = snapshot("HEAD")?;
+    print_diff(
+        base,
+        head,
+        [rev, "HEAD"],
+        args.format,
+        args.sort,
+        args.reverse,
+    )
+}
+
+/// Prints the files whose counts differ between `base` and `head`, labelled
+/// with `labels` in the table header and `base` in JSON.
+fn print_diff(
+    base: BTreeMap<String, FileStat>,
+    head: BTreeMap<String, FileStat>,
+    labels: [&str; 2],
+    format: OutputFormat,
+    sort: SortBy,
+    reverse: bool,
 ) -> Result<()> {
-    let respect_gitignore = args.respect_gitignore();
-    let excludes_for_filter = Arc::clone(excludes);
-    let mut builder = WalkBuilder::new(root);
-    builder.standard_filters(false);
-    builder.follow_links(args.follow_symlinks);
-
-    builder.git_ignore(respect_gitignore);
-    builder.git_global(respect_gitignore);
-    builder.git_exclude(respect_gitignore);
-
-    builder.filter_entry(move |entry| {
-        let excludes = &excludes_for_filter;
-        if entry.depth() == 0 {
-            return true;
+    let [base_label, head_label] = labels;
+    let paths: BTreeSet<&String> = base.keys().chain(head.keys()).collect();
+    let mut rows: Vec<DiffRow> = paths
+        .into_iter()
+        .filter_map(|path| {
+            let before = base.get(path).map(|stat| stat.tokens);
+            let after = head.get(path).map(|stat| stat.tokens);
+            let delta = after.unwrap_or(0) as i64 - before.unwrap_or(0) as i64;
+            (before != after).then(|| DiffRow {
+                path: path.clone(),
+                base: before,
+                head: after,
+                delta,
+            })
+        })
+        .collect();
+    if let SortBy::Tokens = sort {
+        rows.sort_by(|a, b| {
+            b.delta
+                .unsigned_abs()
+                .cmp(&a.delta.unsigned_abs())
+                .then_with(|| a.path.cmp(&b.path))
+        });
+    }
+    if reverse {
+        rows.reverse();
+    }
+
+    let base_summary = build_summary(&base.into_values().collect::<Vec<_>>(), None);
+    let head_summary = build_summary(&head.into_values().collect::<Vec<_>>(), None);
+    let total_delta = head_summary.total as i64 - base_summary.total as i64;
+    let mut out = io::stdout().lock();
+    match format {
+        OutputFormat::Table => {
+            let cells: Vec<[String; 3]> = rows
+                .iter()
+                .map(|row| {
+                    let show = |count: Option<u64>| {
+                        count.map_or_else(|| String::from("-"), |count| count.to_string())
+                    };
+                    [format_delta(row.delta), show(row.base), show(row.head)]
+                })
+                .collect();
+            let mut widths = ["delta".len(), base_label.len(), head_label.len()];
+            for row in &cells {
+                for (width, cell) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(cell.len());
+                }
+            }
+            let [delta_width, base_width, head_width] = widths;
+            writeln!(
+                out,
+                "{:>delta_width$}  {:>base_width$}  {:>head_width$}  path",
+                "delta", base_label, head_label
+            )?;
+            for (row, [delta, before, after]) in rows.iter().zip(&cells) {
+                writeln!(
+                    out,
+                    "{delta:>delta_width$}  {before:>base_width$}  {after:>head_width$}  {}",
+                    row.path
+                )?;
+            }
+            writeln!(out)?;
+            writeln!(
+                out,
+                "total: {} -> {} ({})",
+                base_summary.total,
+                head_summary.total,
+                format_delta(total_delta)
+            )?;
+            writeln!(
+                out,
+                "files: {} -> {}",
+                base_summary.files, head_summary.files
+            )?;
+        }
+        _ => {
+            let report = serde_json::json!({
+                "base": base_label,
+                "files": rows,
+                "summary": {
+                    "base": base_summary,
+                    "head": head_summary,
+                    "total_delta": total_delta,
+                },
+            });
+            serde_json::to_writer_pretty(&mut out, &report)?;
+            writeln!(out)?;
         }
-        let path = entry.path();
-        let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false);
-        if excludes.is_match(path) {
-            if is_dir {
-                debug!("excluding directory {}", path.display());
+    }
+    Ok(())
+}
+
+/// Makes a decoded token printable on one line: control characters and
+/// backslashes are escaped.
+fn escape_piece(text: &str) -> String {
+    let mut piece = String::new();
+    for c in text.chars() {
+        if c.is_control() || c == '\\' {
+            piece.extend(c.escape_default());
+        } else {
+            piece.push(c);
+        }
+    }
+    piece
+}
+
+/// Prints `stats` in the requested format; `scan` supplies the skip counts
+/// for the summary.
+fn output_results(
+    stats: &[FileStat],
+    scan: &Scan,
+    inline: &HashMap<String, String>,
+    baseline: Option<&Baseline>,
+    args: &Args,
+) -> Result<Summary> {
+    let visible: Vec<FileStat> = match args.min_tokens.or(args.min_tokens_strict) {
+        Some(min) => stats.iter().filter(|s| s.tokens >= min).cloned().collect(),
+        None => stats.to_owned(),
+    };
+    let summary_stats = match args.summary_scope {
+        _ if args.min_tokens_strict.is_some() => &visible,
+        SummaryScope::All => stats,
+        SummaryScope::Filtered => &visible,
+    };
+
+    let mut all = visible.clone();
+    all.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut token_sorted = visible.clone();
+    token_sorted.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.path.cmp(&b.path)));
+
+    let display_stats = if let Some(top) = args.top {
+        token_sorted.iter().take(top).cloned().collect::<Vec<_>>()
+    } else {
+        match args.sort {
+            SortBy::Path => all.clone(),
+            SortBy::Tokens => token_sorted.clone(),
+            SortBy::Natural => {
+                let mut natural = visible.clone();
+                natural.sort_by(|a, b| natural_cmp(&a.path, &b.path));
+                natural
             }
-            return !is_dir;
         }
-        true
-    });
+    };
+
+    let mut ordered = if args.summary_only {
+        Vec::new()
+    } else {
+        display_stats
+    };
+    if args.top.is_some() {
+        match args.sort {
+            SortBy::Path => ordered.sort_by(|a, b| a.path.cmp(&b.path)),
+            SortBy::Natural => ordered.sort_by(|a, b| natural_cmp(&a.path, &b.path)),
+            SortBy::Tokens => {
+                ordered.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.path.cmp(&b.path)))
+            }
+        }
+    }
+    // --top still keeps the largest files; --reverse only changes how they are listed.
+    if args.reverse {
+        ordered.reverse();
+    }
+
+    let mut summary = build_summary_with(
+        summary_stats,
+        args.top
+            .map(|n| token_sorted.iter().take(n).cloned().collect::<Vec<_>>()),
+        args.percentile_method,
+    );
+    // Prometheus always exports per-extension gauges, whatever --top keeps.
+    if args.by_ext || matches!(args.format, OutputFormat::Prometheus) {
+        summary.by_extension = Some(extension_breakdown(summary_stats));
+    }
+    if let Some(depth) = args.group_by_dir {
+        summary.by_directory = Some(directory_breakdown(summary_stats, depth));
+    }
+    summary.encoding = Some(args.encoding().name());
+    if !args.percentiles.is_empty() {
+        summary.percentiles = Some(percentiles(
+            summary_stats,
+            &args.percentiles,
+            args.percentile_method,
+        ));
+    }
+    if let Some(limit) = args.max_file_tokens.filter(|_| args.strict) {
+        // From every counted file, whatever --top or --min-tokens show.
+        let mut violations: Vec<Violation> = stats
+            .iter()
+            .filter(|stat| stat.tokens > limit)
+            .map(|stat| Violation {
+                path: stat.path.clone(),
+                tokens: stat.tokens,
+            })
+            .collect();
+        violations.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.path.cmp(&b.path)));
+        summary.violations = Some(violations);
+    }
+    let nonzero = |count: u64| (count > 0).then_some(count);
+    summary.duplicates = args.dedup.then_some(scan.duplicates);
+    summary.skipped_binary = nonzero(scan.skipped_binary);
+    summary.skipped_too_large = nonzero(scan.skipped_too_large);
+    summary.skipped_too_small = nonzero(scan.skipped_too_small);
+    let hidden = (stats.len() - visible.len()) as u64;
+    summary.hidden_files = (hidden > 0).then_some(hidden);
+    if args.lossy {
+        let lossy = summary_stats.iter().filter(|s| s.lossy.is_some()).count();
+        summary.lossy_files = Some(lossy as u64);
+    }
+    let edges = args
+        .histogram
+        .clone()
+        .or(args.histogram_buckets.map(HistogramEdges::Linear));
+    if let Some(edges) = edges {
+        summary.histogram = Some(histogram(summary_stats, &edges.resolve(summary_stats)));
+    }
+    if let Some(price) = args.price_per_1k {
+        summary.cost = Some(estimate_cost(summary.total, price));
+    }
+    if let Some(window) = args.context_window.or(args.fits) {
+        let over = summary_stats
+            .iter()
+            .filter(|s| s.fits == Some(false))
+            .count() as u64;
+        let mut counts: Vec<u64> = summary_stats.iter().map(|s| s.tokens).collect();
+        counts.sort_unstable();
+        if matches!(args.pack_order, PackOrder::Largest) {
+            counts.reverse();
+        }
+        summary.files_over_window = Some(over);
+        summary.context_window = Some(window);
+        summary.files_fitting_individually = Some(summary_stats.len() as u64 - over);
+        summary.files_fitting_packed = Some(packed_count(counts, window));
+    }
+    if let Some(price) = args.price_per_million() {
+        summary.total_cost = Some(cost_per_million(summary.total, price));
+    }
+    if let Some(baseline) = baseline {
+        let before: u64 = baseline.tokens.values().sum();
+        summary.total_delta = Some(summary.total as i64 - before as i64);
+        let counted: HashSet<&str> = stats.iter().map(|stat| stat.path.as_str()).collect();
+        let removed = baseline
+            .tokens
+            .keys()
+            .filter(|path| !counted.contains(path.as_str()))
+            .cloned()
+            .collect();
+        summary.removed_files = Some(removed);
+    }
+    if args.chat_overhead.is_some() {
+        let messages: u64 = summary_stats
+            .iter()
+            .filter_map(|s| s.tokens_with_overhead)
+            .sum();
+        summary.total_with_overhead = Some(messages + CHAT_REPLY_PRIMING);
+    }
+
+    if args.pack {
+        let window = args.context_window.or(args.fits).unwrap_or_default();
+        let options = args.count_options();
+        let encoders = load_encoders(&options)?;
+        let pack = Pack {
+            window,
+            encoder: encoders[0].as_ref(),
+            inline,
+            options: &options,
+        };
+        match &args.output {
+            Some(path) => write_atomically(path, |out| write_pack(out, &ordered, &pack))?,
+            None => write_pack(&mut io::stdout().lock(), &ordered, &pack)?,
+        }
+        return Ok(summary);
+    }
+
+    match &args.output {
+        Some(path) => {
+            let table = args.table_options(false);
+            write_atomically(path, |out| {
+                print_report(out, &ordered, &summary, args, &table)
+            })?;
+            if args.also_table {
+                let table = args.table_options(io::stdout().is_terminal());
+                print_table(&mut io::stdout().lock(), &ordered, &summary, &table)?;
+            }
+        }
+        None => {
+            let table = args.table_options(io::stdout().is_terminal());
+            print_report(&mut io::stdout().lock(), &ordered, &summary, args, &table)?
+        }
+    }
+    Ok(summary)
+}
+
+/// What `--pack` needs besides the rows to pack.
+struct Pack<'a> {
+    window: u64,
+    /// Counts each file together with its separator line, so the bundle as a
+    /// whole stays within the window.
+    encoder: &'a dyn Tokenizer,
+    /// Text of the `<stdin>` and `<text:N>` rows, which cannot be read back.
+    inline: &'a HashMap<String, String>,
+    options: &'a CountOptions,
+}
+
+/// `--pack`: concatenates the files in `ordered` until the next one would take
+/// the total over the window, separator lines included. Files over the window
+/// on their own are skipped with a warning rather than ending the pack.
+fn write_pack(out: &mut impl Write, ordered: &[FileStat], pack: &Pack) -> io::Result<()> {
+    let window = pack.window;
+    let mut used = 0;
+    let mut packed = 0;
+    for stat in ordered {
+        if stat.tokens > window {
+            warn!(
+                "skipping {}: {} tokens exceed the context window of {}",
+                stat.path, stat.tokens, window
+            );
+            continue;
+        }
+        // Re-read rather than holding every file's contents through the scan.
+        let contents = match pack.inline.get(&stat.path) {
+            Some(text) => text.clone(),
+            None => match read_text(Path::new(&stat.path), pack.options) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    warn!("{err}");
+                    continue;
+                }
+            },
+        };
+        let mut chunk = format!("// === {} ===\n{contents}", stat.path);
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            chunk.push('\n');
+        }
+        let tokens = match pack.encoder.count(&chunk) {
+            Ok(tokens) => tokens,
+            Err(err) => {
+                warn!("skipping {}: {err}", stat.path);
+                continue;
+            }
+        };
+        if tokens > window {
+            warn!(
+                "skipping {}: {} tokens with its separator exceed the context window of {}",
+                stat.path, tokens, window
+            );
+            continue;
+        }
+        if used + tokens > window {
+            info!(
+                "pack is full at {used} of {window} tokens, stopping before {}",
+                stat.path
+            );
+            break;
+        }
+        out.write_all(chunk.as_bytes())?;
+        used += tokens;
+        packed += 1;
+    }
+    info!("packed {packed} file(s), {used} of {window} tokens");
+    Ok(())
+}
+
+fn print_report(
+    out: &mut impl Write,
+    ordered: &[FileStat],
+    summary: &Summary,
+    args: &Args,
+    table: &TableOptions,
+) -> io::Result<()> {
+    if args.print0 && !matches!(args.format, OutputFormat::Tsv) {
+        return print_paths(out, ordered, b'\0');
+    }
+    if let Some(template) = &args.template {
+        return print_template(out, ordered, template);
+    }
+    match args.group_by {
+        Some(GroupBy::Ext) => {
+            let mut groups = group_by_extension(ordered);
+            if let SortBy::Tokens = args.sort {
+                groups.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.ext.cmp(&b.ext)));
+            }
+            if args.reverse {
+                groups.reverse();
+            }
+            return print_groups(out, &groups, summary, args, table);
+        }
+        Some(GroupBy::Dir) => {
+            // directory_breakdown already orders by tokens.
+            let mut groups = directory_breakdown(ordered, args.group_depth);
+            match args.sort {
+                SortBy::Path => groups.sort_by(|a, b| a.path.cmp(&b.path)),
+                SortBy::Natural => groups.sort_by(|a, b| natural_cmp(&a.path, &b.path)),
+                SortBy::Tokens => {}
+            }
+            if args.reverse {
+                groups.reverse();
+            }
+            return print_groups(out, &groups, summary, args, table);
+        }
+        None => {}
+    }
+    match args.format {
+        OutputFormat::Table if args.summary_only => print_table_summary(out, summary, table),
+        OutputFormat::Table => print_table(out, ordered, summary, table),
+        OutputFormat::Json if args.summary_only => print_json_summary(out, summary),
+        OutputFormat::Json => print_json(out, ordered, summary),
+        OutputFormat::Json2 => print_json2(out, ordered, summary),
+        OutputFormat::Yaml => print_yaml(out, ordered, summary),
+        OutputFormat::Ndjson => {
+            print_ndjson(out, ordered, summary, &args.fields, args.with_summary())
+        }
+        OutputFormat::Csv => print_csv(
+            out,
+            ordered,
+            summary,
+            &args.csv_fields(),
+            args.trailing_summary(),
+        ),
+        OutputFormat::Tsv => print_tsv(out, ordered, summary, args.trailing_summary(), args.print0),
+        OutputFormat::Markdown => match args.markdown_style {
+            MarkdownStyle::List => print_markdown(out, ordered, summary),
+            MarkdownStyle::Fenced => print_markdown_fenced(out, ordered, summary),
+        },
+        OutputFormat::Html => print_html(out, ordered, summary),
+        OutputFormat::Junit => print_junit(out, ordered, summary, args.max_file_tokens),
+        OutputFormat::Github => print_github(out, ordered, summary, args.max_file_tokens, table),
+        OutputFormat::Prometheus => print_prometheus(out, ordered, summary),
+        OutputFormat::Paths => print_paths(out, ordered, b'\n'),
+        // Just the root line, which carries the total.
+        OutputFormat::Tree if args.summary_only => {
+            writeln!(out, "{}  .", table.count(summary.total))
+        }
+        OutputFormat::Tree => print_tree(out, ordered, args.sort, args.tree_depth, table),
+        OutputFormat::Total => writeln!(out, "{}", summary.total),
+    }
+}
+
+/// A row of `--group-by` output.
+trait GroupRow: Serialize {
+    const HEADER: &'static [&'static str];
+
+    /// Cells in `HEADER` order; the first is left-aligned, the rest right-aligned.
+    fn cells(&self, table: &TableOptions) -> Vec<String>;
+}
+
+impl GroupRow for ExtensionGroup {
+    const HEADER: &'static [&'static str] = &["ext", "files", "tokens", "average", "max"];
+
+    fn cells(&self, table: &TableOptions) -> Vec<String> {
+        vec![
+            self.ext.clone(),
+            self.files.to_string(),
+            table.count(self.tokens),
+            format!("{:.2}", self.average),
+            table.count(self.max),
+        ]
+    }
+}
+
+impl GroupRow for DirectoryStat {
+    const HEADER: &'static [&'static str] = &["dir", "files", "tokens"];
+
+    fn cells(&self, table: &TableOptions) -> Vec<String> {
+        vec![
+            self.path.clone(),
+            self.files.to_string(),
+            table.count(self.tokens),
+        ]
+    }
+}
+
+fn print_groups<R: GroupRow>(
+    out: &mut impl Write,
+    groups: &[R],
+    summary: &Summary,
+    args: &Args,
+    table: &TableOptions,
+) -> io::Result<()> {
+    match args.format {
+        OutputFormat::Json => {
+            let mut rows: Vec<serde_json::Value> = groups
+                .iter()
+                .map(serde_json::to_value)
+                .collect::<serde_json::Result<_>>()?;
+            rows.push(serde_json::json!({ "summary": summary }));
+            serde_json::to_writer_pretty(&mut *out, &rows)?;
+            writeln!(out)
+        }
+        OutputFormat::Json2 => {
+            let report = serde_json::json!({ "version": 2, "groups": groups, "summary": summary });
+            serde_json::to_writer_pretty(&mut *out, &report)?;
+            writeln!(out)
+        }
+        OutputFormat::Ndjson => {
+            for group in groups {
+                writeln!(out, "{}", serde_json::to_string(group)?)?;
+            }
+            if args.with_summary() {
+                writeln!(out, "{}", serde_json::to_string(&SummaryRow { summary })?)?;
+            }
+            Ok(())
+        }
+        _ => {
+            let header: Vec<String> = R::HEADER.iter().map(|cell| cell.to_string()).collect();
+            let rows: Vec<Vec<String>> = groups.iter().map(|group| group.cells(table)).collect();
+            let mut widths: Vec<usize> = header.iter().map(|cell| cell.len()).collect();
+            for row in &rows {
+                for (width, cell) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(cell.chars().count());
+                }
+            }
+            for row in std::iter::once(&header).chain(&rows) {
+                let mut line = String::new();
+                for (index, (cell, width)) in row.iter().zip(&widths).enumerate() {
+                    if index == 0 {
+                        line.push_str(&format!("{cell:<width$}"));
+                    } else {
+                        line.push_str(&format!("  {cell:>width$}"));
+                    }
+                }
+                writeln!(out, "{}", line)?;
+            }
+            print_table_summary(out, summary, table)
+        }
+    }
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so an
+/// interrupted run never leaves a truncated report behind.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<&mut NamedTempFile>) -> io::Result<()>,
+) -> Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create output file {}", path.display()))?;
+    let mut file = NamedTempFile::new_in(dir)
+        .with_context(|| format!("failed to create output file {}", path.display()))?;
+    {
+        let mut out = BufWriter::new(&mut file);
+        write(&mut out)
+            .and_then(|()| out.flush())
+            .with_context(|| format!("failed to write output file {}", path.display()))?;
+    }
+    file.persist(path)
+        .with_context(|| format!("failed to write output file {}", path.display()))?;
+    Ok(())
+}
+
+fn print_table(
+    out: &mut impl Write,
+    stats: &[FileStat],
+    summary: &Summary,
+    options: &TableOptions,
+) -> io::Result<()> {
+    if !options.fields.is_empty() {
+        print_table_fields(out, stats, options)?;
+        return print_table_summary(out, summary, options);
+    }
+
+    let counts: Vec<String> = stats.iter().map(|s| options.count(s.tokens)).collect();
+    let mut width = counts.iter().map(|c| c.len()).max().unwrap_or(1);
+    let metrics = options.extended.then(|| extended_columns(stats));
+    let comparison = summary
+        .totals
+        .is_some()
+        .then(|| comparison_columns(stats, summary));
+    let changes = summary.total_delta.is_some().then(|| delta_columns(stats));
+    let costs = summary.total_cost.is_some().then(|| cost_columns(stats));
+    if metrics.is_some() || comparison.is_some() || changes.is_some() || costs.is_some() {
+        let label = match &comparison {
+            Some(_) => summary.encoding.as_deref().unwrap_or("tokens"),
+            None => "tokens",
+        };
+        width = width.max(label.len());
+        let mut header = format!("{:>width$}", label, width = width);
+        for columns in [&comparison, &changes, &costs, &metrics]
+            .into_iter()
+            .flatten()
+        {
+            header = format!("{header}  {}", columns.header);
+        }
+        writeln!(out, "{header}  path")?;
+    }
+
+    for (index, (stat, count)) in stats.iter().zip(&counts).enumerate() {
+        let mut tokens = format!("{:>width$}", count, width = width);
+        if options.color {
+            let code = token_color(stat.tokens, summary, options.color_limit);
+            tokens = format!("{code}{tokens}{ANSI_RESET}");
+        }
+        for columns in [&comparison, &changes, &costs, &metrics]
+            .into_iter()
+            .flatten()
+        {
+            tokens = format!("{tokens}  {}", columns.rows[index]);
+        }
+        let marker = if stat.over_budget == Some(true) {
+            " !"
+        } else {
+            ""
+        };
+        // A gutter in front of the path flags files that exceed the context window.
+        let path = match stat.fits {
+            Some(false) => format!("! {}", stat.path),
+            Some(true) => format!("  {}", stat.path),
+            None => stat.path.clone(),
+        };
+        match options.file_price_per_1k {
+            Some(price) => writeln!(
+                out,
+                "{}  {:>12.6}  {}{}",
+                tokens,
+                estimate_cost(stat.tokens, price),
+                path,
+                marker
+            )?,
+            None => writeln!(out, "{}  {}{}", tokens, path, marker)?,
+        }
+    }
+
+    print_table_summary(out, summary, options)
+}
+
+/// Pre-rendered `--extended` or comparison columns for the table.
+struct ExtendedColumns {
+    header: String,
+    rows: Vec<String>,
+}
+
+fn extended_columns(stats: &[FileStat]) -> ExtendedColumns {
+    let cells: Vec<[String; 4]> = stats
+        .iter()
+        .map(|stat| {
+            let show = |value: Option<u64>| value.map(|v| v.to_string()).unwrap_or_default();
+            [
+                show(stat.lines),
+                show(stat.bytes),
+                show(stat.chars),
+                stat.bytes_per_token()
+                    .map(|ratio| format!("{ratio:.2}"))
+                    .unwrap_or_default(),
+            ]
+        })
+        .collect();
+    let names = ["lines", "bytes", "chars", "bytes/token"];
+    let mut widths = names.map(str::len);
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let render = |row: [&str; 4]| {
+        row.iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:>width$}"))
+            .collect::<Vec<_>>()
+            .join("  ")
+    };
+    ExtendedColumns {
+        header: render(names),
+        rows: cells
+            .iter()
+            .map(|row| render([&row[0], &row[1], &row[2], &row[3]]))
+            .collect(),
+    }
+}
+
+/// Per-file dollar cost with 4 decimals.
+fn cost_columns(stats: &[FileStat]) -> ExtendedColumns {
+    let rows: Vec<String> = stats
+        .iter()
+        .map(|stat| {
+            stat.cost
+                .map(|cost| format!("{cost:.4}"))
+                .unwrap_or_default()
+        })
+        .collect();
+    let width = rows
+        .iter()
+        .map(String::len)
+        .max()
+        .unwrap_or(0)
+        .max("cost".len());
+    ExtendedColumns {
+        header: format!("{:>width$}", "cost"),
+        rows: rows.iter().map(|row| format!("{row:>width$}")).collect(),
+    }
+}
+
+/// Columns for the encodings after the first, plus the signed delta.
+fn comparison_columns(stats: &[FileStat], summary: &Summary) -> ExtendedColumns {
+    let primary = summary.encoding.as_deref().unwrap_or_default();
+    let mut names: Vec<&str> = summary
+        .totals
+        .iter()
+        .flat_map(|totals| totals.keys())
+        .map(String::as_str)
+        .filter(|name| *name != primary)
+        .collect();
+    names.push("delta");
+    let cells: Vec<Vec<String>> = stats
+        .iter()
+        .map(|stat| {
+            let counts = stat.counts.as_ref();
+            let mut row: Vec<String> = names[..names.len() - 1]
+                .iter()
+                .map(|name| {
+                    counts
+                        .and_then(|counts| counts.get(*name))
+                        .map(u64::to_string)
+                        .unwrap_or_default()
+                })
+                .collect();
+            row.push(stat.delta.map(format_delta).unwrap_or_default());
+            row
+        })
+        .collect();
+    let mut widths: Vec<usize> = names.iter().map(|name| name.len()).collect();
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let render = |row: Vec<&str>| {
+        row.iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:>width$}"))
+            .collect::<Vec<_>>()
+            .join("  ")
+    };
+    ExtendedColumns {
+        header: render(names.clone()),
+        rows: cells
+            .iter()
+            .map(|row| render(row.iter().map(String::as_str).collect()))
+            .collect(),
+    }
+}
+
+/// The signed change since `--baseline`, one cell per row.
+fn delta_columns(stats: &[FileStat]) -> ExtendedColumns {
+    let rows: Vec<String> = stats
+        .iter()
+        .map(|stat| stat.delta.map(format_delta).unwrap_or_default())
+        .collect();
+    let width = rows
+        .iter()
+        .map(String::len)
+        .max()
+        .unwrap_or(0)
+        .max("delta".len());
+    ExtendedColumns {
+        header: format!("{:>width$}", "delta"),
+        rows: rows.iter().map(|row| format!("{row:>width$}")).collect(),
+    }
+}
 
-    for result in builder.build() {
-        match result {
-            Ok(entry) => {
-                if excludes.is_match(entry.path()) {
-                    continue;
-                }
-                if !entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
-                    continue;
-                }
-                if let Some(ext) = entry.path().extension().and_then(|e| e.to_str()) {
-                    if !include_exts.contains(&ext.to_ascii_lowercase()) {
-                        continue;
-                    }
-                } else {
-                    continue;
-                }
-                files.push(entry.into_path());
+fn format_delta(delta: i64) -> String {
+    if delta > 0 {
+        format!("+{delta}")
+    } else {
+        delta.to_string()
+    }
+}
+
+fn print_table_fields(
+    out: &mut impl Write,
+    stats: &[FileStat],
+    options: &TableOptions,
+) -> io::Result<()> {
+    let cell = |field: Field, stat: &FileStat| match field {
+        Field::Tokens => options.count(stat.tokens),
+        _ => field.text(stat),
+    };
+    let widths: Vec<usize> = options
+        .fields
+        .iter()
+        .map(|field| {
+            stats
+                .iter()
+                .map(|stat| cell(*field, stat).chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    for stat in stats {
+        let mut line = String::new();
+        for (index, field) in options.fields.iter().enumerate() {
+            if index > 0 {
+                line.push_str("  ");
             }
-            Err(err) => {
-                if !args.quiet {
-                    warn!("walk error: {err}");
-                }
+            let value = cell(*field, stat);
+            let last = index + 1 == options.fields.len();
+            if field.right_aligned() {
+                line.push_str(&format!("{:>width$}", value, width = widths[index]));
+            } else if last {
+                line.push_str(&value);
+            } else {
+                line.push_str(&format!("{:<width$}", value, width = widths[index]));
             }
         }
+        writeln!(out, "{}", line)?;
     }
     Ok(())
 }
 
-fn count_tokens(files: Vec<PathBuf>, args: &Args, encoding: Arc<CoreBPE>) -> Result<Vec<FileStat>> {
-    let max_bytes = args.max_bytes;
-    let quiet = args.quiet;
-    let stats: Vec<FileStat> = files
-        .par_iter()
-        .filter_map(|path| {
-            let encoder = encoding.clone();
-            match process_file(path, max_bytes, encoder.as_ref()) {
-                Ok(stat) => Some(stat),
-                Err(err @ ProcessError::TooLarge { .. }) => {
-                    if !quiet {
-                        info!("{}", err);
-                    }
-                    None
-                }
-                Err(err) => {
-                    if !quiet {
-                        warn!("{}", err);
-                    }
-                    None
-                }
-            }
+fn print_table_summary(
+    out: &mut impl Write,
+    summary: &Summary,
+    options: &TableOptions,
+) -> io::Result<()> {
+    if !options.color {
+        return print_table_footer(out, summary, options);
+    }
+    let mut footer = Vec::new();
+    print_table_footer(&mut footer, summary, options)?;
+    for line in String::from_utf8_lossy(&footer).lines() {
+        if let Some((label, value)) = line.split_once(": ") {
+            writeln!(out, "{ANSI_BOLD}{label}:{ANSI_RESET} {value}")?;
+        } else if line.ends_with(':') {
+            writeln!(out, "{ANSI_BOLD}{line}{ANSI_RESET}")?;
+        } else {
+            writeln!(out, "{line}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Grades a count green → yellow → red: against the limit when one is set (half of it
+/// turns yellow, going over turns red), otherwise against the scan's p50 and p90.
+fn token_color(tokens: u64, summary: &Summary, limit: Option<u64>) -> &'static str {
+    match limit {
+        Some(limit) if tokens > limit => ANSI_RED,
+        Some(limit) if tokens.saturating_mul(2) > limit => ANSI_YELLOW,
+        Some(_) => ANSI_GREEN,
+        None if tokens as f64 >= summary.p90 && tokens as f64 > summary.p50 => ANSI_RED,
+        None if tokens as f64 > summary.p50 => ANSI_YELLOW,
+        None => ANSI_GREEN,
+    }
+}
+
+fn print_table_footer(
+    out: &mut impl Write,
+    summary: &Summary,
+    options: &TableOptions,
+) -> io::Result<()> {
+    writeln!(out, "\n---")?;
+    writeln!(out, "total files: {}", summary.files)?;
+    writeln!(out, "total tokens: {}", options.count(summary.total))?;
+    if let Some(totals) = &summary.totals {
+        for (name, total) in totals {
+            writeln!(out, "  {}: {}", name, options.count(*total))?;
+        }
+        let others: u64 = totals
+            .iter()
+            .filter(|(name, _)| Some(name.as_str()) != summary.encoding.as_deref())
+            .map(|(_, total)| total)
+            .sum();
+        writeln!(
+            out,
+            "  delta: {}",
+            format_delta(others as i64 - summary.total as i64)
+        )?;
+    }
+    if options.human {
+        writeln!(
+            out,
+            "average/file: {}",
+            humanize(summary.average.round() as u64)
+        )?;
+    } else {
+        writeln!(out, "average/file: {:.2}", summary.average)?;
+    }
+    for (percent, value) in reported_percentiles(summary) {
+        let label = Percentiles::label(percent);
+        if options.human {
+            writeln!(out, "{label}: {}", humanize(value.round() as u64))?;
+        } else {
+            writeln!(out, "{label}: {}", format_percentile(value, summary))?;
+        }
+    }
+    if let Some(duplicates) = summary.duplicates {
+        writeln!(out, "duplicates skipped: {}", duplicates)?;
+    }
+    if let Some(skipped) = summary.skipped_binary {
+        writeln!(out, "binary files skipped: {}", skipped)?;
+    }
+    if let Some(skipped) = summary.skipped_too_large {
+        writeln!(out, "files over --max-bytes: {}", skipped)?;
+    }
+    if let Some(skipped) = summary.skipped_too_small {
+        writeln!(out, "files under --min-bytes: {}", skipped)?;
+    }
+    if let Some(lossy) = summary.lossy_files {
+        writeln!(out, "lossily decoded files: {}", lossy)?;
+    }
+    if let Some(hidden) = summary.hidden_files {
+        writeln!(out, "files below --min-tokens: {}", hidden)?;
+    }
+    if let Some(over) = summary.files_over_window {
+        writeln!(out, "files over context window: {}", over)?;
+    }
+    if let Some(packed) = summary.files_fitting_packed {
+        writeln!(out, "files fitting the window together: {}", packed)?;
+    }
+    if let Some(delta) = summary.total_delta {
+        writeln!(out, "change since baseline: {}", format_delta(delta))?;
+    }
+    if let Some(removed) = summary.removed_files.as_ref().filter(|r| !r.is_empty()) {
+        writeln!(out, "removed since baseline: {}", removed.len())?;
+    }
+    if let Some(cost) = summary.cost {
+        writeln!(out, "estimated cost: {:.6}", cost)?;
+    }
+    if let Some(cost) = summary.total_cost {
+        writeln!(out, "total cost: ${:.4}", cost)?;
+    }
+    if let Some(total) = summary.total_with_overhead {
+        writeln!(out, "total with chat overhead: {}", options.count(total))?;
+    }
+    if let Some(top) = &summary.top {
+        writeln!(out, "top files:")?;
+        for stat in top {
+            writeln!(out, "  {} ({})", stat.path, stat.tokens)?;
+        }
+    }
+    if let Some(by_extension) = &summary.by_extension {
+        let ext_width = by_extension.keys().map(|ext| ext.len()).max().unwrap_or(1);
+        let files_width = by_extension
+            .values()
+            .map(|stat| num_digits(stat.files))
+            .max()
+            .unwrap_or(1);
+        let tokens_width = by_extension
+            .values()
+            .map(|stat| num_digits(stat.tokens))
+            .max()
+            .unwrap_or(1);
+        writeln!(out, "by extension:")?;
+        for (ext, stat) in by_extension {
+            writeln!(
+                out,
+                "  {:<ext_width$}  {:>files_width$} files  {:>tokens_width$} tokens",
+                ext, stat.files, stat.tokens
+            )?;
+        }
+    }
+    if let Some(by_directory) = &summary.by_directory {
+        let tokens_width = by_directory
+            .iter()
+            .map(|dir| num_digits(dir.tokens))
+            .max()
+            .unwrap_or(1);
+        writeln!(out, "by directory:")?;
+        for dir in by_directory {
+            writeln!(
+                out,
+                "  {:>tokens_width$}  {} ({} files)",
+                dir.tokens, dir.path, dir.files
+            )?;
+        }
+    }
+    if let Some(buckets) = &summary.histogram {
+        print_histogram(out, buckets)?;
+    }
+    Ok(())
+}
+
+fn print_histogram(out: &mut impl Write, buckets: &[HistogramBucket]) -> io::Result<()> {
+    let last_edge = buckets.iter().rev().find_map(|bucket| bucket.le);
+    let labels: Vec<String> = buckets
+        .iter()
+        .map(|bucket| match (bucket.le, last_edge) {
+            (Some(le), _) => format!("<= {le}"),
+            (None, Some(edge)) => format!("> {edge}"),
+            (None, None) => String::from("all"),
         })
         .collect();
-    Ok(stats)
+    let label_width = labels.iter().map(|label| label.len()).max().unwrap_or(1);
+    let max_count = buckets.iter().map(|b| b.count).max().unwrap_or(0);
+    let count_width = num_digits(max_count);
+    let bar_width = terminal_width()
+        .saturating_sub(label_width + count_width + 6)
+        .max(10);
+
+    writeln!(out, "histogram:")?;
+    for (label, bucket) in labels.iter().zip(buckets) {
+        let bar = if max_count == 0 {
+            0
+        } else {
+            (bucket.count as u128 * bar_width as u128).div_ceil(max_count as u128) as usize
+        };
+        let line = format!(
+            "  {:>label_width$}  {:>count_width$}  {}",
+            label,
+            bucket.count,
+            "#".repeat(bar)
+        );
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
 }
 
-fn process_file(
-    path: &Path,
-    max_bytes: Option<u64>,
-    encoding: &CoreBPE,
-) -> std::result::Result<FileStat, ProcessError> {
-    let display_path = normalize_display_path(path);
-    let metadata = fs::metadata(path).map_err(|source| ProcessError::Metadata {
-        path: display_path.clone(),
-        source,
-    })?;
-
-    if let Some(limit) = max_bytes {
-        if metadata.len() > limit {
-            return Err(ProcessError::TooLarge {
-                path: display_path.clone(),
-                size: metadata.len(),
-                limit,
-            });
+/// Width for scaled output, from `$COLUMNS` when the shell exports it.
+fn terminal_width() -> usize {
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(80)
+}
+
+/// A directory (or file, when `children` is empty) in `--format tree` output.
+#[derive(Default)]
+struct TreeNode {
+    tokens: u64,
+    is_dir: bool,
+    children: BTreeMap<String, TreeNode>,
+}
+
+impl TreeNode {
+    fn build(stats: &[FileStat]) -> TreeNode {
+        let mut root = TreeNode {
+            is_dir: true,
+            ..TreeNode::default()
+        };
+        for stat in stats {
+            root.tokens += stat.tokens;
+            let mut node = &mut root;
+            let mut parts = Path::new(&stat.path)
+                .components()
+                .filter(|c| !matches!(c, Component::CurDir))
+                .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                .peekable();
+            while let Some(part) = parts.next() {
+                node = node.children.entry(part).or_default();
+                node.tokens += stat.tokens;
+                node.is_dir = parts.peek().is_some();
+            }
         }
+        root
     }
 
-    let contents = fs::read_to_string(path).map_err(|source| ProcessError::Read {
-        path: display_path.clone(),
-        source,
-    })?;
+    fn sorted_children(&self, sort: SortBy) -> Vec<(&String, &TreeNode)> {
+        let mut children: Vec<_> = self.children.iter().collect();
+        if let SortBy::Tokens = sort {
+            children.sort_by(|a, b| b.1.tokens.cmp(&a.1.tokens).then_with(|| a.0.cmp(b.0)));
+        }
+        children
+    }
+}
 
-    let tokens = encoding.encode_ordinary(&contents);
-    Ok(FileStat {
-        path: display_path,
-        tokens: tokens.len() as u64,
-    })
+fn print_tree(
+    out: &mut impl Write,
+    stats: &[FileStat],
+    sort: SortBy,
+    max_depth: Option<usize>,
+    options: &TableOptions,
+) -> io::Result<()> {
+    fn walk(
+        out: &mut impl Write,
+        node: &TreeNode,
+        depth: usize,
+        width: usize,
+        sort: SortBy,
+        max_depth: Option<usize>,
+        options: &TableOptions,
+    ) -> io::Result<()> {
+        if max_depth.is_some_and(|max| depth >= max) {
+            return Ok(());
+        }
+        for (name, child) in node.sorted_children(sort) {
+            let suffix = if child.is_dir { "/" } else { "" };
+            writeln!(
+                out,
+                "{:>width$}  {}{}{}",
+                options.count(child.tokens),
+                "  ".repeat(depth),
+                name,
+                suffix
+            )?;
+            walk(out, child, depth + 1, width, sort, max_depth, options)?;
+        }
+        Ok(())
+    }
+
+    let root = TreeNode::build(stats);
+    let width = options.count(root.tokens).len();
+    writeln!(out, "{:>width$}  .", options.count(root.tokens))?;
+    walk(
+        out,
+        &root,
+        1,
+        width,
+        sort,
+        max_depth.map(|d| d + 1),
+        options,
+    )
 }
 
-fn output_results(stats: &[FileStat], args: &Args) {
-    let mut all = stats.to_owned();
-    all.sort_by(|a, b| a.path.cmp(&b.path));
+fn print_paths(out: &mut impl Write, stats: &[FileStat], terminator: u8) -> io::Result<()> {
+    for stat in stats {
+        out.write_all(stat.path.as_bytes())?;
+        out.write_all(&[terminator])?;
+    }
+    Ok(())
+}
 
-    let mut token_sorted = stats.to_owned();
-    token_sorted.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.path.cmp(&b.path)));
+fn print_json(out: &mut impl Write, stats: &[FileStat], summary: &Summary) -> io::Result<()> {
+    /// Serializes as the rows followed by a `{"summary": ...}` element.
+    struct LegacyReport<'a> {
+        stats: &'a [FileStat],
+        summary: &'a Summary,
+    }
 
-    let display_stats = if let Some(top) = args.top {
-        token_sorted.iter().take(top).cloned().collect::<Vec<_>>()
-    } else {
-        match args.sort {
-            SortBy::Path => all.clone(),
-            SortBy::Tokens => token_sorted.clone(),
+    impl Serialize for LegacyReport<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(self.stats.len() + 1))?;
+            for stat in self.stats {
+                seq.serialize_element(stat)?;
+            }
+            seq.serialize_element(&SummaryRow {
+                summary: self.summary,
+            })?;
+            seq.end()
         }
+    }
+
+    let report = LegacyReport { stats, summary };
+    serde_json::to_writer_pretty(&mut *out, &report)?;
+    writeln!(out)
+}
+
+#[derive(Serialize, JsonSchema)]
+struct SummaryRow<'a> {
+    summary: &'a Summary,
+}
+
+/// Writes the JSON Schema for `--format json` output. Optional fields are
+/// left out of the report rather than written as `null`, so the schema only
+/// marks them as not required.
+fn print_schema(out: &mut impl Write) -> io::Result<()> {
+    let mut schema = schemars::schema_for!(Vec<JsonRow>);
+    schema.insert("title".into(), "tokencount --format json".into());
+    if let Some(definitions) = schema.get_mut("$defs").and_then(|d| d.as_object_mut()) {
+        definitions.values_mut().for_each(drop_null_from_optional);
+    }
+    serde_json::to_writer_pretty(&mut *out, &schema)?;
+    writeln!(out)
+}
+
+/// schemars allows `null` for every `Option` field; ours are skipped instead.
+fn drop_null_from_optional(definition: &mut serde_json::Value) {
+    let required = definition.get("required").cloned();
+    let Some(properties) = definition
+        .get_mut("properties")
+        .and_then(|p| p.as_object_mut())
+    else {
+        return;
     };
+    for (name, property) in properties {
+        if required
+            .as_ref()
+            .and_then(|required| required.as_array())
+            .is_some_and(|required| required.iter().any(|r| r == name.as_str()))
+        {
+            continue;
+        }
+        if let Some(types) = property["type"].as_array_mut() {
+            types.retain(|t| t != "null");
+            if let [only] = types.as_slice() {
+                property["type"] = only.clone();
+            }
+        }
+    }
+}
 
-    let mut ordered = display_stats;
-    if args.top.is_some() {
-        match args.sort {
-            SortBy::Path => ordered.sort_by(|a, b| a.path.cmp(&b.path)),
-            SortBy::Tokens => {
-                ordered.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.path.cmp(&b.path)))
+/// An element of the `--format json` array: a file row, or the trailing summary.
+// Never built; it only describes that shape for --print-schema.
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum JsonRow<'a> {
+    File(FileStat),
+    Summary(SummaryRow<'a>),
+}
+
+/// Current JSON shape, emitted by `--format json2`.
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    version: u32,
+    files: &'a [FileStat],
+    summary: &'a Summary,
+}
+
+fn print_json2(out: &mut impl Write, stats: &[FileStat], summary: &Summary) -> io::Result<()> {
+    let report = JsonReport {
+        version: 2,
+        files: stats,
+        summary,
+    };
+    serde_json::to_writer_pretty(&mut *out, &report)?;
+    writeln!(out)
+}
+
+/// Shape emitted by `--format yaml`.
+#[derive(Serialize)]
+struct YamlReport<'a> {
+    files: &'a [FileStat],
+    summary: &'a Summary,
+}
+
+fn print_yaml(out: &mut impl Write, stats: &[FileStat], summary: &Summary) -> io::Result<()> {
+    let report = YamlReport {
+        files: stats,
+        summary,
+    };
+    serde_yaml::to_writer(out, &report).map_err(io::Error::other)
+}
+
+fn print_json_summary(out: &mut impl Write, summary: &Summary) -> io::Result<()> {
+    serde_json::to_writer_pretty(&mut *out, &SummaryRow { summary })?;
+    writeln!(out)
+}
+
+fn print_ndjson(
+    out: &mut impl Write,
+    stats: &[FileStat],
+    summary: &Summary,
+    fields: &[Field],
+    with_summary: bool,
+) -> io::Result<()> {
+    for stat in stats {
+        if fields.is_empty() {
+            let json = serde_json::to_string(stat)?;
+            writeln!(out, "{}", json)?;
+            continue;
+        }
+        // Built by hand so keys follow the --fields order.
+        let mut row = String::from("{");
+        for (index, field) in fields.iter().enumerate() {
+            if index > 0 {
+                row.push(',');
             }
+            row.push_str(&serde_json::to_string(field.name())?);
+            row.push(':');
+            row.push_str(&serde_json::to_string(&field.json(stat))?);
         }
+        row.push('}');
+        writeln!(out, "{}", row)?;
     }
 
-    let summary = build_summary(
-        stats,
-        args.top
-            .map(|n| token_sorted.iter().take(n).cloned().collect::<Vec<_>>()),
-    );
+    if with_summary {
+        let json = serde_json::to_string(&serde_json::json!({ "summary": summary }))?;
+        writeln!(out, "{}", json)?;
+    }
+    Ok(())
+}
 
-    match args.format {
-        OutputFormat::Table => print_table(&ordered, &summary),
-        OutputFormat::Json => print_json(&ordered, &summary),
-        OutputFormat::Ndjson => print_ndjson(&ordered, &summary, args.with_summary()),
+fn print_csv(
+    out: &mut impl Write,
+    stats: &[FileStat],
+    summary: &Summary,
+    fields: &[Field],
+    with_summary: bool,
+) -> io::Result<()> {
+    let header: Vec<&str> = fields.iter().map(|field| field.name()).collect();
+    writeln!(out, "{}", header.join(","))?;
+    for stat in stats {
+        let row: Vec<String> = fields
+            .iter()
+            .map(|field| csv_field(&field.text(stat)))
+            .collect();
+        writeln!(out, "{}", row.join(","))?;
+    }
+
+    if with_summary {
+        writeln!(out, "# files: {}", summary.files)?;
+        writeln!(out, "# total: {}", summary.total)?;
+        writeln!(out, "# average: {:.2}", summary.average)?;
+        for (percent, value) in reported_percentiles(summary) {
+            let label = Percentiles::label(percent);
+            writeln!(out, "# {label}: {}", format_percentile(value, summary))?;
+        }
     }
+    Ok(())
 }
 
-fn build_summary(all_stats: &[FileStat], top: Option<Vec<FileStat>>) -> Summary {
-    let files = all_stats.len() as u64;
-    let total: u64 = all_stats.iter().map(|s| s.tokens).sum();
-    let average = if files > 0 {
-        total as f64 / files as f64
+fn print_tsv(
+    out: &mut impl Write,
+    stats: &[FileStat],
+    summary: &Summary,
+    with_summary: bool,
+    print0: bool,
+) -> io::Result<()> {
+    // NUL-terminated records can carry any path as is; lines need escaping.
+    let (end, escape): (&str, fn(&str) -> String) = if print0 {
+        ("\0", str::to_string)
     } else {
-        0.0
+        ("\n", tsv_field)
     };
-    let mut counts: Vec<u64> = all_stats.iter().map(|s| s.tokens).collect();
-    counts.sort_unstable();
+    for stat in stats {
+        write!(out, "{}\t{}{end}", stat.tokens, escape(&stat.path))?;
+    }
+    if with_summary {
+        write!(out, "total\t{}{end}", summary.total)?;
+    }
+    Ok(())
+}
+
+/// Escapes the characters that would split a TSV field or row.
+fn tsv_field(value: &str) -> String {
+    if value.contains(['\\', '\t', '\n', '\r']) {
+        value
+            .replace('\\', "\\\\")
+            .replace('\t', "\\t")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r")
+    } else {
+        value.to_string()
+    }
+}
 
-    Summary {
-        files,
-        total,
-        average,
-        p50: percentile(&counts, 0.50),
-        p90: percentile(&counts, 0.90),
-        p99: percentile(&counts, 0.99),
-        top,
+fn print_template(out: &mut impl Write, stats: &[FileStat], template: &Template) -> io::Result<()> {
+    for stat in stats {
+        writeln!(out, "{}", template.render(stat))?;
     }
+    Ok(())
 }
 
-fn percentile(sorted: &[u64], percentile: f64) -> u64 {
-    if sorted.is_empty() {
-        return 0;
+/// Quotes `value` when it would otherwise break the row, or when a leading `#`
+/// would make readers skip it like the `# ` summary lines.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) || value.starts_with('#') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
     }
-    let rank = (percentile * (sorted.len() as f64)).ceil().max(1.0) as usize;
-    sorted[rank.saturating_sub(1).min(sorted.len() - 1)]
 }
 
-fn print_table(stats: &[FileStat], summary: &Summary) {
-    let width = stats
-        .iter()
-        .map(|s| num_digits(s.tokens))
-        .max()
-        .unwrap_or(1);
+fn print_markdown(out: &mut impl Write, stats: &[FileStat], summary: &Summary) -> io::Result<()> {
+    writeln!(out, "| Path | Tokens |")?;
+    writeln!(out, "| --- | ---: |")?;
+    for stat in stats {
+        writeln!(out, "| {} | {} |", markdown_cell(&stat.path), stat.tokens)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "**Summary**")?;
+    writeln!(out)?;
+    writeln!(out, "- **Total files:** {}", summary.files)?;
+    writeln!(out, "- **Total tokens:** {}", summary.total)?;
+    writeln!(out, "- **Average/file:** {:.2}", summary.average)?;
+    for (percent, value) in reported_percentiles(summary) {
+        let label = Percentiles::label(percent);
+        writeln!(out, "- **{label}:** {}", format_percentile(value, summary))?;
+    }
+    if let Some(top) = &summary.top {
+        writeln!(out)?;
+        writeln!(out, "**Top files**")?;
+        writeln!(out)?;
+        for stat in top {
+            writeln!(out, "- {} ({})", markdown_cell(&stat.path), stat.tokens)?;
+        }
+    }
+    Ok(())
+}
 
+/// `--markdown-style fenced`: tokens first, and a summary that pastes into a
+/// PR description as plain text.
+fn print_markdown_fenced(
+    out: &mut impl Write,
+    stats: &[FileStat],
+    summary: &Summary,
+) -> io::Result<()> {
+    writeln!(out, "| tokens | path |")?;
+    writeln!(out, "| ---: | --- |")?;
     for stat in stats {
-        println!("{:>width$}  {}", stat.tokens, stat.path, width = width);
+        writeln!(out, "| {} | {} |", stat.tokens, markdown_cell(&stat.path))?;
     }
 
-    println!("\n---");
-    println!("total files: {}", summary.files);
-    println!("total tokens: {}", summary.total);
-    println!("average/file: {:.2}", summary.average);
-    println!("p50: {}", summary.p50);
-    println!("p90: {}", summary.p90);
-    println!("p99: {}", summary.p99);
+    writeln!(out)?;
+    writeln!(out, "```text")?;
+    writeln!(out, "files: {}", summary.files)?;
+    writeln!(out, "total: {}", summary.total)?;
+    writeln!(out, "average: {:.2}", summary.average)?;
+    for (percent, value) in reported_percentiles(summary) {
+        let label = Percentiles::label(percent);
+        writeln!(out, "{label}: {}", format_percentile(value, summary))?;
+    }
     if let Some(top) = &summary.top {
-        println!("top files:");
+        writeln!(out, "top files:")?;
         for stat in top {
-            println!("  {} ({})", stat.path, stat.tokens);
+            writeln!(out, "  {} ({})", stat.path, stat.tokens)?;
         }
     }
+    writeln!(out, "```")
 }
 
-fn print_json(stats: &[FileStat], summary: &Summary) {
-    let mut rows: Vec<serde_json::Value> = stats
-        .iter()
-        .map(|stat| {
-            serde_json::json!({
-                "path": stat.path,
-                "tokens": stat.tokens,
-            })
-        })
-        .collect();
-    rows.push(serde_json::json!({ "summary": summary }));
+fn markdown_cell(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn print_github(
+    out: &mut impl Write,
+    stats: &[FileStat],
+    summary: &Summary,
+    max_file_tokens: Option<u64>,
+    table: &TableOptions,
+) -> io::Result<()> {
+    if let Some(limit) = max_file_tokens {
+        for stat in stats.iter().filter(|stat| stat.tokens > limit) {
+            writeln!(
+                out,
+                "::warning file={}::{} tokens exceeds limit {}",
+                github_property(&stat.path),
+                stat.tokens,
+                limit
+            )?;
+        }
+    }
+    print_table(out, stats, summary, table)?;
 
-    match serde_json::to_string_pretty(&rows) {
-        Ok(json) => println!("{}", json),
-        Err(err) => eprintln!("failed to serialize json: {err}"),
+    let Some(step_summary) = std::env::var_os("GITHUB_STEP_SUMMARY") else {
+        return Ok(());
+    };
+    let result = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&step_summary)
+        .and_then(|mut file| {
+            writeln!(file, "### tokencount")?;
+            writeln!(file)?;
+            print_markdown(&mut file, stats, summary)
+        });
+    if let Err(err) = result {
+        warn!(
+            "failed to write step summary to {}: {err}",
+            Path::new(&step_summary).display()
+        );
     }
+    Ok(())
+}
+
+/// Escapes a value for use as a GitHub Actions workflow command property.
+fn github_property(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+        .replace(':', "%3A")
+        .replace(',', "%2C")
 }
 
-fn print_ndjson(stats: &[FileStat], summary: &Summary, with_summary: bool) {
+fn print_prometheus(out: &mut impl Write, stats: &[FileStat], summary: &Summary) -> io::Result<()> {
+    writeln!(
+        out,
+        "# HELP tokencount_file_tokens Tokens per scanned file."
+    )?;
+    writeln!(out, "# TYPE tokencount_file_tokens gauge")?;
     for stat in stats {
-        match serde_json::to_string(stat) {
-            Ok(json) => println!("{}", json),
-            Err(err) => eprintln!("failed to serialize ndjson row: {err}"),
+        writeln!(
+            out,
+            "tokencount_file_tokens{{path=\"{}\"}} {}",
+            prometheus_label(&stat.path),
+            stat.tokens
+        )?;
+    }
+    writeln!(
+        out,
+        "# HELP tokencount_total_tokens Total tokens across all scanned files."
+    )?;
+    writeln!(out, "# TYPE tokencount_total_tokens gauge")?;
+    writeln!(out, "tokencount_total_tokens {}", summary.total)?;
+    writeln!(
+        out,
+        "# HELP tokencount_files_total Number of scanned files."
+    )?;
+    writeln!(out, "# TYPE tokencount_files_total gauge")?;
+    writeln!(out, "tokencount_files_total {}", summary.files)?;
+    writeln!(
+        out,
+        "# HELP tokencount_average_tokens Average tokens per file."
+    )?;
+    writeln!(out, "# TYPE tokencount_average_tokens gauge")?;
+    writeln!(out, "tokencount_average_tokens {}", summary.average)?;
+    for (percent, value) in reported_percentiles(summary) {
+        // Metric names allow no dots, so p99.9 becomes tokencount_p99_9_tokens.
+        let name = format!(
+            "tokencount_{}_tokens",
+            Percentiles::label(percent).replace('.', "_")
+        );
+        writeln!(
+            out,
+            "# HELP {name} {} per-file token count.",
+            Percentiles::label(percent)
+        )?;
+        writeln!(out, "# TYPE {name} gauge")?;
+        writeln!(out, "{name} {}", format_percentile(value, summary))?;
+    }
+    if let Some(by_extension) = &summary.by_extension {
+        writeln!(
+            out,
+            "# HELP tokencount_extension_tokens Total tokens per file extension."
+        )?;
+        writeln!(out, "# TYPE tokencount_extension_tokens gauge")?;
+        for (ext, stat) in by_extension {
+            writeln!(
+                out,
+                "tokencount_extension_tokens{{ext=\"{}\"}} {}",
+                prometheus_label(ext),
+                stat.tokens
+            )?;
+        }
+        writeln!(
+            out,
+            "# HELP tokencount_extension_files Number of scanned files per file extension."
+        )?;
+        writeln!(out, "# TYPE tokencount_extension_files gauge")?;
+        for (ext, stat) in by_extension {
+            writeln!(
+                out,
+                "tokencount_extension_files{{ext=\"{}\"}} {}",
+                prometheus_label(ext),
+                stat.files
+            )?;
         }
     }
+    Ok(())
+}
 
-    if with_summary {
-        match serde_json::to_string(&serde_json::json!({ "summary": summary })) {
-            Ok(json) => println!("{}", json),
-            Err(err) => eprintln!("failed to serialize ndjson summary: {err}"),
+/// Escapes a label value per the Prometheus text exposition format.
+fn prometheus_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+const HTML_SORT_SCRIPT: &str = r#"
+document.querySelectorAll("th[data-sort]").forEach(function (th) {
+  th.addEventListener("click", function () {
+    var table = th.closest("table");
+    var body = table.tBodies[0];
+    var index = th.cellIndex;
+    var numeric = th.dataset.sort === "number";
+    var ascending = th.dataset.dir !== "asc";
+    th.dataset.dir = ascending ? "asc" : "desc";
+    Array.from(body.rows)
+      .sort(function (a, b) {
+        var x = a.cells[index].textContent;
+        var y = b.cells[index].textContent;
+        var order = numeric ? Number(x) - Number(y) : x.localeCompare(y);
+        return ascending ? order : -order;
+      })
+      .forEach(function (row) { body.appendChild(row); });
+  });
+});
+"#;
+
+fn print_html(out: &mut impl Write, stats: &[FileStat], summary: &Summary) -> io::Result<()> {
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html lang=\"en\">")?;
+    writeln!(out, "<head>")?;
+    writeln!(out, "<meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>tokencount report</title>")?;
+    writeln!(
+        out,
+        "<style>body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse}}\
+         th,td{{padding:4px 12px;border-bottom:1px solid #ddd}}td.tokens{{text-align:right}}\
+         th[data-sort]{{cursor:pointer}}</style>"
+    )?;
+    writeln!(out, "</head>")?;
+    writeln!(out, "<body>")?;
+    writeln!(out, "<h1>tokencount report</h1>")?;
+    writeln!(out, "<dl id=\"summary\">")?;
+    writeln!(out, "<dt>Total files</dt><dd>{}</dd>", summary.files)?;
+    writeln!(out, "<dt>Total tokens</dt><dd>{}</dd>", summary.total)?;
+    writeln!(out, "<dt>Average/file</dt><dd>{:.2}</dd>", summary.average)?;
+    for (percent, value) in reported_percentiles(summary) {
+        let label = Percentiles::label(percent);
+        let value = format_percentile(value, summary);
+        writeln!(out, "<dt>{label}</dt><dd>{value}</dd>")?;
+    }
+    writeln!(out, "</dl>")?;
+    writeln!(out, "<table id=\"files\">")?;
+    writeln!(out, "<thead><tr><th data-sort=\"text\">Path</th><th data-sort=\"number\">Tokens</th></tr></thead>")?;
+    writeln!(out, "<tbody>")?;
+    for stat in stats {
+        writeln!(
+            out,
+            "<tr><td>{}</td><td class=\"tokens\">{}</td></tr>",
+            html_escape(&stat.path),
+            stat.tokens
+        )?;
+    }
+    writeln!(out, "</tbody>")?;
+    writeln!(out, "</table>")?;
+    writeln!(out, "<script>{HTML_SORT_SCRIPT}</script>")?;
+    writeln!(out, "</body>")?;
+    writeln!(out, "</html>")?;
+    Ok(())
+}
+
+fn html_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+fn print_junit(
+    out: &mut impl Write,
+    stats: &[FileStat],
+    summary: &Summary,
+    max_file_tokens: Option<u64>,
+) -> io::Result<()> {
+    let failures = max_file_tokens
+        .map(|limit| stats.iter().filter(|stat| stat.tokens > limit).count())
+        .unwrap_or(0);
+
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        out,
+        "<testsuites name=\"tokencount\" tests=\"{}\" failures=\"{}\">",
+        stats.len(),
+        failures
+    )?;
+    writeln!(
+        out,
+        "  <testsuite name=\"tokencount\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"0\">",
+        stats.len(),
+        failures
+    )?;
+    writeln!(out, "    <properties>")?;
+    writeln!(
+        out,
+        "      <property name=\"files\" value=\"{}\"/>",
+        summary.files
+    )?;
+    writeln!(
+        out,
+        "      <property name=\"total\" value=\"{}\"/>",
+        summary.total
+    )?;
+    writeln!(
+        out,
+        "      <property name=\"average\" value=\"{:.2}\"/>",
+        summary.average
+    )?;
+    for (percent, value) in reported_percentiles(summary) {
+        writeln!(
+            out,
+            "      <property name=\"{}\" value=\"{}\"/>",
+            Percentiles::label(percent),
+            format_percentile(value, summary)
+        )?;
+    }
+    if let Some(limit) = max_file_tokens {
+        writeln!(
+            out,
+            "      <property name=\"max_file_tokens\" value=\"{limit}\"/>"
+        )?;
+    }
+    writeln!(out, "    </properties>")?;
+    for stat in stats {
+        writeln!(
+            out,
+            "    <testcase name=\"{}\" classname=\"tokencount\">",
+            xml_escape(&stat.path)
+        )?;
+        writeln!(out, "      <properties>")?;
+        writeln!(
+            out,
+            "        <property name=\"tokens\" value=\"{}\"/>",
+            stat.tokens
+        )?;
+        writeln!(out, "      </properties>")?;
+        if let Some(limit) = max_file_tokens.filter(|limit| stat.tokens > *limit) {
+            writeln!(
+                out,
+                "      <failure message=\"{} tokens exceeds limit {}\" type=\"TokenBudget\"/>",
+                stat.tokens, limit
+            )?;
         }
+        writeln!(out, "    </testcase>")?;
     }
+    writeln!(out, "  </testsuite>")?;
+    writeln!(out, "</testsuites>")?;
+    Ok(())
+}
+
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' | '\r' | '\t' => escaped.push_str(&format!("&#{};", ch as u32)),
+            ch if ch.is_control() => escaped.push('\u{FFFD}'),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// The percentiles summary trailers list: the --percentiles list, or else
+/// p50, p90 and p99.
+fn reported_percentiles(summary: &Summary) -> Vec<(f64, f64)> {
+    match &summary.percentiles {
+        Some(Percentiles(points)) => points.clone(),
+        None => vec![
+            (50.0, summary.p50),
+            (90.0, summary.p90),
+            (99.0, summary.p99),
+        ],
+    }
+}
+
+/// Formats one of the [`reported_percentiles`] so the list reads alike: whole
+/// counts when every value is whole, otherwise two decimals throughout.
+fn format_percentile(value: f64, summary: &Summary) -> String {
+    let whole = reported_percentiles(summary)
+        .iter()
+        .all(|(_, value)| value.fract() == 0.0);
+    if whole {
+        format!("{value}")
+    } else {
+        format!("{value:.2}")
+    }
+}
+
+/// Formats a count with a k/M/G/T suffix and one decimal, rounding half-up.
+fn humanize(value: u64) -> String {
+    const UNITS: [(u128, &str); 4] = [
+        (1_000, "k"),
+        (1_000_000, "M"),
+        (1_000_000_000, "G"),
+        (1_000_000_000_000, "T"),
+    ];
+    if value < 1_000 {
+        return value.to_string();
+    }
+    let mut rendered = String::new();
+    for (scale, suffix) in UNITS {
+        let tenths = (value as u128 * 10 + scale / 2) / scale;
+        rendered = format!("{}.{}{}", tenths / 10, tenths % 10, suffix);
+        if tenths < 10_000 {
+            break;
+        }
+    }
+    rendered
 }
 
 fn num_digits(mut value: u64) -> usize {
@@ -542,23 +4203,77 @@ fn num_digits(mut value: u64) -> usize {
     count
 }
 
-fn normalize_display_path(path: &Path) -> String {
-    if let Ok(cwd) = std::env::current_dir() {
-        if let Ok(stripped) = path.strip_prefix(&cwd) {
-            let display = stripped.to_string_lossy();
-            return if display.is_empty() {
-                String::from(".")
-            } else {
-                display.into_owned()
-            };
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn humanize_boundaries() {
+        assert_eq!(humanize(0), "0");
+        assert_eq!(humanize(999), "999");
+        assert_eq!(humanize(1_000), "1.0k");
+        assert_eq!(humanize(1_049), "1.0k");
+        assert_eq!(humanize(1_050), "1.1k");
+        assert_eq!(humanize(999_949), "999.9k");
+        assert_eq!(humanize(999_950), "1.0M");
+        assert_eq!(humanize(3_400_000), "3.4M");
+        assert_eq!(humanize(u64::MAX), "18446744.1T");
     }
-    if let Ok(stripped) = path.strip_prefix(Path::new(".")) {
-        let display = stripped.to_string_lossy();
-        if display.is_empty() {
-            return String::from(".");
-        }
-        return display.into_owned();
+
+    fn sample() -> (Vec<FileStat>, Summary) {
+        let stats = vec![
+            FileStat::new(String::from("src/A.elm"), 7),
+            FileStat::new(String::from("src/Big.elm"), 1234),
+        ];
+        let summary = build_summary(&stats, None);
+        (stats, summary)
+    }
+
+    #[test]
+    fn table_bytes() {
+        let (stats, summary) = sample();
+        let mut out = Vec::new();
+        print_table(&mut out, &stats, &summary, &TableOptions::default()).unwrap();
+        let expected = "   7  src/A.elm\n\
+                        1234  src/Big.elm\n\
+                        \n\
+                        ---\n\
+                        total files: 2\n\
+                        total tokens: 1241\n\
+                        average/file: 620.50\n\
+                        p50: 7\n\
+                        p90: 1234\n\
+                        p99: 1234\n";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn json2_bytes() {
+        let (stats, summary) = sample();
+        let mut out = Vec::new();
+        print_json2(&mut out, &stats, &summary).unwrap();
+        let expected = r#"{
+  "version": 2,
+  "files": [
+    {
+      "path": "src/A.elm",
+      "tokens": 7
+    },
+    {
+      "path": "src/Big.elm",
+      "tokens": 1234
+    }
+  ],
+  "summary": {
+    "files": 2,
+    "total": 1241,
+    "average": 620.5,
+    "p50": 7,
+    "p90": 1234,
+    "p99": 1234
+  }
+}
+"#;
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
     }
-    path.to_string_lossy().into_owned()
 }
@@ -0,0 +1,65 @@
+//! Loading custom tiktoken-format vocabularies behind `--encoding-file`.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use rustc_hash::FxHashMap;
+use tiktoken_rs::CoreBPE;
+
+/// Pre-tokenization split pattern used for custom vocabularies (the one cl100k_base uses).
+const SPLIT_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// Reads a `.tiktoken` file (`<base64 token> <rank>` per line) into a BPE.
+pub fn load_vocab(path: &Path, special_tokens: &[(String, usize)]) -> Result<CoreBPE> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read vocabulary {}", path.display()))?;
+    let encoder =
+        parse_vocab(&contents).with_context(|| format!("invalid vocabulary {}", path.display()))?;
+
+    let mut specials = FxHashMap::default();
+    for (name, id) in special_tokens {
+        if encoder.values().any(|rank| rank == id) {
+            bail!("special token {name} reuses rank {id} from the vocabulary");
+        }
+        if specials.insert(name.clone(), *id).is_some() {
+            bail!("special token {name} is given more than once");
+        }
+    }
+
+    CoreBPE::new(encoder, specials, SPLIT_PATTERN)
+}
+
+fn parse_vocab(contents: &str) -> Result<FxHashMap<Vec<u8>, usize>> {
+    let mut encoder = FxHashMap::default();
+    let mut ranks = FxHashMap::default();
+    for (index, line) in contents.lines().enumerate() {
+        let line_no = index + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(raw), Some(rank), None) = (parts.next(), parts.next(), parts.next()) else {
+            bail!("line {line_no}: expected `<base64 token> <rank>`");
+        };
+        let token = general_purpose::STANDARD
+            .decode(raw)
+            .with_context(|| format!("line {line_no}: token is not valid base64"))?;
+        let rank: usize = rank
+            .parse()
+            .with_context(|| format!("line {line_no}: rank `{rank}` is not a number"))?;
+        if let Some(previous) = ranks.insert(rank, line_no) {
+            bail!("line {line_no}: rank {rank} already used on line {previous}");
+        }
+        if encoder.insert(token, rank).is_some() {
+            bail!("line {line_no}: token `{raw}` is listed twice");
+        }
+    }
+
+    // BPE starts from single bytes, so a vocabulary missing one cannot encode every input.
+    if let Some(byte) = (0..=u8::MAX).find(|byte| !encoder.contains_key([*byte].as_slice())) {
+        bail!("no token for byte 0x{byte:02x}; every single byte needs a rank");
+    }
+    Ok(encoder)
+}
@@ -1,11 +1,13 @@
+use std::collections::BTreeMap;
 use std::fs;
+use std::path::Path;
 use std::process::Command;
 
 use anyhow::Result;
 use assert_cmd::prelude::*;
 use serde_json::Value;
 use tempfile::TempDir;
-use tiktoken_rs::cl100k_base;
+use tiktoken_rs::{cl100k_base, o200k_base, p50k_base, r50k_base};
 
 #[test]
 fn counts_tokens_for_known_input() -> Result<()> {
@@ -109,3 +111,3888 @@ fn json_summary_contains_stats() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn csv_output_round_trips() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "alpha beta gamma")?;
+    fs::write(dir.path().join("b,\"quoted\".elm"), "delta")?;
+    // Unquoted, this row would read as a comment like the summary lines.
+    fs::write(dir.path().join("#notes.elm"), "delta")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "csv", "--sort", "tokens"])
+        .output()?;
+    assert!(output.status.success(), "csv scan failed: {:?}", output);
+
+    let mut reader = csv::Reader::from_reader(output.stdout.as_slice());
+    assert_eq!(reader.headers()?, vec!["path", "tokens"]);
+
+    let bpe = cl100k_base()?;
+    let rows: Vec<(String, u64)> = reader.deserialize().collect::<Result<_, _>>()?;
+    assert_eq!(
+        rows,
+        vec![
+            (
+                "A.elm".to_string(),
+                bpe.encode_ordinary("alpha beta gamma").len() as u64
+            ),
+            (
+                "#notes.elm".to_string(),
+                bpe.encode_ordinary("delta").len() as u64
+            ),
+            (
+                "b,\"quoted\".elm".to_string(),
+                bpe.encode_ordinary("delta").len() as u64
+            ),
+        ]
+    );
+
+    let with_summary = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "csv", "--with-summary"])
+        .output()?;
+    assert!(with_summary.status.success());
+    let stdout = String::from_utf8(with_summary.stdout.clone())?;
+    assert!(stdout.lines().any(|line| line.starts_with("# total: ")));
+
+    let mut reader = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .from_reader(with_summary.stdout.as_slice());
+    assert_eq!(reader.records().count(), 3, "summary rows are comments");
+
+    Ok(())
+}
+
+#[test]
+fn r50k_encoding_matches_tiktoken() -> Result<()> {
+    let dir = TempDir::new()?;
+    let text = "fn main() {\n    println!(\"hello, legacy models\");\n}\n";
+    fs::write(dir.path().join("Legacy.elm"), text)?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json", "--encoding", "r50k_base"])
+        .output()?;
+    assert!(output.status.success(), "r50k scan failed: {:?}", output);
+
+    let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+    let tokens = rows
+        .iter()
+        .find_map(|row| row.get("tokens").and_then(Value::as_u64))
+        .expect("expected tokens field");
+
+    let bpe = r50k_base()?;
+    assert_eq!(tokens, bpe.encode_ordinary(text).len() as u64);
+
+    Ok(())
+}
+
+#[test]
+fn model_names_resolve_to_encodings() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(
+        dir.path().join("Prompt.elm"),
+        "Résumé: naïve café ✨ tokens 12345",
+    )?;
+
+    let count = |encoding: &str| -> Result<u64> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "json", "--encoding", encoding])
+            .output()?;
+        assert!(output.status.success(), "scan failed: {:?}", output);
+        let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+        Ok(rows
+            .iter()
+            .find_map(|row| row.get("tokens").and_then(Value::as_u64))
+            .expect("expected tokens field"))
+    };
+
+    assert_eq!(count("gpt-4o")?, count("o200k_base")?);
+    assert_eq!(count("gpt-4")?, count("cl100k_base")?);
+    assert_eq!(count("gpt-3.5-turbo")?, count("cl100k_base")?);
+
+    let unknown = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--encoding", "not-a-model"])
+        .output()?;
+    assert!(!unknown.status.success());
+    let stderr = String::from_utf8(unknown.stderr)?;
+    assert!(stderr.contains("gpt-4o"), "expected model list: {stderr}");
+
+    Ok(())
+}
+
+#[test]
+fn markdown_output_renders_table() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "alpha beta")?;
+    fs::write(dir.path().join("B.elm"), "gamma")?;
+    fs::write(dir.path().join("odd|name.elm"), "delta epsilon zeta")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "markdown", "--top", "2"])
+        .output()?;
+    assert!(
+        output.status.success(),
+        "markdown scan failed: {:?}",
+        output
+    );
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let table: Vec<&str> = stdout
+        .lines()
+        .take_while(|line| line.starts_with('|'))
+        .collect();
+    assert_eq!(table[0], "| Path | Tokens |");
+    assert_eq!(table[1], "| --- | ---: |");
+    assert_eq!(table.len(), 4, "header, separator and two rows");
+    for row in &table[2..] {
+        let unescaped_pipes = row.replace("\\|", "").matches('|').count();
+        assert_eq!(unescaped_pipes, 3, "row should have two cells: {row}");
+    }
+    assert!(stdout.contains("| odd\\|name.elm |"));
+    assert!(stdout.contains("**Total files:** 3"));
+    assert!(stdout.contains("**Top files**"));
+
+    Ok(())
+}
+
+#[test]
+fn markdown_fenced_style_puts_tokens_first() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), " word".repeat(3))?;
+    fs::write(dir.path().join("odd|name.elm"), " word".repeat(5))?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "markdown", "--markdown-style", "fenced"])
+        .args(["--sort", "path"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "| tokens | path |\n\
+         | ---: | --- |\n\
+         | 3 | A.elm |\n\
+         | 5 | odd\\|name.elm |\n\
+         \n\
+         ```text\n\
+         files: 2\n\
+         total: 8\n\
+         average: 4.00\n\
+         p50: 3\n\
+         p90: 5\n\
+         p99: 5\n\
+         ```\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn stdin_counts_single_blob() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Ignored.elm"), "should not be scanned")?;
+    let text = "Summarize the following diff in two sentences.\n";
+
+    let output = assert_cmd::Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--stdin", "--format", "json"])
+        .write_stdin(text)
+        .output()?;
+    assert!(output.status.success(), "stdin scan failed: {:?}", output);
+
+    let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+    let paths: Vec<&str> = rows
+        .iter()
+        .filter_map(|row| row.get("path").and_then(Value::as_str))
+        .collect();
+    assert_eq!(paths, vec!["<stdin>"]);
+
+    let summary = rows
+        .last()
+        .and_then(|row| row.get("summary"))
+        .expect("summary row");
+    let expected = cl100k_base()?.encode_ordinary(text).len() as u64;
+    assert_eq!(summary.get("files").and_then(Value::as_u64), Some(1));
+    assert_eq!(summary.get("total").and_then(Value::as_u64), Some(expected));
+
+    Ok(())
+}
+
+#[test]
+fn text_arguments_are_counted_inline() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Ignored.elm"), "should not be scanned")?;
+    let first = "hello world";
+    let second = "the quick brown fox jumps over the lazy dog";
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([
+            "--format",
+            "json",
+            "--encoding",
+            "cl100k_base",
+            "--text",
+            first,
+            "--text",
+            second,
+        ])
+        .output()?;
+    assert!(output.status.success(), "text scan failed: {:?}", output);
+
+    let bpe = cl100k_base()?;
+    let first_tokens = bpe.encode_ordinary(first).len() as u64;
+    let second_tokens = bpe.encode_ordinary(second).len() as u64;
+
+    let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+    let files: Vec<(&str, u64)> = rows
+        .iter()
+        .filter_map(|row| Some((row.get("path")?.as_str()?, row.get("tokens")?.as_u64()?)))
+        .collect();
+    assert_eq!(
+        files,
+        vec![("<text:0>", first_tokens), ("<text:1>", second_tokens)]
+    );
+
+    let summary = rows
+        .last()
+        .and_then(|row| row.get("summary"))
+        .expect("summary row");
+    assert_eq!(summary.get("files").and_then(Value::as_u64), Some(2));
+    assert_eq!(
+        summary.get("total").and_then(Value::as_u64),
+        Some(first_tokens + second_tokens)
+    );
+    assert_eq!(
+        summary.get("p50").and_then(Value::as_u64),
+        Some(first_tokens.min(second_tokens))
+    );
+    assert_eq!(
+        summary.get("p99").and_then(Value::as_u64),
+        Some(first_tokens.max(second_tokens))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn html_report_escapes_paths() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("a&b.elm"), "alpha beta")?;
+    fs::write(dir.path().join("<script>.elm"), "gamma")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "html"])
+        .output()?;
+    assert!(output.status.success(), "html scan failed: {:?}", output);
+
+    let html = String::from_utf8(output.stdout)?;
+    assert!(html.contains("a&amp;b.elm"));
+    assert!(html.contains("&lt;script&gt;.elm"));
+
+    let document = scraper::Html::parse_document(&html);
+    assert!(
+        document.errors.is_empty(),
+        "parse errors: {:?}",
+        document.errors
+    );
+
+    let cell = scraper::Selector::parse("#files tbody td:first-child").unwrap();
+    let mut paths: Vec<String> = document
+        .select(&cell)
+        .map(|td| td.text().collect())
+        .collect();
+    paths.sort();
+    assert_eq!(paths, vec!["<script>.elm", "a&b.elm"]);
+
+    let bpe = cl100k_base()?;
+    let total =
+        (bpe.encode_ordinary("alpha beta").len() + bpe.encode_ordinary("gamma").len()) as u64;
+    let summary = scraper::Selector::parse("#summary dd").unwrap();
+    let values: Vec<String> = document
+        .select(&summary)
+        .map(|dd| dd.text().collect())
+        .collect();
+    assert_eq!(values[0], "2");
+    assert_eq!(values[1], total.to_string());
+
+    Ok(())
+}
+
+#[test]
+fn junit_output_reports_budget_failures() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Small.elm"), "tiny")?;
+    fs::write(
+        dir.path().join("Big & <Loud>.elm"),
+        "one two three four five six seven eight nine ten",
+    )?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "junit", "--max-file-tokens", "5"])
+        .output()?;
+    assert!(output.status.success(), "junit scan failed: {:?}", output);
+
+    let xml = String::from_utf8(output.stdout)?;
+    let document = roxmltree::Document::parse(&xml)?;
+    let suite = document
+        .descendants()
+        .find(|node| node.has_tag_name("testsuite"))
+        .expect("testsuite element");
+    assert_eq!(suite.attribute("tests"), Some("2"));
+    assert_eq!(suite.attribute("failures"), Some("1"));
+
+    let total = suite
+        .descendants()
+        .find(|node| node.has_tag_name("property") && node.attribute("name") == Some("total"))
+        .and_then(|node| node.attribute("value"))
+        .expect("total property");
+    let bpe = cl100k_base()?;
+    let big = bpe
+        .encode_ordinary("one two three four five six seven eight nine ten")
+        .len();
+    let expected = big + bpe.encode_ordinary("tiny").len();
+    assert_eq!(total, expected.to_string());
+
+    let failing: Vec<&str> = suite
+        .children()
+        .filter(|node| node.has_tag_name("testcase"))
+        .filter(|case| case.children().any(|child| child.has_tag_name("failure")))
+        .filter_map(|case| case.attribute("name"))
+        .collect();
+    assert_eq!(failing, vec!["Big & <Loud>.elm"]);
+
+    let message = suite
+        .descendants()
+        .find(|node| node.has_tag_name("failure"))
+        .and_then(|node| node.attribute("message"))
+        .expect("failure message");
+    assert_eq!(message, format!("{big} tokens exceeds limit 5"));
+
+    Ok(())
+}
+
+#[test]
+fn github_format_annotates_and_writes_step_summary() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Small.elm"), "tiny")?;
+    fs::write(
+        dir.path().join("Big.elm"),
+        "one two three four five six seven eight nine ten",
+    )?;
+    let summary_dir = TempDir::new()?;
+    let summary_path = summary_dir.path().join("step_summary.md");
+    fs::write(&summary_path, "previous step\n")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .env("GITHUB_STEP_SUMMARY", &summary_path)
+        .args(["--format", "github", "--max-file-tokens", "5"])
+        .output()?;
+    assert!(output.status.success(), "github scan failed: {:?}", output);
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let annotations: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.starts_with("::warning"))
+        .collect();
+    assert_eq!(annotations.len(), 1, "stdout: {stdout}");
+    assert!(annotations[0].starts_with("::warning file=Big.elm::"));
+    assert!(stdout.contains("total files: 2"));
+
+    let step_summary = fs::read_to_string(&summary_path)?;
+    assert!(step_summary.starts_with("previous step\n"));
+    assert!(step_summary.contains("| Path | Tokens |"));
+    assert!(step_summary.contains("| Big.elm |"));
+    assert!(step_summary.contains("**Total files:** 2"));
+
+    Ok(())
+}
+
+#[test]
+fn by_ext_breaks_down_summary() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "alpha beta")?;
+    fs::write(dir.path().join("B.elm"), "gamma")?;
+    fs::write(dir.path().join("C.ts"), "delta epsilon zeta")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([
+            "--format",
+            "json",
+            "--include-ext",
+            "elm",
+            "--include-ext",
+            "ts",
+            "--by-ext",
+        ])
+        .output()?;
+    assert!(output.status.success(), "by-ext scan failed: {:?}", output);
+
+    let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+    let breakdown = rows
+        .last()
+        .and_then(|row| row.pointer("/summary/by_extension"))
+        .expect("by_extension map");
+
+    let bpe = cl100k_base()?;
+    let count = |text: &str| bpe.encode_ordinary(text).len() as u64;
+    assert_eq!(breakdown.pointer("/elm/files"), Some(&Value::from(2)));
+    assert_eq!(
+        breakdown.pointer("/elm/tokens"),
+        Some(&Value::from(count("alpha beta") + count("gamma")))
+    );
+    assert_eq!(breakdown.pointer("/ts/files"), Some(&Value::from(1)));
+
+    let default_output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json"])
+        .output()?;
+    let rows: Vec<Value> = serde_json::from_slice(&default_output.stdout)?;
+    assert!(rows
+        .last()
+        .and_then(|row| row.pointer("/summary/by_extension"))
+        .is_none());
+
+    Ok(())
+}
+
+#[test]
+fn prometheus_output_is_valid_exposition() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "alpha beta")?;
+    fs::write(dir.path().join("we\"ird\\name.elm"), "gamma")?;
+    fs::write(dir.path().join("C.elm"), "delta epsilon zeta eta")?;
+    fs::write(dir.path().join("notes.md"), "theta")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "prometheus", "--include-ext", "elm,md"])
+        .output()?;
+    assert!(
+        output.status.success(),
+        "prometheus scan failed: {:?}",
+        output
+    );
+    let stdout = String::from_utf8(output.stdout)?;
+
+    let samples: Vec<(String, f64)> = stdout
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(parse_prometheus_sample)
+        .collect();
+    assert!(samples
+        .iter()
+        .any(|(series, _)| series == "tokencount_file_tokens{path=\"we\\\"ird\\\\name.elm\"}"));
+    assert!(samples
+        .iter()
+        .any(|(series, value)| series == "tokencount_files_total" && *value == 4.0));
+    let total = samples
+        .iter()
+        .find(|(series, _)| series == "tokencount_total_tokens")
+        .map(|(_, value)| *value)
+        .expect("total tokens sample");
+    let ext_tokens: f64 = samples
+        .iter()
+        .filter(|(series, _)| series.starts_with("tokencount_extension_tokens{ext="))
+        .map(|(_, value)| value)
+        .sum();
+    assert_eq!(ext_tokens, total);
+    assert!(samples.iter().any(|(series, value)| series
+        == "tokencount_extension_files{ext=\"elm\"}"
+        && *value == 3.0));
+    assert!(samples
+        .iter()
+        .any(|(series, _)| series == "tokencount_p90_tokens"));
+
+    let bounded = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([
+            "--format",
+            "prometheus",
+            "--top",
+            "1",
+            "--include-ext",
+            "elm,md",
+        ])
+        .output()?;
+    let stdout = String::from_utf8(bounded.stdout)?;
+    let file_series: Vec<&str> = stdout
+        .lines()
+        .filter(|line| line.starts_with("tokencount_file_tokens{"))
+        .collect();
+    assert_eq!(file_series.len(), 1);
+    assert!(file_series[0].starts_with("tokencount_file_tokens{path=\"C.elm\"}"));
+
+    Ok(())
+}
+
+/// Splits a sample line into its series and value, validating the metric
+/// name and label syntax along the way.
+fn parse_prometheus_sample(line: &str) -> (String, f64) {
+    let (series, value) = line.rsplit_once(' ').expect("sample has a value");
+    let name_end = series.find('{').unwrap_or(series.len());
+    let name = &series[..name_end];
+    assert!(
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':'),
+        "invalid metric name in {line:?}"
+    );
+    if name_end < series.len() {
+        let labels = series[name_end + 1..]
+            .strip_suffix('}')
+            .expect("closing brace");
+        let (label, quoted) = labels.split_once('=').expect("label pair");
+        assert!(label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        let inner = quoted
+            .strip_prefix('"')
+            .and_then(|q| q.strip_suffix('"'))
+            .expect("quoted label value");
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => assert!(matches!(chars.next(), Some('\\' | '"' | 'n'))),
+                '"' | '\n' => panic!("unescaped character in {line:?}"),
+                _ => {}
+            }
+        }
+    }
+    (series.to_string(), value.parse().expect("numeric value"))
+}
+
+#[test]
+fn group_by_dir_totals_directories() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir_all(dir.path().join("src/nested"))?;
+    fs::create_dir_all(dir.path().join("tests"))?;
+    fs::write(dir.path().join("Root.elm"), "root")?;
+    fs::write(dir.path().join("src/A.elm"), "alpha beta")?;
+    fs::write(
+        dir.path().join("src/nested/B.elm"),
+        "gamma delta epsilon zeta",
+    )?;
+    fs::write(dir.path().join("tests/C.elm"), "eta")?;
+
+    let by_directory = |flag: &str| -> Result<Vec<(String, u64, u64)>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "json", flag])
+            .output()?;
+        assert!(output.status.success(), "group scan failed: {:?}", output);
+        let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+        let dirs = rows
+            .last()
+            .and_then(|row| row.pointer("/summary/by_directory"))
+            .and_then(Value::as_array)
+            .expect("by_directory array");
+        Ok(dirs
+            .iter()
+            .map(|d| {
+                (
+                    d["path"].as_str().unwrap().to_string(),
+                    d["files"].as_u64().unwrap(),
+                    d["tokens"].as_u64().unwrap(),
+                )
+            })
+            .collect())
+    };
+
+    let bpe = cl100k_base()?;
+    let count = |text: &str| bpe.encode_ordinary(text).len() as u64;
+
+    let top_level = by_directory("--group-by-dir")?;
+    assert_eq!(top_level[0].0, "src");
+    assert_eq!(top_level[0].1, 2);
+    assert_eq!(
+        top_level[0].2,
+        count("alpha beta") + count("gamma delta epsilon zeta")
+    );
+    assert!(top_level
+        .iter()
+        .any(|(path, files, _)| path == "." && *files == 1));
+    assert!(top_level.iter().any(|(path, _, _)| path == "tests"));
+
+    let nested = by_directory("--group-by-dir=2")?;
+    assert!(nested
+        .iter()
+        .any(|(path, files, _)| path == "src/nested" && *files == 1));
+    assert!(nested
+        .iter()
+        .any(|(path, files, _)| path == "src" && *files == 1));
+
+    Ok(())
+}
+
+#[test]
+fn output_flag_writes_report_file() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "alpha beta")?;
+    let out_dir = TempDir::new()?;
+    let report = out_dir.path().join("report.json");
+    fs::write(&report, "x".repeat(64 * 1024))?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json", "--also-table", "--output"])
+        .arg(&report)
+        .output()?;
+    assert!(output.status.success(), "output scan failed: {:?}", output);
+
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(
+        stdout.contains("total files: 1"),
+        "table on stdout: {stdout}"
+    );
+
+    let rows: Vec<Value> = serde_json::from_slice(&fs::read(&report)?)?;
+    assert_eq!(rows[0].get("path").and_then(Value::as_str), Some("A.elm"));
+    let leftovers: Vec<_> = fs::read_dir(out_dir.path())?.collect();
+    assert_eq!(leftovers.len(), 1, "temporary files left behind");
+
+    let nested = out_dir.path().join("reports/2024/report.json");
+    let created = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json", "-o"])
+        .arg(&nested)
+        .output()?;
+    assert!(created.status.success(), "{created:?}");
+    let rows: Vec<Value> = serde_json::from_slice(&fs::read(&nested)?)?;
+    assert_eq!(rows[0].get("path").and_then(Value::as_str), Some("A.elm"));
+
+    // A parent that is a regular file cannot be created as a directory.
+    let blocked = report.join("report.json");
+    let failed = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .arg("--output")
+        .arg(&blocked)
+        .output()?;
+    assert!(!failed.status.success());
+    let stderr = String::from_utf8(failed.stderr)?;
+    assert!(stderr.contains(&blocked.display().to_string()), "{stderr}");
+
+    let bad_glob = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--exclude", "[", "--output"])
+        .arg(&report)
+        .output()?;
+    assert!(!bad_glob.status.success());
+    let rows: Vec<Value> = serde_json::from_slice(&fs::read(&report)?)?;
+    assert_eq!(rows.len(), 2, "previous report must stay intact");
+
+    Ok(())
+}
+
+#[test]
+fn price_per_1k_adds_cost_to_summary() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(
+        dir.path().join("A.elm"),
+        "module Main exposing (main)\n\nmain = text \"hi\"\n",
+    )?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json", "--price-per-1k", "0.5"])
+        .output()?;
+    assert!(output.status.success(), "cost scan failed: {:?}", output);
+
+    let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+    let summary = rows.last().and_then(|row| row.get("summary")).unwrap();
+    let total = summary["total"].as_u64().unwrap();
+    let cost = summary["cost"].as_f64().expect("cost field");
+    assert!((cost - total as f64 / 1000.0 * 0.5).abs() < 1e-9);
+
+    let without = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json"])
+        .output()?;
+    let rows: Vec<Value> = serde_json::from_slice(&without.stdout)?;
+    assert!(rows.last().unwrap()["summary"].get("cost").is_none());
+
+    Ok(())
+}
+
+#[test]
+fn print0_emits_nul_terminated_paths() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("with space.elm"), "alpha beta gamma delta")?;
+    fs::write(dir.path().join("with\nnewline.elm"), "alpha beta")?;
+    fs::write(dir.path().join("Plain.elm"), "alpha")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["-0", "--sort", "tokens", "--top", "2"])
+        .output()?;
+    assert!(output.status.success(), "print0 scan failed: {:?}", output);
+
+    let records: Vec<&[u8]> = output.stdout.split(|byte| *byte == 0).collect();
+    assert_eq!(
+        records,
+        vec![&b"with space.elm"[..], &b"with\nnewline.elm"[..], &b""[..]]
+    );
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["-0", "--format", "tsv", "--min-tokens", "2"])
+        .output()?;
+    assert!(
+        output.status.success(),
+        "print0 tsv scan failed: {:?}",
+        output
+    );
+    let nuls = output.stdout.iter().filter(|byte| **byte == 0).count();
+    assert_eq!(nuls, 2, "one NUL per file: {:?}", output.stdout);
+    let records: Vec<&[u8]> = output.stdout.split(|byte| *byte == 0).collect();
+    assert_eq!(records[0], b"2\twith\nnewline.elm");
+    assert!(records[1].ends_with(b"\twith space.elm"));
+
+    Ok(())
+}
+
+#[test]
+fn color_flag_controls_ansi_codes() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Big.elm"), "one two three four five six")?;
+    fs::write(dir.path().join("Small.elm"), "one")?;
+
+    let never = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--color", "never"])
+        .output()?;
+    assert!(never.status.success());
+    assert!(!never.stdout.contains(&0x1b), "unexpected ANSI codes");
+
+    let auto = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .output()?;
+    assert!(!auto.stdout.contains(&0x1b), "piped output must stay plain");
+
+    let always = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .env("NO_COLOR", "1")
+        .args(["--color", "always"])
+        .output()?;
+    assert!(always.status.success());
+    let stdout = String::from_utf8(always.stdout)?;
+    assert!(stdout.contains("\x1b["), "expected ANSI codes: {stdout:?}");
+    assert!(
+        stdout.contains("\x1b[31m"),
+        "largest file should be red: {stdout:?}"
+    );
+    assert!(
+        stdout.contains("\x1b[32m"),
+        "smallest file should be green: {stdout:?}"
+    );
+    assert!(
+        stdout.contains("\x1b[1mtotal tokens:\x1b[0m"),
+        "labels should be bold: {stdout:?}"
+    );
+
+    let limited = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--color", "always", "--max-file-tokens", "10"])
+        .output()?;
+    let stdout = String::from_utf8(limited.stdout)?;
+    assert!(
+        !stdout.contains("\x1b[31m"),
+        "nothing exceeds the limit: {stdout:?}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn max_total_tokens_sets_exit_code() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "one two three four five")?;
+    fs::write(dir.path().join("B.elm"), "six seven")?;
+
+    let over = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json", "--top", "1", "--max-total-tokens", "3"])
+        .output()?;
+    assert_eq!(over.status.code(), Some(2), "expected budget failure");
+    let rows: Vec<Value> = serde_json::from_slice(&over.stdout)?;
+    assert!(
+        rows.last().unwrap().get("summary").is_some(),
+        "report still printed"
+    );
+    let stderr = String::from_utf8(over.stderr)?;
+    assert!(stderr.contains("exceed budget of 3"), "{stderr}");
+
+    let under = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--max-total-tokens", "1000"])
+        .output()?;
+    assert_eq!(under.status.code(), Some(0));
+
+    Ok(())
+}
+
+#[test]
+fn max_file_tokens_marks_over_budget_files() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(
+        dir.path().join("Big.elm"),
+        "one two three four five six seven eight",
+    )?;
+    fs::write(dir.path().join("Small.elm"), "one")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json", "--max-file-tokens", "4"])
+        .output()?;
+    assert!(output.status.success(), "budget scan failed: {:?}", output);
+    let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+    let flags: Vec<(&str, bool)> = rows
+        .iter()
+        .filter_map(|row| Some((row["path"].as_str()?, row["over_budget"].as_bool()?)))
+        .collect();
+    assert_eq!(flags, vec![("Big.elm", true), ("Small.elm", false)]);
+
+    let table = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--max-file-tokens", "4"])
+        .output()?;
+    let stdout = String::from_utf8(table.stdout)?;
+    assert!(stdout.lines().any(|line| line.ends_with("Big.elm !")));
+    assert!(stdout.lines().any(|line| line.ends_with("Small.elm")));
+
+    let strict = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--max-file-tokens", "4", "--strict"])
+        .output()?;
+    assert_eq!(strict.status.code(), Some(2));
+
+    let plain = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json"])
+        .output()?;
+    let rows: Vec<Value> = serde_json::from_slice(&plain.stdout)?;
+    assert!(rows.iter().all(|row| row.get("over_budget").is_none()));
+
+    Ok(())
+}
+
+#[test]
+fn min_tokens_filters_rows_with_summary_scope() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "one")?;
+    fs::write(dir.path().join("B.elm"), "one two three four")?;
+    fs::write(
+        dir.path().join("C.elm"),
+        "one two three four five six seven eight",
+    )?;
+
+    let run = |extra: &[&str]| -> Result<(Vec<String>, Value)> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "json"])
+            .args(extra)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "min-tokens scan failed: {:?}",
+            output
+        );
+        let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+        let paths = rows
+            .iter()
+            .filter_map(|row| row.get("path").and_then(Value::as_str))
+            .map(String::from)
+            .collect();
+        let summary = rows.last().unwrap()["summary"].clone();
+        Ok((paths, summary))
+    };
+
+    let bpe = cl100k_base()?;
+    let count = |text: &str| bpe.encode_ordinary(text).len() as u64;
+    let (a, b, c) = (
+        count("one"),
+        count("one two three four"),
+        count("one two three four five six seven eight"),
+    );
+
+    let (paths, summary) = run(&["--min-tokens", "4", "--summary-scope", "all"])?;
+    assert_eq!(paths, vec!["B.elm", "C.elm"]);
+    assert_eq!(summary["files"], 3);
+    assert_eq!(summary["total"], a + b + c);
+    assert_eq!(summary["p50"], b);
+    assert_eq!(summary["hidden_files"], 1);
+
+    let (paths, summary) = run(&["--min-tokens", "4", "--summary-scope", "filtered"])?;
+    assert_eq!(paths, vec!["B.elm", "C.elm"]);
+    assert_eq!(summary["files"], 2);
+    assert_eq!(summary["total"], b + c);
+    assert_eq!(summary["p50"], b);
+    assert_eq!(summary["p90"], c);
+
+    let (paths, summary) = run(&["--min-tokens-strict", "4", "--top", "1"])?;
+    assert_eq!(paths, vec!["C.elm"]);
+    assert_eq!(summary["files"], 2);
+    assert_eq!(summary["total"], b + c);
+    assert_eq!(summary["hidden_files"], 1);
+
+    let (_, summary) = run(&["--min-tokens", "1"])?;
+    assert!(summary.get("hidden_files").is_none());
+
+    Ok(())
+}
+
+#[test]
+fn fields_select_and_order_columns() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), "module Main exposing (main)")?;
+    let tokens = cl100k_base()?
+        .encode_ordinary("module Main exposing (main)")
+        .len();
+
+    let run = |format: &str, fields: &str| -> Result<String> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", format, "--fields", fields, "--no-summary"])
+            .output()?;
+        assert!(output.status.success(), "fields run failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    let csv = run("csv", "tokens,path")?;
+    assert_eq!(csv, format!("tokens,path\n{tokens},Main.elm\n"));
+    let csv = run("csv", "path")?;
+    assert_eq!(csv, "path\nMain.elm\n");
+
+    let ndjson = run("ndjson", "tokens,path")?;
+    assert_eq!(
+        ndjson,
+        format!("{{\"tokens\":{tokens},\"path\":\"Main.elm\"}}\n")
+    );
+    let ndjson = run("ndjson", "path,tokens")?;
+    assert_eq!(
+        ndjson,
+        format!("{{\"path\":\"Main.elm\",\"tokens\":{tokens}}}\n")
+    );
+
+    let table = run("table", "tokens,path")?;
+    assert!(
+        table.starts_with(&format!("{tokens}  Main.elm\n")),
+        "{table}"
+    );
+
+    // Metric columns are measured without --extended.
+    let csv = run("csv", "bytes,path")?;
+    assert_eq!(csv, "bytes,path\n27,Main.elm\n");
+    let ndjson = run("ndjson", "lines,chars,path")?;
+    assert_eq!(ndjson, "{\"lines\":1,\"chars\":27,\"path\":\"Main.elm\"}\n");
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--fields", "path,size"])
+        .output()?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("size"), "stderr: {stderr}");
+    assert!(stderr.contains("tokens"), "stderr: {stderr}");
+    Ok(())
+}
+
+#[test]
+fn template_renders_rows_with_escapes() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), "module Main exposing (main)")?;
+    let tokens = cl100k_base()?
+        .encode_ordinary("module Main exposing (main)")
+        .len();
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--template", r"{{tokens}}\t{{path}}\n--"])
+        .output()?;
+    assert!(output.status.success(), "template run failed: {:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        format!("{tokens}\tMain.elm\n--\n")
+    );
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([
+            "--template",
+            "{{path}} ({{tokens}} tok)",
+            "--format",
+            "json",
+        ])
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        format!("Main.elm ({tokens} tok)\n")
+    );
+    Ok(())
+}
+
+#[test]
+fn template_rejects_unknown_placeholders() -> Result<()> {
+    let output = Command::cargo_bin("tokencount")?
+        .args(["--template", "{{nope}}", "does-not-exist"])
+        .output()?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        stderr.contains("unknown placeholder {{nope}}"),
+        "stderr: {stderr}"
+    );
+    Ok(())
+}
+
+#[test]
+fn library_counts_paths_like_the_cli() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), "module Main exposing (main)")?;
+    fs::write(dir.path().join("lib.RS"), "fn main() {}")?;
+    fs::write(dir.path().join("notes.txt"), "ignored")?;
+
+    let options = tokencount::CountOptions {
+        include_ext: vec![".elm".into(), "rs".into()],
+        ..tokencount::CountOptions::default()
+    };
+    let mut stats = tokencount::count_paths(&[dir.path().to_path_buf()], &options)?;
+    stats.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let bpe = cl100k_base()?;
+    let names: Vec<_> = stats
+        .iter()
+        .map(|stat| stat.path.rsplit('/').next().unwrap().to_string())
+        .collect();
+    assert_eq!(names, vec!["Main.elm", "lib.RS"]);
+    assert_eq!(
+        stats[0].tokens,
+        bpe.encode_ordinary("module Main exposing (main)").len() as u64
+    );
+
+    let summary = tokencount::build_summary(&stats, None);
+    assert_eq!(summary.files, 2);
+    assert_eq!(summary.total, stats[0].tokens + stats[1].tokens);
+    Ok(())
+}
+
+#[test]
+fn percentile_methods_on_four_values() -> Result<()> {
+    use tokencount::{percentile, PercentileMethod::*};
+
+    let sorted = [1, 2, 3, 4];
+    let at = |p, method| percentile(&sorted, p, method);
+    assert_eq!(at(0.50, Nearest), 2.0);
+    assert_eq!(at(0.90, Nearest), 4.0);
+    assert_eq!(at(0.99, Nearest), 4.0);
+    assert_eq!(at(0.50, Linear), 2.5);
+    assert!((at(0.90, Linear) - 3.7).abs() < 1e-9);
+    assert!((at(0.99, Linear) - 3.97).abs() < 1e-9);
+    assert_eq!(percentile(&[], 0.5, Linear), 0.0);
+    assert_eq!(percentile(&[7], 0.9, Linear), 7.0);
+
+    let dir = TempDir::new()?;
+    for (name, tokens) in [("A", 1), ("B", 2), ("C", 3), ("D", 4)] {
+        fs::write(
+            dir.path().join(format!("{name}.elm")),
+            " word".repeat(tokens),
+        )?;
+    }
+    let summary = |method: &str| -> Result<Value> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "json", "--percentile-method", method])
+            .output()?;
+        assert!(output.status.success(), "{:?}", output);
+        let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+        Ok(rows.last().unwrap()["summary"].clone())
+    };
+    // Nearest-rank values stay integers in the JSON.
+    let nearest = summary("nearest")?;
+    assert_eq!(nearest["p50"], serde_json::json!(2));
+    assert_eq!(nearest["p99"], serde_json::json!(4));
+    assert_eq!(summary("linear")?["p50"], serde_json::json!(2.5));
+    Ok(())
+}
+
+#[test]
+fn custom_percentiles_are_reported_in_order() -> Result<()> {
+    let dir = TempDir::new()?;
+    for tokens in 1..=20 {
+        fs::write(
+            dir.path().join(format!("F{tokens}.elm")),
+            " word".repeat(tokens),
+        )?;
+    }
+    let run = |args: &[&str]| {
+        Command::cargo_bin("tokencount")
+            .unwrap()
+            .current_dir(dir.path())
+            .args(args)
+            .output()
+    };
+
+    let output = run(&["--percentiles", "95,25", "--percentiles", "99.9"])?;
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.ends_with("p25: 5\np95: 19\np99.9: 20\n"), "{stdout}");
+    assert!(!stdout.contains("p50:"), "{stdout}");
+
+    let output = run(&["--format", "json2", "--percentiles", "25,95"])?;
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(
+        report["summary"]["percentiles"],
+        serde_json::json!({ "p25": 5, "p95": 19 })
+    );
+    assert_eq!(report["summary"]["p50"], 10);
+
+    let output = run(&["--format", "prometheus", "--percentiles", "75,99.9"])?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("tokencount_p75_tokens 15\n"), "{stdout}");
+    assert!(stdout.contains("tokencount_p99_9_tokens 20\n"), "{stdout}");
+    assert!(!stdout.contains("tokencount_p50_tokens"), "{stdout}");
+    let output = run(&["--format", "csv", "--with-summary", "--percentiles", "75"])?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.ends_with("# p75: 15\n"), "{stdout}");
+    for format in ["markdown", "html", "junit"] {
+        let output = run(&["--format", format, "--percentiles", "75"])?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.contains("p75"), "{format}: {stdout}");
+        assert!(!stdout.contains("p90"), "{format}: {stdout}");
+    }
+
+    // Interpolated values share two decimals, whole or not.
+    let output = run(&["--percentiles", "75,100", "--percentile-method", "linear"])?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.ends_with("p75: 15.25\np100: 20.00\n"), "{stdout}");
+
+    for bad in ["0", "100.5", "-5", "abc"] {
+        let output = run(&[&format!("--percentiles={bad}")])?;
+        assert_eq!(output.status.code(), Some(2), "{bad}");
+        assert!(String::from_utf8(output.stderr)?.contains("above 0 and up to 100"));
+    }
+    Ok(())
+}
+
+#[test]
+fn include_globs_select_nested_files() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir_all(dir.path().join("src/Page"))?;
+    fs::write(dir.path().join("src/Main.elm"), "main")?;
+    fs::write(dir.path().join("src/Page/Home.elm"), "home")?;
+    fs::write(dir.path().join("Top.elm"), "top")?;
+    fs::write(dir.path().join("src/util.ts"), "util")?;
+
+    let run = |args: &[&str]| -> Result<Vec<String>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(args)
+            .args(["--format", "paths"])
+            .output()?;
+        assert!(output.status.success(), "include run failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .map(String::from)
+            .collect())
+    };
+
+    let paths = run(&["--include", "src/**/*.elm"])?;
+    assert_eq!(paths, vec!["src/Main.elm", "src/Page/Home.elm"]);
+
+    let paths = run(&["--include", "src/**/*.elm", "--include-ext", "ts"])?;
+    assert_eq!(
+        paths,
+        vec!["src/Main.elm", "src/Page/Home.elm", "src/util.ts"]
+    );
+    Ok(())
+}
+
+#[test]
+fn summary_only_omits_rows_in_every_format() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "one")?;
+    fs::write(dir.path().join("B.elm"), "one two three four")?;
+
+    let run = |format: &str| -> Result<String> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--summary-only", "--top", "1", "--format", format])
+            .output()?;
+        assert!(output.status.success(), "summary-only failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    let table = run("table")?;
+    assert!(table.trim_start().starts_with("---\n"), "table: {table}");
+    assert!(table.contains("total files: 2"));
+    assert!(!table.contains("A.elm"), "table: {table}");
+
+    let json: Value = serde_json::from_str(&run("json")?)?;
+    let summary = &json["summary"];
+    assert_eq!(summary["files"], 2);
+    assert_eq!(summary["top"].as_array().unwrap().len(), 1);
+    assert_eq!(summary["top"][0]["path"], "B.elm");
+
+    let ndjson = run("ndjson")?;
+    let lines: Vec<&str> = ndjson.lines().collect();
+    assert_eq!(lines.len(), 1);
+    let line: Value = serde_json::from_str(lines[0])?;
+    assert_eq!(line["summary"]["files"], 2);
+
+    // No path rows anywhere, but the total is still there.
+    let total = 5; // "one" plus "one two three four"
+    for (format, total_line) in [
+        ("csv", format!("# total: {total}")),
+        ("tsv", format!("total\t{total}")),
+        ("markdown", format!("**Total tokens:** {total}")),
+        ("tree", format!("{total}  .")),
+        ("yaml", format!("total: {total}")),
+    ] {
+        let out = run(format)?;
+        assert!(!out.contains("A.elm"), "{format}: {out}");
+        assert!(out.contains(&total_line), "{format}: {out}");
+    }
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--summary-only", "--format", "paths"])
+        .output()?;
+    assert!(!output.status.success());
+    Ok(())
+}
+
+#[test]
+fn tokencountignore_skips_listed_paths() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir_all(dir.path().join("vendor"))?;
+    fs::write(dir.path().join("Main.elm"), "main")?;
+    fs::write(dir.path().join("vendor/Lib.elm"), "vendored")?;
+    fs::write(dir.path().join(".tokencountignore"), "vendor/\n")?;
+
+    for extra in [None, Some("--no-respect-gitignore")] {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "ignore run failed: {:?}", output);
+        assert_eq!(String::from_utf8(output.stdout)?, "Main.elm\n");
+    }
+    Ok(())
+}
+
+#[test]
+fn dot_ignore_files_exclude_directories() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir_all(dir.path().join("generated"))?;
+    fs::write(dir.path().join("Main.elm"), "main")?;
+    fs::write(dir.path().join("generated/Api.elm"), "generated")?;
+    fs::write(dir.path().join(".ignore"), "generated/\n")?;
+
+    let paths = |extra: &[&str]| -> Result<String> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "ignore run failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?)
+    };
+    // No git repository here, and .gitignore handling is a separate switch.
+    assert_eq!(paths(&[])?, "Main.elm\n");
+    assert_eq!(paths(&["--no-respect-gitignore"])?, "Main.elm\n");
+    assert_eq!(
+        paths(&["--no-respect-ignore"])?,
+        "Main.elm\ngenerated/Api.elm\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn total_prints_a_bare_integer() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), "module Main exposing (main)")?;
+    fs::write(dir.path().join("Big.elm"), "x".repeat(4096))?;
+    let tokens = cl100k_base()?
+        .encode_ordinary("module Main exposing (main)")
+        .len();
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--total", "--max-bytes", "1024"])
+        .output()?;
+    assert!(output.status.success(), "total failed: {:?}", output);
+    assert_eq!(String::from_utf8(output.stdout)?, format!("{tokens}\n"));
+
+    // --format is ignored, filters are not.
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json", "--total", "--exclude", "**/Big.elm"])
+        .output()?;
+    assert!(output.status.success(), "total failed: {:?}", output);
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), tokens.to_string());
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "total", "--include-ext", "rs"])
+        .output()?;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    Ok(())
+}
+
+#[test]
+fn lossy_counts_invalid_utf8_files() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Latin.elm"), b"\xFF\xFEhello")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json"])
+        .output()?;
+    assert!(output.status.success());
+    let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(rows.len(), 1, "file should be skipped without --lossy");
+    assert!(String::from_utf8(output.stderr)?.contains("--lossy"));
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json", "--lossy"])
+        .output()?;
+    assert!(output.status.success(), "lossy run failed: {:?}", output);
+    let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+    let expected = cl100k_base()?
+        .encode_ordinary("\u{FFFD}\u{FFFD}hello")
+        .len();
+    assert_eq!(rows[0]["path"], "Latin.elm");
+    assert_eq!(rows[0]["tokens"], expected);
+    assert_eq!(rows[0]["lossy"], true);
+    assert_eq!(rows[1]["summary"]["lossy_files"], 1);
+
+    // A count cached under --lossy must not resurrect the file without it.
+    let cache = dir.path().join("cache.json");
+    for lossy in [true, false] {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "json", "--cache"])
+            .arg(&cache)
+            .args(lossy.then_some("--lossy"))
+            .output()?;
+        let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(rows.len(), if lossy { 2 } else { 1 }, "lossy: {lossy}");
+    }
+    Ok(())
+}
+
+#[test]
+fn json_formats_keep_legacy_array_and_add_v2_object() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "one")?;
+    fs::write(dir.path().join("B.elm"), "one two three four")?;
+
+    let run = |format: &str| -> Result<Value> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", format])
+            .output()?;
+        assert!(output.status.success(), "{format} failed: {:?}", output);
+        Ok(serde_json::from_slice(&output.stdout)?)
+    };
+
+    let legacy = run("json")?;
+    let rows = legacy.as_array().expect("legacy json is an array");
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0]["path"], "A.elm");
+    assert_eq!(rows[2]["summary"]["files"], 2);
+
+    let v2 = run("json2")?;
+    assert_eq!(v2["version"], 2);
+    let files = v2["files"].as_array().expect("files array");
+    let paths: Vec<&str> = files.iter().filter_map(|f| f["path"].as_str()).collect();
+    assert_eq!(paths, vec!["A.elm", "B.elm"]);
+    assert_eq!(v2["summary"], rows[2]["summary"]);
+    Ok(())
+}
+
+#[test]
+fn binary_files_are_skipped_by_default() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), "main")?;
+    fs::write(dir.path().join("Blob.elm"), b"abc\0def\0\x01\x02")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "paths"])
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout)?, "Main.elm\n");
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        !stderr.contains("WARN"),
+        "binary skip should not warn: {stderr}"
+    );
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "paths", "--no-skip-binary"])
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout)?, "Blob.elm\nMain.elm\n");
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json2", "-v"])
+        .output()?;
+    assert!(output.status.success());
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["files"].as_array().unwrap().len(), 1);
+    assert_eq!(report["summary"]["skipped_binary"].as_u64(), Some(1));
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        !stderr.contains("Blob.elm"),
+        "only logged at debug: {stderr}"
+    );
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json2", "--no-binary-check"])
+        .output()?;
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["files"].as_array().unwrap().len(), 2);
+    assert!(report["summary"].get("skipped_binary").is_none());
+    Ok(())
+}
+
+#[test]
+fn depth_limits_recursion() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir_all(dir.path().join("sub"))?;
+    fs::write(dir.path().join("Top.elm"), "top")?;
+    fs::write(dir.path().join("sub/Deep.elm"), "deep")?;
+
+    let run = |depth: &str| -> Result<String> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths", "--depth", depth])
+            .output()?;
+        assert!(output.status.success(), "depth run failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    assert_eq!(run("1")?, "Top.elm\n");
+    assert_eq!(run("2")?, "Top.elm\nsub/Deep.elm\n");
+    Ok(())
+}
+
+#[test]
+fn histogram_buckets_are_inclusive_and_handle_empty_input() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "one")?;
+    fs::write(dir.path().join("B.elm"), "one two three four")?;
+    let bpe = cl100k_base()?;
+    let a = bpe.encode_ordinary("one").len();
+    let b = bpe.encode_ordinary("one two three four").len();
+
+    let histogram = |dir: &std::path::Path, flag: &str| -> Result<Value> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir)
+            .args(["--format", "json2", flag])
+            .output()?;
+        assert!(output.status.success(), "histogram failed: {:?}", output);
+        let json: Value = serde_json::from_slice(&output.stdout)?;
+        Ok(json["summary"]["histogram"].clone())
+    };
+
+    let buckets = histogram(dir.path(), &format!("--histogram={a},{b}"))?;
+    assert_eq!(
+        buckets,
+        serde_json::json!([
+            { "le": a, "range_start": 0, "range_end": a, "count": 1 },
+            { "le": b, "range_start": a + 1, "range_end": b, "count": 1 },
+            { "le": null, "range_start": b + 1, "range_end": null, "count": 0 },
+        ])
+    );
+
+    let buckets = histogram(dir.path(), &format!("--histogram={}", b - 1))?;
+    assert_eq!(buckets[0]["count"], 1);
+    assert_eq!(buckets[1]["count"], 1);
+
+    let empty = TempDir::new()?;
+    let buckets = histogram(empty.path(), "--histogram")?;
+    assert_eq!(
+        buckets,
+        serde_json::json!([
+            { "le": 10, "range_start": 0, "range_end": 10, "count": 0 },
+            { "le": null, "range_start": 11, "range_end": null, "count": 0 },
+        ])
+    );
+
+    // Equal-width buckets from 0 to the largest file, the last one open-ended.
+    let buckets = histogram(dir.path(), "--histogram-buckets=2")?;
+    let width = (b as u64).div_ceil(2);
+    assert_eq!(buckets.as_array().unwrap().len(), 2);
+    assert_eq!(buckets[0]["range_end"], width);
+    assert_eq!(buckets[1]["range_start"], width + 1);
+    assert_eq!(buckets[0]["count"], 1);
+    assert_eq!(buckets[1]["count"], 1);
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(empty.path())
+        .args(["--histogram=100,500"])
+        .output()?;
+    assert!(output.status.success());
+    let table = String::from_utf8(output.stdout)?;
+    assert!(table.contains("histogram:\n"), "table: {table}");
+    assert!(table.contains("<= 500  0\n"), "table: {table}");
+    Ok(())
+}
+
+#[test]
+fn hidden_flags_control_dotfiles() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), "main")?;
+    fs::write(dir.path().join(".secret.elm"), "secret")?;
+
+    let run = |flag: Option<&str>| -> Result<String> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths"])
+            .args(flag)
+            .output()?;
+        assert!(output.status.success(), "hidden run failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    assert_eq!(run(None)?, ".secret.elm\nMain.elm\n");
+    assert_eq!(run(Some("--hidden"))?, ".secret.elm\nMain.elm\n");
+    assert_eq!(run(Some("--no-hidden"))?, "Main.elm\n");
+    Ok(())
+}
+
+#[test]
+fn group_by_ext_emits_one_row_per_extension() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "one")?;
+    fs::write(dir.path().join("B.elm"), "one two three")?;
+    fs::write(
+        dir.path().join("c.ts"),
+        "one two three four five six seven eight nine ten",
+    )?;
+    let bpe = cl100k_base()?;
+    let count = |text: &str| bpe.encode_ordinary(text).len() as u64;
+    let (a, b) = (count("one"), count("one two three"));
+    let c = count("one two three four five six seven eight nine ten");
+
+    let run = |format: &str, sort: &str| -> Result<String> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--include-ext", "elm", "--include-ext", "ts"])
+            .args(["--group-by", "ext", "--format", format, "--sort", sort])
+            .output()?;
+        assert!(output.status.success(), "group-by failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    let rows: Vec<Value> = serde_json::from_str(&run("json", "path")?)?;
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0]["ext"], "elm");
+    assert_eq!(rows[0]["files"], 2);
+    assert_eq!(rows[0]["tokens"], a + b);
+    assert_eq!(rows[0]["max"], a.max(b));
+    assert_eq!(rows[0]["average"], (a + b) as f64 / 2.0);
+    assert_eq!(rows[1]["ext"], "ts");
+    assert_eq!(rows[2]["summary"]["files"], 3);
+
+    let ndjson = run("ndjson", "tokens")?;
+    let exts: Vec<String> = ndjson
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .filter_map(|row| row["ext"].as_str().map(String::from))
+        .collect();
+    assert!(c > a + b);
+    assert_eq!(exts, vec!["ts", "elm"]);
+
+    let table = run("table", "path")?;
+    let lines: Vec<&str> = table.lines().collect();
+    assert!(lines[0].starts_with("ext"), "table: {table}");
+    assert!(lines[1].starts_with("elm"), "table: {table}");
+    assert!(lines[2].starts_with("ts "), "table: {table}");
+    Ok(())
+}
+
+#[test]
+fn absolute_flag_emits_canonical_paths() -> Result<()> {
+    let dir = TempDir::new()?;
+    let file = dir.path().join("Main.elm");
+    fs::write(&file, "module Main exposing (main)")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--absolute", "--format", "ndjson", "--no-summary"])
+        .output()?;
+    assert!(output.status.success(), "absolute run failed: {:?}", output);
+    let row: Value = serde_json::from_slice(&output.stdout)?;
+    let path = std::path::PathBuf::from(row["path"].as_str().unwrap());
+    assert!(path.is_absolute(), "path: {}", path.display());
+    assert_eq!(path, fs::canonicalize(&file)?);
+    Ok(())
+}
+
+#[test]
+fn group_by_dir_rolls_up_to_depth() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir_all(dir.path().join("src/Page"))?;
+    fs::create_dir_all(dir.path().join("tests"))?;
+    fs::write(dir.path().join("Root.elm"), "root file")?;
+    fs::write(
+        dir.path().join("src/Main.elm"),
+        "module Main exposing (main)",
+    )?;
+    fs::write(dir.path().join("src/Page/Home.elm"), "home page view")?;
+    fs::write(dir.path().join("tests/Spec.elm"), "suite = describe")?;
+
+    let run = |depth: &str| -> Result<(Vec<Value>, Value)> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--group-by", "dir", "--group-depth", depth])
+            .args(["--format", "json", "--sort", "path"])
+            .output()?;
+        assert!(output.status.success(), "group-by dir failed: {:?}", output);
+        let mut rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+        let summary = rows.pop().unwrap()["summary"].clone();
+        Ok((rows, summary))
+    };
+
+    let (rows, summary) = run("1")?;
+    let dirs: Vec<&str> = rows.iter().filter_map(|r| r["path"].as_str()).collect();
+    assert_eq!(dirs, vec![".", "src", "tests"]);
+    assert_eq!(rows[1]["files"], 2);
+    let rolled: u64 = rows.iter().filter_map(|r| r["tokens"].as_u64()).sum();
+    assert_eq!(rolled, summary["total"].as_u64().unwrap());
+    assert_eq!(summary["files"], 4);
+
+    let (rows, summary) = run("2")?;
+    let dirs: Vec<&str> = rows.iter().filter_map(|r| r["path"].as_str()).collect();
+    assert_eq!(dirs, vec![".", "src", "src/Page", "tests"]);
+    let rolled: u64 = rows.iter().filter_map(|r| r["tokens"].as_u64()).sum();
+    assert_eq!(rolled, summary["total"].as_u64().unwrap());
+    Ok(())
+}
+
+#[test]
+fn extended_adds_wc_style_fields() -> Result<()> {
+    let dir = TempDir::new()?;
+    let contents = "module Main exposing (main)\n\nmain = \"héllo\"\n";
+    fs::write(dir.path().join("Main.elm"), contents)?;
+
+    let run = |extra: &[&str]| -> Result<Value> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "ndjson", "--no-summary"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "extended run failed: {:?}", output);
+        Ok(serde_json::from_slice(&output.stdout)?)
+    };
+
+    let plain = run(&[])?;
+    assert!(plain.get("lines").is_none());
+    assert!(plain.get("bytes").is_none());
+
+    let extended = run(&["--extended"])?;
+    assert_eq!(extended["lines"], 3);
+    assert_eq!(extended["bytes"], contents.len());
+    assert_eq!(extended["chars"], contents.chars().count());
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .arg("--extended")
+        .output()?;
+    let table = String::from_utf8(output.stdout)?;
+    let header = table.lines().next().unwrap();
+    assert!(
+        header.contains("lines") && header.contains("bytes/token"),
+        "table: {table}"
+    );
+    Ok(())
+}
+
+#[test]
+fn tree_format_shows_subtotals_and_collapses_depth() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir_all(dir.path().join("a/b"))?;
+    let files = [
+        ("R.elm", "one two"),
+        ("a/A.elm", "one two three"),
+        ("a/b/B.elm", "one two three four five"),
+    ];
+    for (path, contents) in files {
+        fs::write(dir.path().join(path), contents)?;
+    }
+    let bpe = cl100k_base()?;
+    let count = |text: &str| bpe.encode_ordinary(text).len() as u64;
+    let (r, a, b) = (count(files[0].1), count(files[1].1), count(files[2].1));
+
+    let run = |extra: &[&str]| -> Result<Vec<(u64, String)>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "tree"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "tree run failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .map(|line| {
+                let line = line.trim_start();
+                let (tokens, name) = line.split_once("  ").unwrap();
+                (tokens.parse().unwrap(), name.to_string())
+            })
+            .collect())
+    };
+
+    let full = run(&[])?;
+    assert_eq!(
+        full,
+        vec![
+            (r + a + b, ".".to_string()),
+            (r, "  R.elm".to_string()),
+            (a + b, "  a/".to_string()),
+            (a, "    A.elm".to_string()),
+            (b, "    b/".to_string()),
+            (b, "      B.elm".to_string()),
+        ]
+    );
+
+    let collapsed = run(&["--tree-depth", "1"])?;
+    assert_eq!(
+        collapsed,
+        vec![
+            (r + a + b, ".".to_string()),
+            (r, "  R.elm".to_string()),
+            (a + b, "  a/".to_string()),
+        ]
+    );
+
+    let two = run(&["--tree-depth", "2"])?;
+    assert_eq!(two.len(), 5);
+    assert_eq!(two[4], (b, "    b/".to_string()));
+    Ok(())
+}
+
+#[test]
+fn legacy_encodings_differ_from_cl100k() -> Result<()> {
+    let text = "def add(a, b):\n        return a + b  # indented with spaces\n";
+    let count = |encoding: &str| -> Result<u64> {
+        let output = Command::cargo_bin("tokencount")?
+            .args(["--text", text, "--encoding", encoding, "--total"])
+            .output()?;
+        assert!(output.status.success(), "{encoding} failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?.trim().parse()?)
+    };
+
+    let cl100k = count("cl100k_base")?;
+    let p50k = count("p50k_base")?;
+    let r50k = count("r50k_base")?;
+    assert_eq!(cl100k, cl100k_base()?.encode_ordinary(text).len() as u64);
+    assert_eq!(p50k, p50k_base()?.encode_ordinary(text).len() as u64);
+    assert_eq!(r50k, r50k_base()?.encode_ordinary(text).len() as u64);
+    // The older vocabularies merge runs of spaces less aggressively.
+    assert!(p50k > cl100k, "p50k {p50k} vs cl100k {cl100k}");
+    assert!(r50k > p50k, "r50k {r50k} vs p50k {p50k}");
+    Ok(())
+}
+
+#[test]
+fn dedup_counts_identical_contents_once() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "module A exposing (shared)")?;
+    fs::write(dir.path().join("B.elm"), "module A exposing (shared)")?;
+    fs::write(dir.path().join("C.elm"), "module C exposing (other)")?;
+
+    let run = |extra: &[&str]| -> Result<(Vec<String>, Value)> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "json2"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "dedup run failed: {:?}", output);
+        let json: Value = serde_json::from_slice(&output.stdout)?;
+        let paths = json["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|f| f["path"].as_str().map(String::from))
+            .collect();
+        Ok((paths, json["summary"].clone()))
+    };
+
+    let (paths, summary) = run(&[])?;
+    assert_eq!(paths.len(), 3);
+    assert!(summary.get("duplicates").is_none());
+
+    let (paths, summary) = run(&["--dedup"])?;
+    assert_eq!(paths.len(), 2);
+    assert_eq!(paths.iter().filter(|p| *p == "C.elm").count(), 1);
+    assert_eq!(summary["files"], 2);
+    assert_eq!(summary["duplicates"], 1);
+    Ok(())
+}
+
+#[test]
+fn cache_reuses_counts_for_unchanged_files() -> Result<()> {
+    let dir = TempDir::new()?;
+    let file = dir.path().join("Main.elm");
+    fs::write(&file, "module Main exposing (main)")?;
+    let cache = dir.path().join("cache.json");
+    let tokens = cl100k_base()?
+        .encode_ordinary("module Main exposing (main)")
+        .len() as u64;
+
+    let total = |extra: &[&str]| -> Result<u64> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--total", "--cache", "cache.json"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "cache run failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?.trim().parse()?)
+    };
+
+    assert_eq!(total(&[])?, tokens);
+    let mut entries: Value = serde_json::from_str(&fs::read_to_string(&cache)?)?;
+    let (key, entry) = entries.as_object_mut().unwrap().iter_mut().next().unwrap();
+    assert!(key.ends_with("Main.elm"));
+    assert_eq!(entry["tokens"], tokens);
+    assert_eq!(entry["encoding"], "cl100k_base");
+
+    // A doctored count proves the second run trusts the cache instead of re-reading.
+    entry["tokens"] = 999.into();
+    fs::write(&cache, serde_json::to_string(&entries)?)?;
+    assert_eq!(total(&[])?, 999);
+
+    // A different encoding invalidates the entry.
+    let o200k = total(&["--encoding", "o200k_base"])?;
+    assert_ne!(o200k, 999);
+    Ok(())
+}
+
+#[test]
+fn cache_keeps_entries_outside_the_scanned_paths() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir(dir.path().join("src"))?;
+    fs::write(dir.path().join("Main.elm"), "main = 1")?;
+    fs::write(dir.path().join("src/A.elm"), "a = 1")?;
+    fs::write(dir.path().join("src/B.elm"), "b = 1")?;
+    let cache = dir.path().join("cache.json");
+
+    let run = |paths: &[&str]| -> Result<Vec<String>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--total", "--cache", "cache.json"])
+            .args(paths)
+            .output()?;
+        assert!(output.status.success(), "cache run failed: {:?}", output);
+        let entries: Value = serde_json::from_str(&fs::read_to_string(&cache)?)?;
+        let mut names: Vec<String> = entries
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|key| {
+                Path::new(key)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    };
+
+    assert_eq!(run(&[])?, ["A.elm", "B.elm", "Main.elm"]);
+    assert_eq!(run(&["src"])?, ["A.elm", "B.elm", "Main.elm"]);
+    // Only files that no longer exist lose their entries.
+    fs::remove_file(dir.path().join("src/B.elm"))?;
+    assert_eq!(run(&["src"])?, ["A.elm", "Main.elm"]);
+    Ok(())
+}
+
+#[test]
+fn cache_does_not_bypass_the_binary_check() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), "main")?;
+    fs::write(dir.path().join("Blob.elm"), b"abc\0def\0\x01\x02")?;
+
+    let paths = |extra: &[&str]| -> Result<String> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths", "--cache", "cache.json"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "cache run failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    assert_eq!(paths(&["--no-skip-binary"])?, "Blob.elm\nMain.elm\n");
+    assert_eq!(paths(&[])?, "Main.elm\n");
+    Ok(())
+}
+
+#[test]
+fn cache_keeps_chunked_and_whole_file_counts_apart() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), "main = 1\n".repeat(2000))?;
+    let cache = dir.path().join("cache.json");
+
+    let total = |extra: &[&str]| -> Result<u64> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--total", "--cache", "cache.json"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "cache run failed: {:?}", output);
+        Ok(String::from_utf8(output.stdout)?.trim().parse()?)
+    };
+
+    let whole = total(&[])?;
+    total(&["--chunk-bytes", "8192"])?;
+    let mut entries: Value = serde_json::from_str(&fs::read_to_string(&cache)?)?;
+    let (_, entry) = entries.as_object_mut().unwrap().iter_mut().next().unwrap();
+    assert_eq!(entry["encoding"], "cl100k_base+chunk:8192");
+    // A doctored chunked count must not answer a whole-file run.
+    entry["tokens"] = 999.into();
+    fs::write(&cache, serde_json::to_string(&entries)?)?;
+    assert_eq!(total(&["--chunk-bytes", "8192"])?, 999);
+    assert_eq!(total(&[])?, whole);
+    Ok(())
+}
+
+#[test]
+fn model_flag_selects_encoding_and_reports_it() -> Result<()> {
+    let text = "Model shortcuts résolvent l'encodage 🚀 automatically";
+    let o200k = o200k_base()?.encode_with_special_tokens(text).len();
+    let cl100k = cl100k_base()?.encode_with_special_tokens(text).len();
+    assert_ne!(o200k, cl100k);
+
+    for (model, expected, encoding) in [
+        ("gpt-4o", o200k, "o200k_base"),
+        ("gpt-3.5-turbo", cl100k, "cl100k_base"),
+    ] {
+        let output = Command::cargo_bin("tokencount")?
+            .args(["--text", text, "--model", model, "--format", "json2"])
+            .output()?;
+        assert!(output.status.success());
+        let report: Value = serde_json::from_slice(&output.stdout)?;
+        assert_eq!(report["summary"]["total"].as_u64(), Some(expected as u64));
+        assert_eq!(report["summary"]["encoding"], encoding);
+    }
+
+    let unknown = Command::cargo_bin("tokencount")?
+        .args(["--text", "x", "--model", "gpt-9000"])
+        .output()?;
+    assert!(!unknown.status.success());
+    let stderr = String::from_utf8(unknown.stderr)?;
+    assert!(stderr.contains("gpt-4o-mini"), "stderr: {stderr}");
+
+    let conflict = Command::cargo_bin("tokencount")?
+        .args([
+            "--text",
+            "x",
+            "--model",
+            "gpt-4o",
+            "--encoding",
+            "cl100k_base",
+        ])
+        .output()?;
+    assert!(!conflict.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn encoding_file_loads_custom_vocab() -> Result<()> {
+    use base64::{engine::general_purpose, Engine as _};
+
+    let temp = TempDir::new()?;
+    let mut vocab: Vec<String> = (0..=255u8)
+        .map(|byte| format!("{} {byte}", general_purpose::STANDARD.encode([byte])))
+        .collect();
+    vocab.push(format!("{} 256", general_purpose::STANDARD.encode("ab")));
+    vocab.push(format!("{} 257", general_purpose::STANDARD.encode(" ab")));
+    let vocab_path = temp.path().join("tiny.tiktoken");
+    fs::write(&vocab_path, vocab.join("\n"))?;
+
+    // "ab" and " ab" are single tokens; "c" falls back to its byte.
+    let output = Command::cargo_bin("tokencount")?
+        .args(["--text", "ab abc", "--format", "json2", "--encoding-file"])
+        .arg(&vocab_path)
+        .args(["--special-token", "<|end|>=300"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["summary"]["total"].as_u64(), Some(3));
+    assert_eq!(report["summary"]["encoding"].as_str(), vocab_path.to_str());
+
+    let broken_path = temp.path().join("broken.tiktoken");
+    fs::write(&broken_path, "YQ== 0\nnot-base64! 1\n")?;
+    let broken = Command::cargo_bin("tokencount")?
+        .args(["--text", "x", "--encoding-file"])
+        .arg(&broken_path)
+        .output()?;
+    assert!(!broken.status.success());
+    let stderr = String::from_utf8(broken.stderr)?;
+    assert!(stderr.contains("line 2"), "stderr: {stderr}");
+
+    Ok(())
+}
+
+#[test]
+fn chunked_counts_stay_close_to_whole_file_counts() -> Result<()> {
+    let temp = TempDir::new()?;
+    let mut contents = String::new();
+    for i in 0..2000 {
+        contents.push_str(&format!(
+            "view{i} model = text (String.fromInt model.count{i})\n"
+        ));
+        if i % 7 == 0 {
+            contents.push_str("\n\n    \n");
+        }
+    }
+    fs::write(temp.path().join("Big.elm"), &contents)?;
+
+    let run = |extra: &[&str]| -> Result<Value> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(temp.path())
+            .args(["--format", "json2", "--extended"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(serde_json::from_slice(&output.stdout)?)
+    };
+    let whole = run(&[])?;
+    let chunked = run(&["--chunk-bytes", "8192"])?;
+
+    let whole_file = &whole["files"][0];
+    let chunked_file = &chunked["files"][0];
+    for field in ["lines", "bytes", "chars"] {
+        assert_eq!(whole_file[field], chunked_file[field], "{field}");
+    }
+    let exact = whole_file["tokens"].as_u64().unwrap();
+    let approx = chunked_file["tokens"].as_u64().unwrap();
+    let chunks = contents.len() as u64 / 8192 + 1;
+    assert!(exact.abs_diff(approx) <= chunks, "{exact} vs {approx}");
+    assert_eq!(
+        exact,
+        cl100k_base()?.encode_ordinary(&contents).len() as u64
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "hf")]
+#[test]
+fn tokenizer_json_counts_with_hugging_face_tokenizer() -> Result<()> {
+    let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tokenizer.json");
+
+    // Word-level vocabulary split on whitespace and punctuation:
+    // hello , world ! unknown -> 5 tokens.
+    let output = Command::cargo_bin("tokencount")?
+        .args(["--text", "Hello, world! unknown", "--total"])
+        .args(["--tokenizer-json", fixture])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), "5");
+
+    Ok(())
+}
+
+#[cfg(feature = "hf")]
+#[test]
+fn tokenizer_json_failures_skip_the_file() -> Result<()> {
+    let dir = TempDir::new()?;
+    // No usable unknown token, so any word outside the vocabulary fails to encode.
+    let fixture = fs::read_to_string(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/tokenizer.json"
+    ))?
+    .replace("\"unk_token\": \"[UNK]\"", "\"unk_token\": \"[MISSING]\"");
+    fs::write(dir.path().join("tokenizer.json"), fixture)?;
+    fs::write(dir.path().join("Known.elm"), "hello world")?;
+    fs::write(dir.path().join("Unknown.elm"), "hello stranger")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--tokenizer-json", "tokenizer.json", "--format", "json2"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(
+        report["files"],
+        serde_json::json!([{ "path": "Known.elm", "tokens": 2 }])
+    );
+    assert_eq!(report["summary"]["total"], 2);
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        stderr.contains("skipping Unknown.elm: tokenizer failed"),
+        "{stderr}"
+    );
+    Ok(())
+}
+
+#[test]
+fn mmap_reads_match_standard_reads() -> Result<()> {
+    let temp = TempDir::new()?;
+    fs::write(
+        temp.path().join("Main.elm"),
+        "module Main exposing (main)\n\nmain = text \"héllo\"\n",
+    )?;
+    fs::write(temp.path().join("Empty.elm"), "")?;
+
+    let run = |extra: &[&str]| -> Result<Value> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(temp.path())
+            .args(["--format", "json2", "--extended", "--sort", "path"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(serde_json::from_slice(&output.stdout)?)
+    };
+    let standard = run(&[])?;
+    let mapped = run(&["--mmap"])?;
+    assert_eq!(standard["files"], mapped["files"]);
+    assert_eq!(mapped["files"].as_array().map(Vec::len), Some(2));
+
+    Ok(())
+}
+
+#[test]
+fn max_bytes_keeps_files_at_the_limit() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Exact.elm"), "a".repeat(64))?;
+    fs::write(dir.path().join("Over.elm"), "b".repeat(65))?;
+
+    for extra in [&[][..], &["--mmap"], &["--chunk-bytes", "1"]] {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths", "--max-bytes", "64"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(String::from_utf8(output.stdout)?, "Exact.elm\n");
+    }
+    Ok(())
+}
+
+#[test]
+fn allow_special_counts_markers_as_single_tokens() -> Result<()> {
+    let dir = TempDir::new()?;
+    let text = "first doc<|endoftext|>second doc<|fim_prefix|>";
+    fs::write(dir.path().join("Prompt.elm"), text)?;
+    let bpe = cl100k_base()?;
+
+    let total = |extra: &[&str]| -> Result<u64> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .arg("--total")
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(String::from_utf8(output.stdout)?.trim().parse()?)
+    };
+
+    let ordinary = total(&[])?;
+    assert_eq!(ordinary, bpe.encode_ordinary(text).len() as u64);
+    let special = total(&["--allow-special"])?;
+    assert_eq!(special, bpe.encode_with_special_tokens(text).len() as u64);
+    assert!(special < ordinary, "{special} vs {ordinary}");
+    let only_eot = total(&["--allow-special=<|endoftext|>"])?;
+    assert!(special < only_eot && only_eot < ordinary, "{only_eot}");
+    Ok(())
+}
+
+#[test]
+fn yaml_matches_json2_files_and_summary() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Small.elm"), "main = 1")?;
+    fs::write(
+        dir.path().join("Large.elm"),
+        "module Large exposing (..)\n\nview model = text model.name\n",
+    )?;
+
+    let report = |format: &str| -> Result<Value> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", format, "--sort", "tokens", "--top", "1"])
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(if format == "yaml" {
+            serde_yaml::from_slice(&output.stdout)?
+        } else {
+            serde_json::from_slice(&output.stdout)?
+        })
+    };
+    let yaml = report("yaml")?;
+    let json = report("json2")?;
+
+    assert_eq!(yaml["summary"]["total"], json["summary"]["total"]);
+    assert_eq!(yaml["files"], json["files"]);
+    assert_eq!(yaml["files"][0]["path"], "Large.elm");
+    assert_eq!(yaml["files"].as_array().map(Vec::len), Some(1));
+    Ok(())
+}
+
+#[test]
+fn repeated_encoding_compares_counts_per_file() -> Result<()> {
+    let dir = TempDir::new()?;
+    let texts = [
+        ("A.elm", "migrating prompts from gpt-4 to gpt-4o 🚀"),
+        (
+            "B.elm",
+            "module B exposing (..)\n\nlabel = \"héllo wörld\"\n",
+        ),
+    ];
+    for (name, text) in texts {
+        fs::write(dir.path().join(name), text)?;
+    }
+    let (cl100k, o200k) = (cl100k_base()?, o200k_base()?);
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json2", "--sort", "path"])
+        .args(["--encoding", "cl100k_base", "--encoding", "gpt-4o"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    let files = report["files"].as_array().unwrap();
+    let mut delta_sum = 0;
+    for ((_, text), file) in texts.iter().zip(files) {
+        let old = cl100k.encode_ordinary(text).len() as i64;
+        let new = o200k.encode_ordinary(text).len() as i64;
+        assert_eq!(file["tokens"].as_i64(), Some(old));
+        assert_eq!(file["counts"]["cl100k_base"].as_i64(), Some(old));
+        assert_eq!(file["counts"]["o200k_base"].as_i64(), Some(new));
+        assert_eq!(file["delta"].as_i64(), Some(new - old));
+        delta_sum += new - old;
+    }
+    let totals = &report["summary"]["totals"];
+    assert_eq!(totals["cl100k_base"], report["summary"]["total"]);
+    assert_eq!(
+        totals["o200k_base"].as_i64().unwrap() - totals["cl100k_base"].as_i64().unwrap(),
+        delta_sum
+    );
+
+    let table = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--encoding", "cl100k_base", "--encoding", "o200k_base"])
+        .output()?;
+    let stdout = String::from_utf8(table.stdout)?;
+    let header: Vec<&str> = stdout.lines().next().unwrap().split_whitespace().collect();
+    assert_eq!(header, ["cl100k_base", "o200k_base", "delta", "path"]);
+
+    // A single encoding keeps the plain shape.
+    let single = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json2"])
+        .output()?;
+    let single: Value = serde_json::from_slice(&single.stdout)?;
+    assert!(single["files"][0].get("counts").is_none());
+    assert!(single["summary"].get("totals").is_none());
+    Ok(())
+}
+
+#[test]
+fn generate_completions_lists_enum_values_without_scanning() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), "module Main exposing (main)")?;
+
+    // PowerShell scripts only complete flag names, not their values.
+    for shell in ["bash", "zsh", "fish", "powershell"] {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--generate-completions", shell])
+            .output()?;
+        assert!(output.status.success(), "{shell}: {output:?}");
+        let script = String::from_utf8(output.stdout)?;
+        assert!(!script.contains("Main.elm"), "{shell} ran a scan");
+        assert!(script.contains("max-total-tokens"), "{shell}");
+        if shell == "powershell" {
+            continue;
+        }
+        // Values of --encoding, --format and --sort.
+        for value in ["o200k-base", "p50k-edit", "json2", "prometheus", "tokens"] {
+            assert!(script.contains(value), "{shell} completions lack {value}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn completions_subcommand_prints_scripts_and_bare_scans_still_work() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), " word".repeat(3))?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["completions", "zsh"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let script = String::from_utf8(output.stdout)?;
+    assert!(script.starts_with("#compdef tokencount"), "{script}");
+    for format in ["table", "json2", "ndjson", "markdown", "prometheus", "yaml"] {
+        assert!(script.contains(format), "zsh completions lack {format}");
+    }
+
+    // Without a subcommand, paths are scanned as before.
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([".", "--total"])
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), "3");
+    Ok(())
+}
+
+#[test]
+fn price_per_million_adds_file_and_total_costs() -> Result<()> {
+    let dir = TempDir::new()?;
+    let text = "module Main exposing (main)\n\nmain = text \"priced\"\n".repeat(50);
+    fs::write(dir.path().join("Main.elm"), &text)?;
+    let tokens = cl100k_base()?.encode_ordinary(&text).len() as f64;
+
+    let report = |extra: &[&str]| -> Result<Value> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "json2"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(serde_json::from_slice(&output.stdout)?)
+    };
+    let close = |value: &Value, expected: f64| (value.as_f64().unwrap() - expected).abs() < 1e-12;
+
+    let plain = report(&[])?;
+    assert!(plain["files"][0].get("cost").is_none());
+    assert!(plain["summary"].get("total_cost").is_none());
+
+    let priced = report(&["--price-per-million", "3"])?;
+    assert!(close(&priced["files"][0]["cost"], tokens * 3.0 / 1e6));
+    assert!(close(&priced["summary"]["total_cost"], tokens * 3.0 / 1e6));
+
+    // gpt-4o-mini is $0.15 per million, unless an explicit price overrides it.
+    let model = report(&["--price-model", "gpt-4o-mini"])?;
+    assert!(close(&model["summary"]["total_cost"], tokens * 0.15 / 1e6));
+    let both = report(&["--price-model", "gpt-4o-mini", "--price-per-million", "10"])?;
+    assert!(close(&both["summary"]["total_cost"], tokens * 10.0 / 1e6));
+
+    let table = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--price-per-million", "1000"])
+        .output()?;
+    let stdout = String::from_utf8(table.stdout)?;
+    let cost = format!("{:.4}", tokens * 1000.0 / 1e6);
+    assert!(stdout.contains(&format!("total cost: ${cost}")), "{stdout}");
+    Ok(())
+}
+
+#[test]
+fn context_window_flags_files_and_sets_exit_code() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Small.elm"), "main = 1")?;
+    fs::write(dir.path().join("Large.elm"), "word ".repeat(40))?;
+    let bpe = cl100k_base()?;
+    let window = bpe.encode_ordinary("main = 1").len().to_string();
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([
+            "--format",
+            "json2",
+            "--sort",
+            "path",
+            "--context-window",
+            &window,
+        ])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["files"][0]["fits"], false);
+    assert_eq!(report["files"][1]["fits"], true);
+    assert_eq!(report["summary"]["files_over_window"], 1);
+
+    let failing = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--context-window", &window, "--fail-if-any-over"])
+        .output()?;
+    assert_eq!(failing.status.code(), Some(3));
+    let stdout = String::from_utf8(failing.stdout)?;
+    assert!(stdout.contains("! Large.elm"), "{stdout}");
+    assert!(stdout.contains("files over context window: 1"), "{stdout}");
+
+    // Everything fits gpt-4o's 128k window.
+    let fits = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([
+            "--fits",
+            "gpt-4o",
+            "--fail-if-any-over",
+            "--format",
+            "json2",
+        ])
+        .output()?;
+    assert_eq!(fits.status.code(), Some(0));
+    let report: Value = serde_json::from_slice(&fits.stdout)?;
+    assert_eq!(report["summary"]["files_over_window"], 0);
+    Ok(())
+}
+
+#[test]
+fn chat_overhead_applies_per_file_and_once_per_request() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "module A exposing (a)\n")?;
+    fs::write(dir.path().join("B.elm"), "module B exposing (b)\n\nb = 1\n")?;
+
+    let report = |extra: &[&str]| -> Result<Value> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "json2"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(serde_json::from_slice(&output.stdout)?)
+    };
+    let check = |report: &Value, per_file: u64, priming: u64| {
+        let files = report["files"].as_array().unwrap();
+        for file in files {
+            let tokens = file["tokens"].as_u64().unwrap();
+            assert_eq!(
+                file["tokens_with_overhead"].as_u64(),
+                Some(tokens + per_file)
+            );
+        }
+        let total = report["summary"]["total"].as_u64().unwrap();
+        let expected = total + per_file * files.len() as u64 + priming;
+        assert_eq!(
+            report["summary"]["total_with_overhead"].as_u64(),
+            Some(expected)
+        );
+    };
+
+    let plain = report(&[])?;
+    assert!(plain["files"][0].get("tokens_with_overhead").is_none());
+    assert!(plain["summary"].get("total_with_overhead").is_none());
+
+    check(&report(&["--chat-overhead"])?, 3, 3);
+    check(
+        &report(&["--chat-overhead", "--encoding", "r50k_base"])?,
+        4,
+        3,
+    );
+    check(&report(&["--chat-overhead=10"])?, 10, 3);
+    Ok(())
+}
+
+#[test]
+fn dump_tokens_round_trips_through_decode() -> Result<()> {
+    let dir = TempDir::new()?;
+    let contents = "module Main exposing (main)\n\n\tmain = text \"café 🦀\"\n";
+    fs::write(dir.path().join("Main.elm"), contents)?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--dump-tokens", "Main.elm", "--encoding", "o200k_base"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let ids: Vec<usize> = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(ids, o200k_base()?.encode_ordinary(contents));
+    assert_eq!(o200k_base()?.decode(ids.clone())?, contents);
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--dump-tokens", "--decode-each", "Main.elm"])
+        .args(["--encoding", "o200k_base"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout)?;
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), ids.len());
+    for (line, id) in lines.iter().zip(&ids) {
+        let (line_id, _) = line.split_once('\t').expect("id<TAB>piece");
+        assert_eq!(line_id.parse::<usize>()?, *id);
+    }
+    assert!(
+        stdout.contains("\\n"),
+        "newlines should be escaped: {stdout}"
+    );
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--dump-tokens", "Main.elm", "Main.elm"])
+        .output()?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("exactly one file"), "{stderr}");
+    Ok(())
+}
+
+#[test]
+fn large_scans_keep_piped_output_free_of_progress() -> Result<()> {
+    let dir = TempDir::new()?;
+    for i in 0..600 {
+        fs::write(
+            dir.path().join(format!("M{i}.elm")),
+            "module M exposing (x)\n",
+        )?;
+    }
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .arg("--total")
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let per_file = cl100k_base()?
+        .encode_ordinary("module M exposing (x)\n")
+        .len();
+    assert_eq!(
+        String::from_utf8(output.stdout)?.trim(),
+        (per_file * 600).to_string()
+    );
+    assert!(
+        output.stderr.is_empty(),
+        "{:?}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(())
+}
+
+#[test]
+fn reverse_flips_each_sort_order_with_and_without_top() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "one two")?;
+    fs::write(dir.path().join("B.elm"), "one two three four five six")?;
+    fs::write(
+        dir.path().join("C.elm"),
+        "one two three four five six seven eight",
+    )?;
+
+    let paths = |extra: &[&str]| -> Result<Vec<String>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "json2"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        let report: Value = serde_json::from_slice(&output.stdout)?;
+        Ok(report["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| {
+                file["path"]
+                    .as_str()
+                    .unwrap()
+                    .trim_start_matches("./")
+                    .to_string()
+            })
+            .collect())
+    };
+
+    assert_eq!(paths(&[])?, ["A.elm", "B.elm", "C.elm"]);
+    assert_eq!(paths(&["--reverse"])?, ["C.elm", "B.elm", "A.elm"]);
+    assert_eq!(paths(&["--sort", "tokens"])?, ["C.elm", "B.elm", "A.elm"]);
+    let reversed = paths(&["--sort", "tokens", "--reverse"])?;
+    assert_eq!(reversed, ["A.elm", "B.elm", "C.elm"]);
+
+    // --top picks the largest files first, whatever the display order.
+    assert_eq!(paths(&["--top", "2"])?, ["B.elm", "C.elm"]);
+    assert_eq!(paths(&["--top", "2", "--reverse"])?, ["C.elm", "B.elm"]);
+    let top = paths(&["--top", "2", "--sort", "tokens"])?;
+    assert_eq!(top, ["C.elm", "B.elm"]);
+    let top = paths(&["--top", "2", "--sort", "tokens", "--reverse"])?;
+    assert_eq!(top, ["B.elm", "C.elm"]);
+    Ok(())
+}
+
+#[test]
+fn dash_path_reads_stdin_alongside_directories() -> Result<()> {
+    let dir = TempDir::new()?;
+    let module = "module Main exposing (main)\n";
+    fs::create_dir(dir.path().join("src"))?;
+    fs::write(dir.path().join("src/Main.elm"), module)?;
+    fs::write(
+        dir.path().join("Ignored.elm"),
+        "not named on the command line",
+    )?;
+    let text = "Summarize the following diff in two sentences.\n";
+    let bpe = cl100k_base()?;
+
+    let output = assert_cmd::Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["-", "--format", "json2"])
+        .write_stdin(text)
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["files"][0]["path"], "<stdin>");
+    let expected = bpe.encode_ordinary(text).len() as u64;
+    assert_eq!(report["summary"]["total"].as_u64(), Some(expected));
+    assert_eq!(report["summary"]["files"].as_u64(), Some(1));
+
+    let output = assert_cmd::Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["src", "-", "--format", "json2"])
+        .write_stdin(text)
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    let paths: Vec<&str> = report["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|file| file["path"].as_str())
+        .collect();
+    assert_eq!(paths, ["<stdin>", "src/Main.elm"]);
+    let expected = expected + bpe.encode_ordinary(module).len() as u64;
+    assert_eq!(report["summary"]["total"].as_u64(), Some(expected));
+    Ok(())
+}
+
+#[test]
+fn file_arguments_bypass_extension_and_exclude_filters() -> Result<()> {
+    let dir = TempDir::new()?;
+    let readme = "# Notes\n\nNot an Elm file at all.\n";
+    fs::write(dir.path().join("README.md"), readme)?;
+    fs::write(dir.path().join("Main.elm"), "module Main exposing (main)\n")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["README.md", "--exclude", "*.md", "--format", "json2"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["files"][0]["path"], "README.md");
+    let expected = cl100k_base()?.encode_ordinary(readme).len() as u64;
+    assert_eq!(report["summary"]["total"].as_u64(), Some(expected));
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([".", "--format", "json2"])
+        .output()?;
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    let files = report["files"].as_array().unwrap();
+    assert_eq!(files.len(), 1);
+    assert!(files[0]["path"].as_str().unwrap().ends_with("Main.elm"));
+    Ok(())
+}
+
+#[test]
+fn natural_sort_orders_digit_runs_numerically() -> Result<()> {
+    let dir = TempDir::new()?;
+    for name in ["file10.elm", "file2.elm", "file1.elm", "file02.elm"] {
+        fs::write(dir.path().join(name), "module M exposing (x)\n")?;
+    }
+
+    let paths = |sort: &str| -> Result<Vec<String>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths", "--sort", sort])
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .map(|line| line.trim_start_matches("./").to_string())
+            .collect())
+    };
+
+    let natural = paths("natural")?;
+    assert_eq!(
+        natural,
+        ["file1.elm", "file2.elm", "file02.elm", "file10.elm"]
+    );
+    let plain = paths("path")?;
+    assert_eq!(
+        plain,
+        ["file02.elm", "file1.elm", "file10.elm", "file2.elm"]
+    );
+    Ok(())
+}
+
+#[test]
+fn include_name_admits_extensionless_files() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), "module Main exposing (main)\n")?;
+    fs::write(
+        dir.path().join("Dockerfile"),
+        "FROM rust:1.80\nRUN cargo build\n",
+    )?;
+    fs::write(dir.path().join("justfile"), "build:\n    cargo build\n")?;
+
+    let paths = |extra: &[&str]| -> Result<Vec<String>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .map(|line| line.trim_start_matches("./").to_string())
+            .collect())
+    };
+
+    assert_eq!(paths(&[])?, ["Main.elm"]);
+    assert_eq!(
+        paths(&["--include-name", "Dockerfile"])?,
+        ["Dockerfile", "Main.elm"]
+    );
+    assert_eq!(paths(&["--include-name", "dockerfile"])?, ["Main.elm"]);
+    assert_eq!(
+        paths(&["--include-name", "dockerfile", "--ignore-name-case"])?,
+        ["Dockerfile", "Main.elm"]
+    );
+    assert_eq!(
+        paths(&["--include-common-names"])?,
+        ["Dockerfile", "Main.elm"]
+    );
+    assert_eq!(
+        paths(&["--include-common-names", "--ignore-name-case"])?,
+        ["Dockerfile", "Main.elm", "justfile"]
+    );
+    Ok(())
+}
+
+#[test]
+fn tsv_prints_unpadded_token_path_lines() -> Result<()> {
+    let dir = TempDir::new()?;
+    let small = "module A exposing (a)\n";
+    let big = "module Big exposing (main)\n\nmain = text \"hello world\"\n";
+    fs::write(dir.path().join("A.elm"), small)?;
+    fs::write(dir.path().join("Big.elm"), big)?;
+    let bpe = cl100k_base()?;
+    let (small, big) = (
+        bpe.encode_ordinary(small).len(),
+        bpe.encode_ordinary(big).len(),
+    );
+
+    let tsv = |extra: &[&str]| -> Result<Vec<Vec<String>>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "tsv"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .map(|line| line.split('\t').map(str::to_string).collect())
+            .collect())
+    };
+
+    let rows = tsv(&[])?;
+    assert_eq!(
+        rows,
+        [
+            vec![small.to_string(), String::from("A.elm")],
+            vec![big.to_string(), String::from("Big.elm")],
+        ]
+    );
+
+    let rows = tsv(&["--with-summary"])?;
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[2], ["total", &(small + big).to_string()]);
+    Ok(())
+}
+
+#[test]
+fn detect_shebang_includes_extensionless_scripts() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir(dir.path().join("bin"))?;
+    fs::write(
+        dir.path().join("bin/deploy"),
+        "#!/usr/bin/env python3\nprint('deploying')\n",
+    )?;
+    fs::write(dir.path().join("bin/setup"), "#!/bin/bash\necho setup\n")?;
+    fs::write(dir.path().join("bin/blob"), [0u8, 159, 146, 150, 0, 255])?;
+    fs::write(dir.path().join("tool.py"), "print('tool')\n")?;
+
+    let paths = |extra: &[&str]| -> Result<Vec<String>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths", "--include-ext", "py"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .map(|line| line.trim_start_matches("./").to_string())
+            .collect())
+    };
+
+    assert_eq!(paths(&[])?, ["tool.py"]);
+    assert_eq!(paths(&["--detect-shebang"])?, ["bin/deploy", "tool.py"]);
+    assert_eq!(
+        paths(&["--detect-shebang", "--include-ext", "sh"])?,
+        ["bin/deploy", "bin/setup", "tool.py"]
+    );
+    Ok(())
+}
+
+#[test]
+fn include_ext_accepts_comma_separated_lists() -> Result<()> {
+    let dir = TempDir::new()?;
+    for name in ["Main.elm", "app.ts", "util.js", "notes.md"] {
+        fs::write(dir.path().join(name), "alpha")?;
+    }
+
+    let paths = |extra: &[&str]| -> Result<Vec<String>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .map(str::to_string)
+            .collect())
+    };
+
+    assert_eq!(
+        paths(&["--include-ext", ".elm,ts"])?,
+        ["Main.elm", "app.ts"]
+    );
+    let mixed = paths(&[
+        "--include-ext",
+        "elm,ts",
+        "--include-ext",
+        "js",
+        "--include-ext",
+        "ts",
+    ])?;
+    assert_eq!(mixed, ["Main.elm", "app.ts", "util.js"]);
+    Ok(())
+}
+
+#[test]
+fn all_text_and_star_include_every_extension() -> Result<()> {
+    let dir = TempDir::new()?;
+    for name in ["Main.elm", "notes.md", "data.json", "LICENSE"] {
+        fs::write(dir.path().join(name), "alpha beta")?;
+    }
+
+    let paths = |extra: &[&str], env: Option<&str>| -> Result<Vec<String>> {
+        let mut command = Command::cargo_bin("tokencount")?;
+        command.current_dir(dir.path()).args(["--format", "paths"]);
+        match env {
+            Some(exts) => command.env("TOKENCOUNT_EXT", exts),
+            None => command.env_remove("TOKENCOUNT_EXT"),
+        };
+        let output = command.args(extra).output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .map(str::to_string)
+            .collect())
+    };
+
+    let every = ["LICENSE", "Main.elm", "data.json", "notes.md"];
+    assert_eq!(paths(&[], None)?, ["Main.elm"]);
+    assert_eq!(paths(&["--all-text"], None)?, every);
+    assert_eq!(paths(&["--include-ext", "*"], None)?, every);
+    assert_eq!(paths(&[], Some("md,json"))?, ["data.json", "notes.md"]);
+    assert_eq!(paths(&[], Some("*"))?, every);
+    // Explicit flags win over the environment.
+    assert_eq!(paths(&["--include-ext", "elm"], Some("md"))?, ["Main.elm"]);
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .env_remove("TOKENCOUNT_EXT")
+        .args(["-v", "--total"])
+        .output()?;
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("--all-text"), "hint under -v: {stderr}");
+    Ok(())
+}
+
+#[test]
+fn transcode_counts_utf16_and_bom_files_like_plain_utf8() -> Result<()> {
+    let dir = TempDir::new()?;
+    let text = "module Main exposing (main)\n\n-- café au lait\nmain = 1\n";
+    let mut utf16 = vec![0xFF, 0xFE];
+    utf16.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+    let mut bom = b"\xEF\xBB\xBF".to_vec();
+    bom.extend(text.as_bytes());
+    let latin: Vec<u8> = text.chars().map(|c| c as u8).collect();
+    fs::write(dir.path().join("Utf16.elm"), utf16)?;
+    fs::write(dir.path().join("Bom.elm"), bom)?;
+    fs::write(dir.path().join("Plain.elm"), text)?;
+    fs::write(dir.path().join("Latin.elm"), latin)?;
+    let expected = cl100k_base()?.encode_ordinary(text).len() as u64;
+
+    let report = |extra: &[&str]| -> Result<BTreeMap<String, Value>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "json2"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        let report: Value = serde_json::from_slice(&output.stdout)?;
+        Ok(report["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| (file["path"].as_str().unwrap().to_string(), file.clone()))
+            .collect())
+    };
+
+    let files = report(&["--transcode"])?;
+    for (path, charset) in [
+        ("Utf16.elm", "UTF-16LE"),
+        ("Bom.elm", "UTF-8"),
+        ("Plain.elm", "UTF-8"),
+        ("Latin.elm", "windows-1252"),
+    ] {
+        assert_eq!(files[path]["tokens"].as_u64(), Some(expected), "{path}");
+        assert_eq!(files[path]["charset"], charset, "{path}");
+    }
+
+    // Without --transcode the UTF-16 file looks binary and Latin-1 is not UTF-8.
+    let files = report(&[])?;
+    assert_eq!(files.keys().collect::<Vec<_>>(), ["Bom.elm", "Plain.elm"]);
+    assert!(files["Plain.elm"].get("charset").is_none());
+    Ok(())
+}
+
+#[test]
+fn config_file_sets_defaults_that_flags_override() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir(dir.path().join("docs"))?;
+    fs::write(dir.path().join("docs/guide.md"), "alpha beta")?;
+    fs::write(dir.path().join("Main.elm"), "main")?;
+    fs::write(
+        dir.path().join(".tokencount.toml"),
+        "format = \"json\"\ninclude_ext = [\"md\"]\n",
+    )?;
+
+    // Found from a subdirectory too.
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path().join("docs"))
+        .env_remove("TOKENCOUNT_EXT")
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let rows: Vec<Value> = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(rows[0]["path"], "guide.md");
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .env_remove("TOKENCOUNT_EXT")
+        .args(["--format", "paths", "--include-ext", "elm"])
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?, "Main.elm\n");
+
+    fs::write(dir.path().join(".tokencount.toml"), "formt = \"json\"\n")?;
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .output()?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains(".tokencount.toml"), "{stderr}");
+    Ok(())
+}
+
+#[test]
+fn env_vars_set_defaults_that_flags_override() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir(dir.path().join("docs"))?;
+    fs::write(dir.path().join("docs/guide.md"), "alpha beta")?;
+    fs::write(dir.path().join("notes.txt"), "gamma")?;
+    fs::write(dir.path().join("Main.elm"), "main")?;
+
+    let run = |env: &[(&str, &str)], args: &[&str]| -> Result<String> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .env_remove("TOKENCOUNT_EXT")
+            .envs(env.iter().copied())
+            .args(args)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    // Repeatable flags split their variable on commas.
+    let paths = run(
+        &[
+            ("TOKENCOUNT_FORMAT", "paths"),
+            ("TOKENCOUNT_INCLUDE_EXT", "md,txt"),
+        ],
+        &["--sort", "path"],
+    )?;
+    assert_eq!(paths, "docs/guide.md\nnotes.txt\n");
+
+    // Commas inside glob alternatives stay put.
+    let paths = run(
+        &[
+            ("TOKENCOUNT_FORMAT", "paths"),
+            ("TOKENCOUNT_INCLUDE", "**/*.{md,txt}"),
+        ],
+        &["--sort", "path"],
+    )?;
+    assert_eq!(paths, "docs/guide.md\nnotes.txt\n");
+
+    // Flags win, including over variables they conflict with.
+    let env = [
+        ("TOKENCOUNT_FORMAT", "json"),
+        ("TOKENCOUNT_ENCODING", "o200k-base"),
+    ];
+    let rows: Vec<Value> = serde_json::from_str(&run(&env, &[])?)?;
+    assert_eq!(rows.last().unwrap()["summary"]["encoding"], "o200k_base");
+    assert_eq!(run(&env, &["--format", "paths"])?, "Main.elm\n");
+    let rows: Vec<Value> = serde_json::from_str(&run(&env, &["--model", "gpt-4"])?)?;
+    assert_eq!(rows.last().unwrap()["summary"]["encoding"], "cl100k_base");
+
+    // On/off flags take 1/0 and friends.
+    let env = [("TOKENCOUNT_FORMAT", "paths"), ("TOKENCOUNT_ALL_TEXT", "1")];
+    assert_eq!(run(&env, &[])?.lines().count(), 3);
+    let env = [
+        ("TOKENCOUNT_FORMAT", "paths"),
+        ("TOKENCOUNT_ALL_TEXT", "off"),
+    ];
+    assert_eq!(run(&env, &[])?, "Main.elm\n");
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .env("TOKENCOUNT_ALL_TEXT", "maybe")
+        .output()?;
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr)?.contains("TOKENCOUNT_ALL_TEXT"));
+    Ok(())
+}
+
+#[test]
+fn exclude_ext_matches_compound_suffixes() -> Result<()> {
+    let dir = TempDir::new()?;
+    for name in ["foo.ts", "foo.d.ts", "app.js", "app.min.js", "Types.D.TS"] {
+        fs::write(dir.path().join(name), "alpha")?;
+    }
+
+    let paths = |extra: &[&str]| -> Result<Vec<String>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths", "--sort", "path"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .map(str::to_string)
+            .collect())
+    };
+
+    assert_eq!(
+        paths(&["--include-ext", "ts", "--exclude-ext", ".d.ts"])?,
+        ["foo.ts"]
+    );
+    assert_eq!(
+        paths(&["--include-ext", "ts,js", "--exclude-ext", "d.ts,min.js"])?,
+        ["app.js", "foo.ts"]
+    );
+    assert_eq!(
+        paths(&["--include-ext", "ts,js", "--exclude-ext", "js"])?,
+        ["Types.D.TS", "foo.d.ts", "foo.ts"]
+    );
+    Ok(())
+}
+
+#[test]
+fn files_from_counts_exactly_the_listed_files() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir(dir.path().join("src"))?;
+    fs::write(dir.path().join("src/lib.rs"), "fn main() {}")?;
+    fs::write(dir.path().join("notes.txt"), "alpha beta gamma")?;
+    fs::write(dir.path().join("Main.elm"), "main")?;
+    fs::write(
+        dir.path().join("list.txt"),
+        "src/lib.rs\nmissing.txt\n\nnotes.txt\n",
+    )?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([
+            "--files-from",
+            "list.txt",
+            "--format",
+            "tsv",
+            "--sort",
+            "path",
+        ])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let bpe = cl100k_base()?;
+    let count = |text: &str| bpe.encode_ordinary(text).len();
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        format!(
+            "{}\tnotes.txt\n{}\tsrc/lib.rs\n",
+            count("alpha beta gamma"),
+            count("fn main() {}")
+        )
+    );
+    assert!(String::from_utf8(output.stderr)?.contains("missing.txt"));
+
+    let output = assert_cmd::Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--files-from", "-", "--format", "paths"])
+        .write_stdin("Main.elm\n")
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?, "Main.elm\n");
+    Ok(())
+}
+
+#[test]
+fn min_and_max_bytes_skip_files_and_count_them() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Stub.elm"), "x")?;
+    fs::write(dir.path().join("Main.elm"), "module Main exposing (main)")?;
+    fs::write(dir.path().join("Big.elm"), "word ".repeat(100))?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([
+            "--format",
+            "json2",
+            "--min-bytes",
+            "10",
+            "--max-bytes",
+            "100",
+        ])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    let paths: Vec<&str> = report["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|row| row["path"].as_str())
+        .collect();
+    assert_eq!(paths, ["Main.elm"]);
+    assert_eq!(report["summary"]["skipped_too_small"], 1);
+    assert_eq!(report["summary"]["skipped_too_large"], 1);
+    // Expected skips stay out of the default warning output.
+    assert!(!String::from_utf8(output.stderr)?.contains("Stub.elm"));
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json2"])
+        .output()?;
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert!(report["summary"].get("skipped_too_small").is_none());
+    assert!(report["summary"].get("skipped_too_large").is_none());
+    Ok(())
+}
+
+#[test]
+fn fail_if_file_over_names_violations_and_exits_2() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Big.elm"), "word ".repeat(50))?;
+    fs::write(dir.path().join("Huge.elm"), "word ".repeat(80))?;
+    fs::write(dir.path().join("Small.elm"), "main")?;
+
+    // Neither --top nor --summary-only hides a violation.
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--fail-if-file-over", "20", "--top", "1", "--summary-only"])
+        .output()?;
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("2 file(s) exceed"), "{stderr}");
+    assert!(
+        stderr.contains("Big.elm") && stderr.contains("Huge.elm"),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("Small.elm"), "{stderr}");
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([
+            "--format",
+            "json2",
+            "--fail-if-file-over",
+            "20",
+            "--top",
+            "1",
+        ])
+        .output()?;
+    assert_eq!(output.status.code(), Some(2));
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["files"].as_array().unwrap().len(), 1);
+    let violations: Vec<&str> = report["summary"]["violations"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|v| v["path"].as_str())
+        .collect();
+    assert_eq!(violations, ["Huge.elm", "Big.elm"]);
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json2", "--fail-if-file-over", "1000"])
+        .output()?;
+    assert!(output.status.success());
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["summary"]["violations"], serde_json::json!([]));
+    Ok(())
+}
+
+#[test]
+fn context_window_reports_files_fitting_individually_and_packed() -> Result<()> {
+    let dir = TempDir::new()?;
+    // 10, 6, 4 and 3 tokens: " word" is one token each.
+    for (name, words) in [("A.elm", 10), ("B.elm", 6), ("C.elm", 4), ("D.elm", 3)] {
+        fs::write(dir.path().join(name), " word".repeat(words))?;
+    }
+
+    let summary = |order: &str| -> Result<Value> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "json2", "--context-window", "9"])
+            .args(["--pack-order", order])
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        let report: Value = serde_json::from_slice(&output.stdout)?;
+        Ok(report["summary"].clone())
+    };
+
+    let largest = summary("largest")?;
+    assert_eq!(largest["context_window"], 9);
+    assert_eq!(largest["files_fitting_individually"], 3);
+    // 10 is skipped, then 6 + 3.
+    assert_eq!(largest["files_fitting_packed"], 2);
+    // 3 + 4, then 6 no longer fits.
+    assert_eq!(summary("smallest")?["files_fitting_packed"], 2);
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args([
+            "--format",
+            "json2",
+            "--context-window",
+            "13",
+            "--pack-order",
+            "smallest",
+        ])
+        .output()?;
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["summary"]["files_fitting_packed"], 3);
+    Ok(())
+}
+
+#[test]
+fn pack_concatenates_files_until_the_window_is_full() -> Result<()> {
+    let dir = TempDir::new()?;
+    // 3, 30, 4 and 5 tokens: " word" is one token each. Each `// === X.elm ===`
+    // separator adds 8, so the chunks take 11, 38, 11 and 13.
+    fs::write(dir.path().join("A.elm"), " word".repeat(3))?;
+    fs::write(dir.path().join("B.elm"), " word".repeat(30))?;
+    fs::write(dir.path().join("C.elm"), format!("{}\n", " word".repeat(3)))?;
+    fs::write(dir.path().join("D.elm"), " word".repeat(5))?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--pack", "--context-window", "24", "--sort", "path"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    // B is too big on its own; D would take the pack past 24.
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        format!(
+            "// === A.elm ===\n{}\n// === C.elm ===\n{}\n",
+            " word".repeat(3),
+            " word".repeat(3)
+        )
+    );
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("skipping B.elm"), "{stderr}");
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--pack", "--context-window", "24", "--sort", "tokens"])
+        .args(["-o", "bundle.txt"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    assert!(output.stdout.is_empty());
+    let bundle = fs::read_to_string(dir.path().join("bundle.txt"))?;
+    assert!(bundle.starts_with("// === D.elm ===\n"), "{bundle}");
+    assert!(bundle.contains("// === C.elm ===\n"), "{bundle}");
+    assert!(!bundle.contains("A.elm"), "{bundle}");
+    Ok(())
+}
+
+#[test]
+fn pack_output_fits_the_window_when_recounted() -> Result<()> {
+    let dir = TempDir::new()?;
+    for (name, words) in [("A.elm", 1), ("B.elm", 2), ("C.elm", 4), ("D.elm", 9)] {
+        fs::write(dir.path().join(name), " word".repeat(words))?;
+    }
+
+    for window in ["5", "12", "30", "60"] {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--pack", "--context-window", window, "--sort", "tokens"])
+            .args(["--text", "one two three"])
+            .arg(".")
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        let stderr = String::from_utf8(output.stderr)?;
+        assert!(!stderr.contains("No such file"), "{stderr}");
+
+        let recount = assert_cmd::Command::cargo_bin("tokencount")?
+            .args(["--stdin", "--format", "total"])
+            .write_stdin(output.stdout.clone())
+            .output()?;
+        let total: u64 = String::from_utf8(recount.stdout)?.trim().parse()?;
+        assert!(
+            total <= window.parse()?,
+            "{total} tokens packed into a window of {window}"
+        );
+        if window == "60" {
+            let bundle = String::from_utf8(output.stdout)?;
+            assert!(
+                bundle.contains("// === <text:0> ===\none two three\n"),
+                "{bundle}"
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn fail_if_over_gates_the_total_in_every_format() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), " word".repeat(6))?;
+    fs::write(dir.path().join("B.elm"), " word".repeat(4))?;
+
+    let run = |format: &str, budget: &str| -> Result<(Option<i32>, String)> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--quiet", "--format", format, "--fail-if-over", budget])
+            .output()?;
+        Ok((output.status.code(), String::from_utf8(output.stderr)?))
+    };
+
+    for format in ["table", "json", "csv", "total"] {
+        assert_eq!(run(format, "11")?, (Some(0), String::new()), "{format}");
+        assert_eq!(run(format, "10")?, (Some(0), String::new()), "{format}");
+        let (code, stderr) = run(format, "7")?;
+        assert_eq!(code, Some(2), "{format}");
+        assert_eq!(
+            stderr, "error: total tokens 10 exceed budget of 7 by 3\n",
+            "{format}"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn git_diff_reports_per_file_and_total_deltas() -> Result<()> {
+    let dir = TempDir::new()?;
+    let git = |args: &[&str]| -> Result<()> {
+        let status = std::process::Command::new("git")
+            .current_dir(dir.path())
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .status()?;
+        assert!(status.success(), "git {args:?}");
+        Ok(())
+    };
+    git(&["init", "-q"])?;
+    fs::write(dir.path().join("Main.elm"), " word".repeat(5))?;
+    fs::write(dir.path().join("Old.elm"), " word".repeat(3))?;
+    fs::write(dir.path().join("Same.elm"), " word".repeat(2))?;
+    git(&["add", "."])?;
+    git(&["commit", "-qm", "base"])?;
+    git(&["tag", "base"])?;
+    fs::write(dir.path().join("Main.elm"), " word".repeat(8))?;
+    fs::remove_file(dir.path().join("Old.elm"))?;
+    fs::write(dir.path().join("New.elm"), " word".repeat(4))?;
+    git(&["add", "-A"])?;
+    git(&["commit", "-qm", "change"])?;
+    // Uncommitted edits are not part of HEAD.
+    fs::write(dir.path().join("Same.elm"), " word".repeat(50))?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--git-diff", "base", "--format", "json"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(
+        report["files"],
+        serde_json::json!([
+            { "path": "Main.elm", "base": 5, "head": 8, "delta": 3 },
+            { "path": "New.elm", "base": null, "head": 4, "delta": 4 },
+            { "path": "Old.elm", "base": 3, "head": null, "delta": -3 },
+        ])
+    );
+    assert_eq!(report["summary"]["base"]["total"], 10);
+    assert_eq!(report["summary"]["head"]["total"], 14);
+    assert_eq!(report["summary"]["total_delta"], 4);
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--git-diff", "base"])
+        .output()?;
+    let table = String::from_utf8(output.stdout)?;
+    assert!(table.contains("   +4     -     4  New.elm\n"), "{table}");
+    assert!(table.contains("   -3     3     -  Old.elm\n"), "{table}");
+    assert!(table.contains("total: 10 -> 14 (+4)\n"), "{table}");
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--git-diff", "no-such-ref"])
+        .output()?;
+    assert!(!output.status.success());
+
+    // From a subdirectory, `..` reaches the files above it.
+    fs::create_dir(dir.path().join("sub"))?;
+    fs::write(dir.path().join("sub/Inner.elm"), " word".repeat(2))?;
+    git(&["add", "sub"])?;
+    git(&["commit", "-qm", "sub"])?;
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path().join("sub"))
+        .args(["--git-diff", "base", "--format", "json", ".."])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    let paths: Vec<&str> = report["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|row| row["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        paths,
+        ["../Main.elm", "../New.elm", "../Old.elm", "Inner.elm"]
+    );
+    assert_eq!(report["summary"]["total_delta"], 6);
+    Ok(())
+}
+
+#[test]
+fn baseline_adds_deltas_and_lists_removed_files() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), " word".repeat(5))?;
+    fs::write(dir.path().join("Old.elm"), " word".repeat(3))?;
+    fs::write(dir.path().join("Same.elm"), " word".repeat(2))?;
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json", "-o", "baseline.json"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let baseline = dir.path().join("baseline.json");
+
+    fs::write(dir.path().join("Main.elm"), " word".repeat(8))?;
+    fs::remove_file(dir.path().join("Old.elm"))?;
+    fs::write(dir.path().join("New.elm"), " word".repeat(4))?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json2", "--baseline"])
+        .arg(&baseline)
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    let deltas: BTreeMap<&str, i64> = report["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|row| {
+            (
+                row["path"].as_str().unwrap(),
+                row["delta"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        deltas,
+        BTreeMap::from([("Main.elm", 3), ("New.elm", 4), ("Same.elm", 0)])
+    );
+    assert_eq!(report["summary"]["total_delta"], 4);
+    assert_eq!(
+        report["summary"]["removed_files"],
+        serde_json::json!(["Old.elm"])
+    );
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .arg("--baseline")
+        .arg(&baseline)
+        .output()?;
+    let table = String::from_utf8(output.stdout)?;
+    assert!(table.contains("tokens  delta  path\n"), "{table}");
+    assert!(table.contains("     8     +3  Main.elm\n"), "{table}");
+    assert!(table.contains("change since baseline: +4\n"), "{table}");
+    assert!(table.contains("removed since baseline: 1\n"), "{table}");
+
+    let csv = dir.path().join("baseline.csv");
+    fs::write(&csv, "tokens,path\n5,Main.elm\n")?;
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .arg("--baseline")
+        .arg(&csv)
+        .output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("is not JSON"));
+    fs::write(&csv, r#"{"files": []}"#)?;
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .arg("--baseline")
+        .arg(&csv)
+        .output()?;
+    assert!(String::from_utf8(output.stderr)?.contains("unrecognized baseline"));
+    Ok(())
+}
+
+#[test]
+fn changed_counts_only_files_modified_since_a_ref() -> Result<()> {
+    let dir = TempDir::new()?;
+    let git = |args: &[&str]| -> Result<()> {
+        let status = std::process::Command::new("git")
+            .current_dir(dir.path())
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .status()?;
+        assert!(status.success(), "git {args:?}");
+        Ok(())
+    };
+    git(&["init", "-q"])?;
+    fs::write(dir.path().join("Main.elm"), "main")?;
+    fs::write(dir.path().join("Other.elm"), "other")?;
+    git(&["add", "."])?;
+    git(&["commit", "-qm", "base"])?;
+    fs::write(dir.path().join("Main.elm"), "main = 1")?;
+    fs::write(dir.path().join("notes.md"), "edited")?;
+    git(&["add", "notes.md"])?;
+
+    let paths = |extra: &[&str]| -> Result<String> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(["--format", "paths"])
+            .args(extra)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(String::from_utf8(output.stdout)?)
+    };
+    // notes.md changed too, but the extension filter still applies.
+    assert_eq!(paths(&["--changed"])?, "Main.elm\n");
+    assert_eq!(
+        paths(&["--changed=HEAD", "--include-ext", "elm,md"])?,
+        "Main.elm\nnotes.md\n"
+    );
+
+    let plain = TempDir::new()?;
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(plain.path())
+        .env("GIT_CEILING_DIRECTORIES", plain.path().parent().unwrap())
+        .arg("--changed")
+        .output()?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        stderr.contains("only works inside a git repository"),
+        "{stderr}"
+    );
+    Ok(())
+}
+
+#[test]
+fn check_compares_counts_with_a_written_baseline() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), " word".repeat(100))?;
+    fs::write(dir.path().join("Util.elm"), " word".repeat(20))?;
+
+    let run = |args: &[&str]| -> Result<(Option<i32>, String)> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(args)
+            .output()?;
+        Ok((output.status.code(), String::from_utf8(output.stderr)?))
+    };
+    assert_eq!(
+        run(&["--write-baseline", ".tokencount-baseline.json"])?.0,
+        Some(0)
+    );
+    let snapshot: Value = serde_json::from_str(&fs::read_to_string(
+        dir.path().join(".tokencount-baseline.json"),
+    )?)?;
+    assert_eq!(
+        snapshot,
+        serde_json::json!({
+            "encoding": "cl100k_base",
+            "files": { "Main.elm": 100, "Util.elm": 20 },
+        })
+    );
+
+    // Shrinking is always fine.
+    fs::write(dir.path().join("Util.elm"), " word".repeat(10))?;
+    assert_eq!(run(&["--check"])?, (Some(0), String::new()));
+
+    // Growth within the tolerance passes, beyond it fails with exit code 4.
+    fs::write(dir.path().join("Main.elm"), " word".repeat(105))?;
+    assert_eq!(run(&["--check", "--tolerance", "5%"])?.0, Some(0));
+    assert_eq!(run(&["--check", "--tolerance", "5"])?.0, Some(0));
+    let (code, stderr) = run(&["--check", "--tolerance", "4%"])?;
+    assert_eq!(code, Some(4));
+    assert!(
+        stderr.contains("1 file(s) grew past the baseline"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("  Main.elm: 100 -> 105 (+5)\n"), "{stderr}");
+
+    // New files only fail beyond --new-file-limit.
+    fs::write(dir.path().join("New.elm"), " word".repeat(30))?;
+    assert_eq!(run(&["--check", "--tolerance", "5"])?.0, Some(0));
+    let (code, stderr) = run(&["--check", "--tolerance", "5", "--new-file-limit", "25"])?;
+    assert_eq!(code, Some(4));
+    assert!(stderr.contains("  New.elm: new -> 30\n"), "{stderr}");
+
+    let (code, stderr) = run(&["--check=missing.json"])?;
+    assert_eq!(code, Some(1));
+    assert!(
+        stderr.contains("record one with --write-baseline"),
+        "{stderr}"
+    );
+    Ok(())
+}
+
+#[test]
+fn watch_reprints_after_a_file_changes() -> Result<()> {
+    use std::io::{BufRead, BufReader};
+    use std::process::Stdio;
+
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Main.elm"), " word".repeat(10))?;
+    fs::write(dir.path().join("notes.txt"), "ignored")?;
+
+    let mut child = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "ndjson", "--watch"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut lines = BufReader::new(child.stdout.take().expect("piped stdout")).lines();
+    let mut next_total = || -> Result<u64> {
+        loop {
+            let line = lines.next().expect("watch output ended")?;
+            let value: Value = serde_json::from_str(&line)?;
+            if let Some(total) = value.pointer("/summary/total") {
+                return Ok(total.as_u64().unwrap());
+            }
+        }
+    };
+
+    let first = next_total();
+    // Give the watcher time to register before touching anything.
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    fs::write(dir.path().join("notes.txt"), "still ignored")?;
+    fs::write(dir.path().join("Main.elm"), " word".repeat(25))?;
+    let second = next_total();
+    child.kill()?;
+    child.wait()?;
+
+    assert_eq!(first?, 10);
+    assert_eq!(second?, 25);
+    Ok(())
+}
+
+#[test]
+fn print_schema_describes_the_json_report() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("A.elm"), "alpha")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--print-schema"])
+        .output()?;
+    assert!(output.status.success(), "{:?}", output);
+    let schema: Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(schema["type"], "array");
+    let defs = &schema["$defs"];
+
+    // Optional fields are left out rather than written as null.
+    let summary = &defs["Summary"];
+    let required = summary["required"].as_array().unwrap();
+    assert!(required.contains(&"total".into()));
+    assert!(!required.contains(&"top".into()));
+    assert_eq!(summary["properties"]["top"]["type"], "array");
+
+    // Every key of a real report is described.
+    let report = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["--format", "json", "--top", "1", "--extended"])
+        .output()?;
+    let rows: Vec<Value> = serde_json::from_slice(&report.stdout)?;
+    for (row, definition) in [(&rows[0], "FileStat"), (&rows[1]["summary"], "Summary")] {
+        for key in row.as_object().unwrap().keys() {
+            assert!(
+                defs[definition]["properties"].get(key).is_some(),
+                "{definition} schema lacks {key}"
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn scan_subcommand_matches_a_bare_scan() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::create_dir(dir.path().join("src"))?;
+    fs::write(dir.path().join("src/Main.elm"), " word".repeat(4))?;
+    fs::write(dir.path().join("Other.elm"), " word".repeat(9))?;
+
+    let report = |args: &[&str]| -> Result<String> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(args)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(String::from_utf8(output.stdout)?)
+    };
+    assert_eq!(
+        report(&["scan", "src", "--format", "json"])?,
+        report(&["src", "--format", "json"])?
+    );
+    assert_eq!(report(&["scan", "--total"])?.trim(), "13");
+    Ok(())
+}
+
+#[test]
+fn text_subcommand_prints_the_count() -> Result<()> {
+    let text = "module Main exposing (main)";
+    let output = Command::cargo_bin("tokencount")?
+        .args(["text", text, "--encoding", "o200k_base"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8(output.stdout)?.trim(),
+        o200k_base()?.encode_ordinary(text).len().to_string()
+    );
+
+    let output = assert_cmd::Command::cargo_bin("tokencount")?
+        .args(["text", "-"])
+        .write_stdin(" word".repeat(7))
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), "7");
+    Ok(())
+}
+
+#[test]
+fn diff_subcommand_compares_two_reports() -> Result<()> {
+    let dir = TempDir::new()?;
+    fs::write(dir.path().join("Kept.elm"), " word".repeat(5))?;
+    fs::write(dir.path().join("Grown.elm"), " word".repeat(2))?;
+    fs::write(dir.path().join("Gone.elm"), " word".repeat(3))?;
+    let save = |name: &str, format: &str| -> Result<()> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args([".", "--format", format, "-o", name])
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(())
+    };
+    save("old.json", "json")?;
+    fs::write(dir.path().join("Grown.elm"), " word".repeat(6))?;
+    fs::remove_file(dir.path().join("Gone.elm"))?;
+    save("new.json", "json2")?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["diff", "old.json", "new.json", "--format", "json"])
+        .args(["--sort", "tokens"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report: Value = serde_json::from_slice(&output.stdout)?;
+    let files = report["files"].as_array().unwrap();
+    let rows: Vec<(&str, i64)> = files
+        .iter()
+        .map(|row| {
+            (
+                row["path"].as_str().unwrap(),
+                row["delta"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(rows, [("Grown.elm", 4), ("Gone.elm", -3)]);
+    assert_eq!(files[1]["head"], Value::Null);
+    assert_eq!(report["summary"]["total_delta"], 1);
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["diff", "old.json", "new.json"])
+        .output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("total: 10 -> 11 (+1)"), "{stdout}");
+    Ok(())
+}
+
+#[test]
+fn tokens_subcommand_dumps_one_file() -> Result<()> {
+    let dir = TempDir::new()?;
+    let contents = "main = text \"café\"\n";
+    fs::write(dir.path().join("Main.elm"), contents)?;
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["tokens", "Main.elm", "--model", "gpt-4o"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let ids: Vec<usize> = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(ids, o200k_base()?.encode_ordinary(contents));
+
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["tokens", "Main.elm", "--decode-each"])
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout)?;
+    assert_eq!(
+        stdout.lines().count(),
+        cl100k_base()?.encode_ordinary(contents).len()
+    );
+
+    // Decoded like the scan would, so the IDs are the ones it counted.
+    fs::write(dir.path().join("Latin.elm"), b"caf\xe9 cr\xe8me")?;
+    let output = Command::cargo_bin("tokencount")?
+        .current_dir(dir.path())
+        .args(["tokens", "Latin.elm"])
+        .output()?;
+    assert!(!output.status.success());
+    let ids = |args: &[&str]| -> Result<Vec<usize>> {
+        let output = Command::cargo_bin("tokencount")?
+            .current_dir(dir.path())
+            .args(args)
+            .output()?;
+        assert!(output.status.success(), "{output:?}");
+        Ok(serde_json::from_slice(&output.stdout)?)
+    };
+    let bpe = cl100k_base()?;
+    assert_eq!(
+        ids(&["tokens", "Latin.elm", "--transcode"])?,
+        bpe.encode_ordinary("café crème")
+    );
+    assert_eq!(
+        ids(&["tokens", "Latin.elm", "--lossy"])?,
+        bpe.encode_ordinary("caf\u{fffd} cr\u{fffd}me")
+    );
+    assert_eq!(
+        ids(&["--dump-tokens", "Latin.elm", "--transcode"])?,
+        bpe.encode_ordinary("café crème")
+    );
+    Ok(())
+}